@@ -1,47 +1,143 @@
 use std::collections::{HashMap, HashSet};
-use crate::nfa::{NFA, StateId as NFAStateId};
+use std::io::{self, Read};
+use crate::nfa::{shortest_rules, trailing_splits, NFA, StateId as NFAStateId, Transition};
+use crate::regex_parser::{char_after, char_before, in_ranges, intersect_ranges, merge_ranges, TrailingSplit};
+use crate::spec_parser::{Action, Spec, Unmatched};
+use crate::token::{apply_actions, best_rule, drop_unmatched, scan, without_context, Match, RuleTables, Token};
 
+/// Identifies a DFA state; ids are contiguous from 0
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct DFAStateId(pub usize);
 
+/// A DFA state together with the NFA states it was built from
 #[derive(Debug, Clone)]
 pub struct DFAState {
     pub nfa_states: HashMap<usize, HashSet<NFAStateId>>, // Map from NFA index to states
     pub is_accepting: bool,
     pub rule_index: Option<usize>, // Index of the matching rule (for precedence)
+    pub line_end_rule_index: Option<usize>, // Rule matching here only if a newline or end of input follows
+    pub end_of_input_rule_index: Option<usize>, // Rule matching here only at the end of input ('\z')
 }
 
+impl DFAState {
+    // Whether some rule accepts here, perhaps only before a line end or the end of input
+    fn accepts_anything(&self) -> bool {
+        self.is_accepting || self.line_end_rule_index.is_some() || self.end_of_input_rule_index.is_some()
+    }
+}
+
+/// Anchors are zero-width and do not change how tokens are chosen: a `^` rule is
+/// only eligible when the token starts at column 1, and a `$` rule only accepts at
+/// a position followed by a newline or end of input, and a `\z` rule only at the end
+/// of input. Among the eligible matches the longest still wins, with ties going to the
+/// highest priority and then the earliest rule, except that a match stops growing at the
+/// first position a `(SHORTEST)` rule wins. Matches are at least one character long: a
+/// rule that accepts the empty string only ever matches something longer, and where
+/// nothing longer matches the character is unmatched. The generated lexers follow the
+/// same policy.
+///
+/// Each start condition of the spec has its own pair of start states, indexed like
+/// `Spec::conditions`; the states reachable from them are shared where they coincide.
 #[derive(Debug, Clone)]
 pub struct DFA {
     pub states: HashMap<DFAStateId, DFAState>,
-    pub start_state: DFAStateId,
-    pub transitions: HashMap<(DFAStateId, char), DFAStateId>,
+    pub start_states: Vec<DFAStateId>,
+    pub line_start_states: Vec<DFAStateId>, // Start states used at column 1, where '^' rules apply
+    pub classes: Vec<(char, char)>, // Sorted, disjoint runs of characters that every state treats alike
+    pub transitions: HashMap<(DFAStateId, usize), DFAStateId>, // On the index of a run in `classes`
+    pub priorities: Vec<i32>, // Priority of each rule, which outranks line order when set
+    pub trailing: Vec<Option<TrailingSplit>>, // Where the token ends for each rule with trailing context
+    pub shortest: Vec<bool>, // Rules whose match ends as soon as they accept, from `(SHORTEST)`
+    state_index: HashMap<StateKey, DFAStateId>, // Lookup of states by their NFA membership
     next_state_id: usize,
 }
 
+// Canonical, hashable form of a DFA state's NFA membership
+type StateKey = Vec<(usize, Vec<usize>)>;
+
+fn state_key(nfa_states: &HashMap<usize, HashSet<NFAStateId>>) -> StateKey {
+    let mut key: StateKey = nfa_states
+        .iter()
+        .map(|(nfa_index, states)| {
+            let mut ids: Vec<usize> = states.iter().map(|state| state.0).collect();
+            ids.sort_unstable();
+            (*nfa_index, ids)
+        })
+        .collect();
+    key.sort_unstable();
+    key
+}
+
 impl DFA {
-    pub fn from_nfas(nfas: Vec<(NFA, usize)>) -> Self {
+    /// Runs the subset construction over one NFA per rule. Each NFA is paired with
+    /// its rule index, and lower indices take precedence when several rules accept.
+    /// `conditions` lists, for each start condition, the positions in `nfas` of the
+    /// rules active in it, and `priorities` gives each rule index a priority. Rules
+    /// built from the same regex with the same options are only determinized once per
+    /// start condition.
+    pub fn from_nfas(nfas: Vec<(NFA, usize)>, conditions: &[Vec<usize>], priorities: &[i32]) -> Self {
+        Self::from_nfas_with(nfas, conditions, priorities, None, None)
+            .unwrap_or_else(|_| unreachable!("construction without a state limit cannot fail"))
+    }
+
+    /// Like `from_nfas`, giving up with an error once construction has made more than
+    /// `max_states` states, if set, instead of running on with a blown-up spec. With an
+    /// `alphabet`, sorted ranges as in `Spec::alphabet`, only those characters get
+    /// transitions and any other character never matches.
+    pub fn from_nfas_with(
+        nfas: Vec<(NFA, usize)>,
+        conditions: &[Vec<usize>],
+        priorities: &[i32],
+        max_states: Option<usize>,
+        alphabet: Option<&[(char, char)]>,
+    ) -> Result<Self, String> {
+        // Only characters that some rule can actually consume need to be considered, and
+        // the characters of a class all go to the same state
+        let mut classes = alphabet_classes(&nfas);
+        if let Some(alphabet) = alphabet {
+            classes = intersect_ranges(&classes, alphabet);
+        }
+
         let mut dfa = DFA {
             states: HashMap::new(),
-            start_state: DFAStateId(0),
+            start_states: Vec::new(),
+            line_start_states: Vec::new(),
+            classes: classes.clone(),
             transitions: HashMap::new(),
+            priorities: priorities.to_vec(),
+            trailing: trailing_splits(&nfas),
+            shortest: shortest_rules(&nfas),
+            state_index: HashMap::new(),
             next_state_id: 0,
         };
 
-        // Create start state with all NFA start states
-        let mut start_nfa_states = HashMap::new();
-        for (nfa_index, (nfa, _)) in nfas.iter().enumerate() {
-            let mut start_set = HashSet::new();
-            start_set.insert(nfa.start_state.clone());
-            let epsilon_closure = nfa.epsilon_closure(&start_set);
-            start_nfa_states.insert(nfa_index, epsilon_closure);
-        }
+        // Each start condition begins in the NFA start states of its rules
+        for active in without_duplicates(&nfas, conditions, priorities) {
+            let mut start_nfa_states = HashMap::new();
+            let mut line_start_nfa_states = HashMap::new();
+            for nfa_index in active {
+                let nfa = &nfas[nfa_index].0;
+                let mut start_set = HashSet::new();
+                start_set.insert(nfa.start_state.clone());
+                let epsilon_closure = nfa.epsilon_closure(&start_set);
 
-        let start_state = dfa.new_state(start_nfa_states, &nfas);
-        dfa.start_state = start_state;
+                // At column 1 the '^' anchors can also be passed
+                let mut line_start_closure = nfa.move_on(&epsilon_closure, Transition::LineStart);
+                line_start_closure = nfa.epsilon_closure(&line_start_closure);
+                line_start_closure.extend(epsilon_closure.iter().cloned());
+
+                start_nfa_states.insert(nfa_index, epsilon_closure);
+                line_start_nfa_states.insert(nfa_index, line_start_closure);
+            }
+
+            let start_state = dfa.find_or_create_state(start_nfa_states, &nfas);
+            let line_start_state = dfa.find_or_create_state(line_start_nfa_states, &nfas);
+            dfa.start_states.push(start_state);
+            dfa.line_start_states.push(line_start_state);
+        }
 
         // Build DFA using subset construction
-        let mut worklist = vec![dfa.start_state.clone()];
+        let mut worklist: Vec<DFAStateId> = dfa.start_states.iter().chain(&dfa.line_start_states).cloned().collect();
         let mut processed = HashSet::new();
 
         while let Some(current_state_id) = worklist.pop() {
@@ -49,17 +145,27 @@ impl DFA {
                 continue;
             }
             processed.insert(current_state_id.clone());
+            if let Some(max_states) = max_states
+                && dfa.next_state_id > max_states
+            {
+                return Err(format!(
+                    "DFA has over {} states ({} so far, {} still to expand); simplify the rules or raise the limit",
+                    max_states,
+                    dfa.next_state_id,
+                    worklist.len() + 1
+                ));
+            }
 
             let current_state = dfa.states.get(&current_state_id).unwrap().clone();
 
-            // For each possible input character
-            for ch in (32..127u8).map(|b| b as char) {
+            // For each class of input characters
+            for (class, &(low, _)) in classes.iter().enumerate() {
                 let mut next_nfa_states = HashMap::new();
 
-                // Compute move on character for each NFA separately
+                // Compute move on the class's first character for each NFA separately
                 for (nfa_index, (nfa, _)) in nfas.iter().enumerate() {
                     if let Some(current_nfa_states) = current_state.nfa_states.get(&nfa_index) {
-                        let moved = nfa.move_on_char(current_nfa_states, ch);
+                        let moved = nfa.move_on_char(current_nfa_states, low);
                         if !moved.is_empty() {
                             let epsilon_closure = nfa.epsilon_closure(&moved);
                             next_nfa_states.insert(nfa_index, epsilon_closure);
@@ -71,8 +177,7 @@ impl DFA {
                     // Find or create DFA state
                     let next_state_id = dfa.find_or_create_state(next_nfa_states, &nfas);
 
-                    // Add transition
-                    dfa.transitions.insert((current_state_id.clone(), ch), next_state_id.clone());
+                    dfa.transitions.insert((current_state_id.clone(), class), next_state_id.clone());
 
                     if !processed.contains(&next_state_id) {
                         worklist.push(next_state_id);
@@ -81,19 +186,25 @@ impl DFA {
             }
         }
 
-        dfa
+        Ok(dfa)
     }
 
     fn new_state(&mut self, nfa_states: HashMap<usize, HashSet<NFAStateId>>, nfas: &[(NFA, usize)]) -> DFAStateId {
         let state_id = DFAStateId(self.next_state_id);
         self.next_state_id += 1;
 
-        let (is_accepting, rule_index) = check_accepting(&nfa_states, nfas);
+        let (is_accepting, rule_index) = check_accepting(&nfa_states, nfas, &self.priorities);
+        let line_end_rule_index = check_anchor_accepting(&nfa_states, nfas, &self.priorities, Transition::LineEnd);
+        let end_of_input_rule_index = check_anchor_accepting(&nfa_states, nfas, &self.priorities, Transition::EndOfInput);
+
+        self.state_index.insert(state_key(&nfa_states), state_id.clone());
 
         let state = DFAState {
             nfa_states,
             is_accepting,
             rule_index,
+            line_end_rule_index,
+            end_of_input_rule_index,
         };
 
         self.states.insert(state_id.clone(), state);
@@ -102,108 +213,1437 @@ impl DFA {
 
     fn find_or_create_state(&mut self, nfa_states: HashMap<usize, HashSet<NFAStateId>>, nfas: &[(NFA, usize)]) -> DFAStateId {
         // Check if state already exists
-        for (state_id, state) in &self.states {
-            if state.nfa_states == nfa_states {
-                return state_id.clone();
-            }
+        if let Some(state_id) = self.state_index.get(&state_key(&nfa_states)) {
+            return state_id.clone();
         }
 
         // Create new state
         self.new_state(nfa_states, nfas)
     }
 
-    // pub fn simulate(&self, input: &str) -> Vec<(String, usize, usize, Option<usize>)> {
-    //     let mut tokens = Vec::new();
-    //     let mut line = 1;
-    //     let mut column = 1;
-    //     let mut pos = 0;
-    //     let chars: Vec<char> = input.chars().collect();
-    //
-    //     while pos < chars.len() {
-    //         let (token_length, rule_index) = self.longest_match(&chars[pos..]);
-    //
-    //         if token_length > 0 {
-    //             let lexeme: String = chars[pos..pos + token_length].iter().collect();
-    //             tokens.push((lexeme, line, column, rule_index));
-    //
-    //             // Update position
-    //             for i in pos..pos + token_length {
-    //                 if chars[i] == '\n' {
-    //                     line += 1;
-    //                     column = 1;
-    //                 } else {
-    //                     column += 1;
-    //                 }
-    //             }
-    //             pos += token_length;
-    //         } else {
-    //             // No match found, skip character
-    //             if chars[pos] == '\n' {
-    //                 line += 1;
-    //                 column = 1;
-    //             } else {
-    //                 column += 1;
-    //             }
-    //             pos += 1;
-    //         }
-    //     }
-    //
-    //     tokens.push(("".to_string(), line, column, None)); // EOF marker
-    //     tokens
-    // }
-    //
-    // fn longest_match(&self, input: &[char]) -> (usize, Option<usize>) {
-    //     let mut current_state = &self.start_state;
-    //     let mut last_accepting_pos = 0;
-    //     let mut last_accepting_rule = None;
-    //
-    //     // Check if start state is accepting
-    //     if let Some(state) = self.states.get(current_state) {
-    //         if state.is_accepting {
-    //             last_accepting_pos = 0;
-    //             last_accepting_rule = state.rule_index;
-    //         }
-    //     }
-    //
-    //     for (pos, &ch) in input.iter().enumerate() {
-    //         if let Some(next_state_id) = self.transitions.get(&(current_state.clone(), ch)) {
-    //             current_state = next_state_id;
-    //
-    //             if let Some(state) = self.states.get(current_state) {
-    //                 if state.is_accepting {
-    //                     last_accepting_pos = pos + 1;
-    //                     last_accepting_rule = state.rule_index;
-    //                 }
-    //             }
-    //         } else {
-    //             break;
-    //         }
-    //     }
-    //
-    //     (last_accepting_pos, last_accepting_rule)
-    // }
-}
-
-fn check_accepting(nfa_states: &HashMap<usize, HashSet<NFAStateId>>, nfas: &[(NFA, usize)]) -> (bool, Option<usize>) {
-    let mut best_rule_index = None;
+    /// The index in `classes` of the run holding `ch`, if any transition consumes it
+    pub fn class_of(&self, ch: char) -> Option<usize> {
+        let index = self.classes.partition_point(|&(_, high)| high < ch);
+        self.classes.get(index).filter(|&&(low, _)| low <= ch).map(|_| index)
+    }
 
-    for (nfa_index, (nfa, rule_index)) in nfas.iter().enumerate() {
-        if let Some(current_nfa_states) = nfa_states.get(&nfa_index) {
-            for accept_state in &nfa.accept_states {
-                if current_nfa_states.contains(accept_state) {
-                    // Found an accepting state - check if this rule has higher precedence
-                    match best_rule_index {
-                        None => best_rule_index = Some(*rule_index),
-                        Some(current_best) => {
-                            if *rule_index < current_best {
-                                best_rule_index = Some(*rule_index);
-                            }
-                        }
+    /// The state `state` moves to on `ch`, if it has a transition on it
+    pub fn next_state(&self, state: &DFAStateId, ch: char) -> Option<&DFAStateId> {
+        self.transitions.get(&(state.clone(), self.class_of(ch)?))
+    }
+
+    /// The transitions out of `state` as runs of characters and the state each goes to,
+    /// in character order, with neighbouring runs to the same state joined
+    pub fn transition_runs(&self, state: &DFAStateId) -> Vec<(char, char, DFAStateId)> {
+        let mut runs: Vec<(char, char, DFAStateId)> = Vec::new();
+        for (class, &(low, high)) in self.classes.iter().enumerate() {
+            let Some(to) = self.transitions.get(&(state.clone(), class)) else {
+                continue;
+            };
+            match runs.last_mut() {
+                Some((_, last_high, last_to)) if last_to == to && adjacent(*last_high, low) => *last_high = high,
+                _ => runs.push((low, high, to.clone())),
+            }
+        }
+        runs
+    }
+
+    /// Indices below `rule_count` of the rules no state accepts after at least one
+    /// character, so they can never win a match: every lexeme they match is taken by a
+    /// longer or higher-precedence rule, or they only match the empty string
+    pub fn unmatchable_rules(&self, rule_count: usize) -> Vec<usize> {
+        let accepted: HashSet<usize> = self
+            .transitions
+            .values()
+            .map(|state_id| &self.states[state_id])
+            .flat_map(|state| [state.rule_index, state.line_end_rule_index, state.end_of_input_rule_index])
+            .flatten()
+            .collect();
+        (0..rule_count).filter(|rule_index| !accepted.contains(rule_index)).collect()
+    }
+
+    /// Deletes states from which no accepting state can be reached, along with their
+    /// transitions, and renumbers the rest contiguously in their original order. Start
+    /// states are always kept, even when they are dead.
+    pub fn remove_dead_states(&mut self) {
+        // Walk transitions backwards from every accepting state
+        let mut predecessors: HashMap<&DFAStateId, Vec<&DFAStateId>> = HashMap::new();
+        for ((from_state, _), to_state) in &self.transitions {
+            predecessors.entry(to_state).or_default().push(from_state);
+        }
+
+        let mut live: HashSet<DFAStateId> = HashSet::new();
+        let mut stack: Vec<&DFAStateId> = self
+            .states
+            .iter()
+            .filter(|(_, state)| state.accepts_anything())
+            .map(|(state_id, _)| state_id)
+            .collect();
+        while let Some(state_id) = stack.pop() {
+            if live.insert(state_id.clone()) {
+                stack.extend(predecessors.get(state_id).into_iter().flatten());
+            }
+        }
+        live.extend(self.start_states.iter().chain(&self.line_start_states).cloned());
+
+        let mut kept: Vec<DFAStateId> = live.into_iter().collect();
+        kept.sort_by_key(|state_id| state_id.0);
+        let new_id: HashMap<DFAStateId, DFAStateId> = kept
+            .iter()
+            .enumerate()
+            .map(|(i, state_id)| (state_id.clone(), DFAStateId(i)))
+            .collect();
+
+        self.states = std::mem::take(&mut self.states)
+            .into_iter()
+            .filter_map(|(state_id, state)| Some((new_id.get(&state_id)?.clone(), state)))
+            .collect();
+        self.transitions = std::mem::take(&mut self.transitions)
+            .into_iter()
+            .filter_map(|((from_state, class), to_state)| {
+                Some(((new_id.get(&from_state)?.clone(), class), new_id.get(&to_state)?.clone()))
+            })
+            .collect();
+        self.state_index.retain(|_, state_id| new_id.contains_key(state_id));
+        for state_id in self.state_index.values_mut() {
+            *state_id = new_id[state_id].clone();
+        }
+        for state_id in self.start_states.iter_mut().chain(&mut self.line_start_states) {
+            *state_id = new_id[state_id].clone();
+        }
+        self.next_state_id = kept.len();
+    }
+
+    /// Collapses equivalent states using Hopcroft's partition refinement. States start
+    /// out grouped by the rules they accept, so rule precedence is unaffected.
+    pub fn minimize(&self) -> DFA {
+        // Dense indices for the states, plus a sink standing in for missing transitions
+        let mut ids: Vec<&DFAStateId> = self.states.keys().collect();
+        ids.sort_by_key(|id| id.0);
+        let index_of: HashMap<&DFAStateId, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let sink = ids.len();
+
+        let mut alphabet: Vec<usize> = self.transitions.keys().map(|(_, class)| *class).collect();
+        alphabet.sort();
+        alphabet.dedup();
+
+        let target = |state: usize, class: usize| -> usize {
+            if state == sink {
+                return sink;
+            }
+            self.transitions
+                .get(&(ids[state].clone(), class))
+                .map_or(sink, |next| index_of[next])
+        };
+
+        // Classes every state treats alike are refined as one, keyed by their first member
+        let mut group_of_column: HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for &class in &alphabet {
+            let column: Vec<usize> = (0..sink).map(|state| target(state, class)).collect();
+            let group = *group_of_column.entry(column).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[group].push(class);
+        }
+        let alphabet: Vec<usize> = groups.iter().map(|group| group[0]).collect();
+
+        // Predecessors of every state for each class
+        let mut predecessors: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for state in 0..=sink {
+            for &class in &alphabet {
+                predecessors.entry((target(state, class), class)).or_default().push(state);
+            }
+        }
+
+        // Initial partition by accepting behaviour
+        let mut blocks: Vec<Vec<usize>> = Vec::new();
+        let mut block_keys: HashMap<[Option<usize>; 3], usize> = HashMap::new();
+        let mut block_of = vec![0; sink + 1];
+        for (state, state_block) in block_of.iter_mut().enumerate() {
+            let key = match ids.get(state) {
+                Some(id) => {
+                    let state = &self.states[*id];
+                    [state.rule_index, state.line_end_rule_index, state.end_of_input_rule_index]
+                }
+                None => [None; 3],
+            };
+            let block = *block_keys.entry(key).or_insert_with(|| {
+                blocks.push(Vec::new());
+                blocks.len() - 1
+            });
+            blocks[block].push(state);
+            *state_block = block;
+        }
+
+        let mut worklist: Vec<usize> = (0..blocks.len()).collect();
+        let mut in_worklist = vec![true; blocks.len()];
+
+        while let Some(splitter) = worklist.pop() {
+            in_worklist[splitter] = false;
+            let splitter_states = blocks[splitter].clone();
+
+            for &class in &alphabet {
+                // States that move into the splitter on this class
+                let mut incoming: HashSet<usize> = HashSet::new();
+                for &state in &splitter_states {
+                    if let Some(sources) = predecessors.get(&(state, class)) {
+                        incoming.extend(sources);
+                    }
+                }
+
+                let mut touched: Vec<usize> = incoming.iter().map(|&state| block_of[state]).collect();
+                touched.sort();
+                touched.dedup();
+
+                for block in touched {
+                    let (inside, outside): (Vec<usize>, Vec<usize>) =
+                        blocks[block].iter().partition(|state| incoming.contains(state));
+                    if outside.is_empty() {
+                        continue;
+                    }
+
+                    let new_block = blocks.len();
+                    for &state in &outside {
+                        block_of[state] = new_block;
+                    }
+                    let inside_len = inside.len();
+                    let outside_len = outside.len();
+                    blocks[block] = inside;
+                    blocks.push(outside);
+                    in_worklist.push(false);
+
+                    if in_worklist[block] || outside_len <= inside_len {
+                        worklist.push(new_block);
+                        in_worklist[new_block] = true;
+                    } else {
+                        worklist.push(block);
+                        in_worklist[block] = true;
+                    }
+                }
+            }
+        }
+
+        // Number the surviving blocks by their lowest original state, dropping the sink's
+        // block unless a start state fell into it
+        let sink_block = block_of[sink];
+        let start_blocks: Vec<usize> = self
+            .start_states
+            .iter()
+            .chain(&self.line_start_states)
+            .map(|state| block_of[index_of[state]])
+            .collect();
+        let mut live_blocks: Vec<usize> = (0..blocks.len())
+            .filter(|&block| !blocks[block].is_empty())
+            .filter(|&block| block != sink_block || start_blocks.contains(&block))
+            .collect();
+        live_blocks.sort_by_key(|&block| blocks[block].iter().min().copied());
+        let new_id: HashMap<usize, DFAStateId> = live_blocks
+            .iter()
+            .enumerate()
+            .map(|(i, &block)| (block, DFAStateId(i)))
+            .collect();
+
+        let mut minimized = DFA {
+            states: HashMap::new(),
+            start_states: self.start_states.iter().map(|state| new_id[&block_of[index_of[state]]].clone()).collect(),
+            line_start_states: self.line_start_states.iter().map(|state| new_id[&block_of[index_of[state]]].clone()).collect(),
+            classes: self.classes.clone(),
+            transitions: HashMap::new(),
+            priorities: self.priorities.clone(),
+            trailing: self.trailing.clone(),
+            shortest: self.shortest.clone(),
+            state_index: HashMap::new(),
+            next_state_id: live_blocks.len(),
+        };
+
+        for &block in &live_blocks {
+            let members: Vec<usize> = blocks[block].iter().copied().filter(|&state| state != sink).collect();
+            let representative = members[0];
+            let original = &self.states[ids[representative]];
+
+            // Keep the union of the merged NFA state sets
+            let mut nfa_states: HashMap<usize, HashSet<NFAStateId>> = HashMap::new();
+            for &state in &members {
+                for (nfa_index, set) in &self.states[ids[state]].nfa_states {
+                    nfa_states.entry(*nfa_index).or_default().extend(set.iter().cloned());
+                }
+            }
+
+            minimized.state_index.insert(state_key(&nfa_states), new_id[&block].clone());
+            minimized.states.insert(new_id[&block].clone(), DFAState {
+                nfa_states,
+                is_accepting: original.is_accepting,
+                rule_index: original.rule_index,
+                line_end_rule_index: original.line_end_rule_index,
+                end_of_input_rule_index: original.end_of_input_rule_index,
+            });
+
+            for group in &groups {
+                let next_block = block_of[target(representative, group[0])];
+                if next_block != sink_block {
+                    for &class in group {
+                        minimized.transitions.insert((new_id[&block].clone(), class), new_id[&next_block].clone());
+                    }
+                }
+            }
+        }
+
+        minimized
+    }
+
+    /// Layers `overlay` over `base`, as for a dialect adding keywords to a language.
+    /// Rule `i` of the overlay becomes rule `overlay_offset + i`, so the spec used with
+    /// the result lists the overlay's rules there, after the base's. The longest match
+    /// still wins; at equal length any overlay rule beats any base rule, and priorities
+    /// only order the rules within each side. Start condition `c` runs condition `c` of
+    /// both, or of whichever has it.
+    ///
+    /// Panics if `overlay_offset` is below the number of base rules.
+    pub fn merge(base: &DFA, overlay: &DFA, overlay_offset: usize) -> DFA {
+        assert!(overlay_offset >= base.priorities.len(), "overlay rules must come after the base rules");
+        let rule_count = overlay_offset + overlay.priorities.len();
+
+        // Lift the overlay's priorities above all of the base's, keeping their order
+        let lift = match (base.priorities.iter().max(), overlay.priorities.iter().min()) {
+            (Some(&top), Some(&bottom)) => top.saturating_sub(bottom).saturating_add(1).max(0),
+            _ => 0,
+        };
+        let mut priorities = vec![0; rule_count];
+        let mut trailing = vec![None; rule_count];
+        let mut shortest = vec![false; rule_count];
+        priorities[..base.priorities.len()].copy_from_slice(&base.priorities);
+        trailing[..base.trailing.len()].copy_from_slice(&base.trailing);
+        shortest[..base.shortest.len()].copy_from_slice(&base.shortest);
+        for (rule, priority) in overlay.priorities.iter().enumerate() {
+            priorities[overlay_offset + rule] = priority.saturating_add(lift);
+        }
+        trailing[overlay_offset..overlay_offset + overlay.trailing.len()].copy_from_slice(&overlay.trailing);
+        shortest[overlay_offset..overlay_offset + overlay.shortest.len()].copy_from_slice(&overlay.shortest);
+
+        // The runs of both sides, cut wherever either side has a boundary, so each lies
+        // within one run of each side or outside its runs altogether
+        let classes = split_runs(base.classes.iter().chain(&overlay.classes).copied().collect());
+        let mut merged = DFA {
+            states: HashMap::new(),
+            start_states: Vec::new(),
+            line_start_states: Vec::new(),
+            classes: classes.clone(),
+            transitions: HashMap::new(),
+            priorities,
+            trailing,
+            shortest,
+            state_index: HashMap::new(),
+            next_state_id: 0,
+        };
+
+        // The merged classes leaving each state, so a pair only looks at its own characters
+        let outgoing = |dfa: &DFA| {
+            let mut pieces: Vec<Vec<usize>> = vec![Vec::new(); dfa.classes.len()];
+            for (class, &(low, _)) in classes.iter().enumerate() {
+                if let Some(side_class) = dfa.class_of(low) {
+                    pieces[side_class].push(class);
+                }
+            }
+            let mut outgoing: HashMap<usize, Vec<usize>> = HashMap::new();
+            for (from, side_class) in dfa.transitions.keys() {
+                outgoing.entry(from.0).or_default().extend(&pieces[*side_class]);
+            }
+            outgoing
+        };
+        let base_outgoing = outgoing(base);
+        let overlay_outgoing = outgoing(overlay);
+
+        // Each merged state is a pair of a base state and an overlay state, either of which
+        // is missing once that side can no longer match
+        type Pair = (Option<usize>, Option<usize>);
+        let mut ids: HashMap<Pair, DFAStateId> = HashMap::new();
+        let mut worklist: Vec<(Pair, DFAStateId)> = Vec::new();
+        let mut state_for = |pair: Pair, merged: &mut DFA, worklist: &mut Vec<(Pair, DFAStateId)>| -> DFAStateId {
+            if let Some(id) = ids.get(&pair) {
+                return id.clone();
+            }
+            let slot = |slot: fn(&DFAState) -> Option<usize>| {
+                let overlay_rule = pair.1.and_then(|state| slot(&overlay.states[&DFAStateId(state)]));
+                let base_rule = pair.0.and_then(|state| slot(&base.states[&DFAStateId(state)]));
+                overlay_rule.map(|rule| overlay_offset + rule).or(base_rule)
+            };
+            let rule_index = slot(|state| state.rule_index);
+            let id = DFAStateId(merged.next_state_id);
+            merged.next_state_id += 1;
+            merged.states.insert(
+                id.clone(),
+                DFAState {
+                    nfa_states: HashMap::new(),
+                    is_accepting: rule_index.is_some(),
+                    rule_index,
+                    line_end_rule_index: slot(|state| state.line_end_rule_index),
+                    end_of_input_rule_index: slot(|state| state.end_of_input_rule_index),
+                },
+            );
+            ids.insert(pair, id.clone());
+            worklist.push((pair, id.clone()));
+            id
+        };
+
+        for condition in 0..base.start_states.len().max(overlay.start_states.len()) {
+            let start = (base.start_states.get(condition).map(|id| id.0), overlay.start_states.get(condition).map(|id| id.0));
+            let line_start = (
+                base.line_start_states.get(condition).map(|id| id.0),
+                overlay.line_start_states.get(condition).map(|id| id.0),
+            );
+            let start = state_for(start, &mut merged, &mut worklist);
+            let line_start = state_for(line_start, &mut merged, &mut worklist);
+            merged.start_states.push(start);
+            merged.line_start_states.push(line_start);
+        }
+
+        while let Some((pair, from)) = worklist.pop() {
+            let mut state_classes: Vec<usize> = Vec::new();
+            for (state, outgoing) in [(pair.0, &base_outgoing), (pair.1, &overlay_outgoing)] {
+                if let Some(side_classes) = state.and_then(|state| outgoing.get(&state)) {
+                    state_classes.extend(side_classes);
+                }
+            }
+            state_classes.sort_unstable();
+            state_classes.dedup();
+
+            for class in state_classes {
+                let step = |dfa: &DFA, state: Option<usize>| {
+                    state.and_then(|state| dfa.next_state(&DFAStateId(state), classes[class].0)).map(|id| id.0)
+                };
+                let to = state_for((step(base, pair.0), step(overlay, pair.1)), &mut merged, &mut worklist);
+                merged.transitions.insert((from.clone(), class), to);
+            }
+        }
+
+        merged.remove_dead_states();
+        merged.minimize()
+    }
+
+    /// Renders the DFA in GraphViz format. Accepting states are double circles labeled
+    /// with their rule, and parallel edges are merged into one label of character ranges.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        dot.push_str("digraph DFA {\n");
+        dot.push_str("    rankdir=LR;\n");
+        for (condition, (start_state, line_start_state)) in self.start_states.iter().zip(&self.line_start_states).enumerate() {
+            // Extra start conditions are told apart by their index
+            let (suffix, label) = match condition {
+                0 => (String::new(), String::new()),
+                _ => (format!("_{}", condition), format!("<{}> ", condition)),
+            };
+            dot.push_str(&format!("    start{} [shape=point];\n", suffix));
+            dot.push_str(&format!("    start{} -> {}", suffix, start_state.0));
+            dot.push_str(&if label.is_empty() { ";\n".to_string() } else { format!(" [label=\"{}\"];\n", label.trim_end()) });
+            if line_start_state != start_state {
+                dot.push_str(&format!("    line_start{} [shape=point];\n", suffix));
+                dot.push_str(&format!("    line_start{} -> {} [label=\"{}^\"];\n", suffix, line_start_state.0, label));
+            }
+        }
+
+        let mut state_ids: Vec<&DFAStateId> = self.states.keys().collect();
+        state_ids.sort_by_key(|id| id.0);
+        for state_id in state_ids {
+            let state = &self.states[state_id];
+            let mut label = state_id.0.to_string();
+            if let Some(rule_index) = state.rule_index {
+                label.push_str(&format!("\\nrule {}", rule_index));
+            }
+            if let Some(rule_index) = state.line_end_rule_index {
+                label.push_str(&format!("\\nrule {} at $", rule_index));
+            }
+            if let Some(rule_index) = state.end_of_input_rule_index {
+                label.push_str(&format!("\\nrule {} at \\\\z", rule_index));
+            }
+            let shape = if state.accepts_anything() { "doublecircle" } else { "circle" };
+            dot.push_str(&format!("    {} [shape={}, label=\"{}\"];\n", state_id.0, shape, label));
+        }
+
+        // Group runs by edge so each source/destination pair gets one arrow
+        let mut edges: HashMap<(usize, usize), Vec<(char, char)>> = HashMap::new();
+        for from_state in self.states.keys() {
+            for (low, high, to_state) in self.transition_runs(from_state) {
+                edges.entry((from_state.0, to_state.0)).or_default().push((low, high));
+            }
+        }
+        let mut edges: Vec<_> = edges.into_iter().collect();
+        edges.sort();
+        for ((from_state, to_state), runs) in edges {
+            dot.push_str(&format!("    {} -> {} [label=\"{}\"];\n", from_state, to_state, dot_escape(&runs_label(&runs))));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Serializes the automaton, so it can be cached and loaded back with `from_bytes`
+    /// instead of being rebuilt from the spec. The NFA states each DFA state came from
+    /// are only needed during construction and are left out.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = DFA_MAGIC.to_vec();
+        let push = |bytes: &mut Vec<u8>, value: usize| bytes.extend((value as u32).to_le_bytes());
+        let push_rule = |bytes: &mut Vec<u8>, rule: Option<usize>| bytes.extend(rule.map_or(u32::MAX, |rule| rule as u32).to_le_bytes());
+
+        let mut ids: Vec<&DFAStateId> = self.states.keys().collect();
+        ids.sort_by_key(|id| id.0);
+        push(&mut bytes, ids.len());
+        for id in ids {
+            let state = &self.states[id];
+            push(&mut bytes, id.0);
+            bytes.push(state.is_accepting as u8);
+            push_rule(&mut bytes, state.rule_index);
+            push_rule(&mut bytes, state.line_end_rule_index);
+            push_rule(&mut bytes, state.end_of_input_rule_index);
+        }
+
+        for starts in [&self.start_states, &self.line_start_states] {
+            push(&mut bytes, starts.len());
+            for id in starts {
+                push(&mut bytes, id.0);
+            }
+        }
+
+        push(&mut bytes, self.classes.len());
+        for &(low, high) in &self.classes {
+            push(&mut bytes, low as usize);
+            push(&mut bytes, high as usize);
+        }
+
+        let mut transitions: Vec<(usize, usize, usize)> =
+            self.transitions.iter().map(|((from, class), to)| (from.0, *class, to.0)).collect();
+        transitions.sort();
+        push(&mut bytes, transitions.len());
+        for (from, class, to) in transitions {
+            push(&mut bytes, from);
+            push(&mut bytes, class);
+            push(&mut bytes, to);
+        }
+
+        push(&mut bytes, self.priorities.len());
+        for priority in &self.priorities {
+            bytes.extend(priority.to_le_bytes());
+        }
+
+        // Each rule's trailing context as a tag, 0 for none, and a length
+        push(&mut bytes, self.trailing.len());
+        for split in &self.trailing {
+            let (tag, length) = match split {
+                None => (0, 0),
+                Some(TrailingSplit::Head(length)) => (1, *length),
+                Some(TrailingSplit::Tail(length)) => (2, *length),
+            };
+            bytes.push(tag);
+            push(&mut bytes, length);
+        }
+
+        push(&mut bytes, self.shortest.len());
+        bytes.extend(self.shortest.iter().map(|&shortest| shortest as u8));
+        bytes
+    }
+
+    /// Loads an automaton written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<DFA, String> {
+        if !bytes.starts_with(DFA_MAGIC) {
+            return Err("Not a serialized DFA".to_string());
+        }
+        let mut reader = ByteReader { bytes, pos: DFA_MAGIC.len() };
+
+        let mut states = HashMap::new();
+        for _ in 0..reader.count()? {
+            let id = DFAStateId(reader.usize()?);
+            let is_accepting = reader.byte()? != 0;
+            let rule_index = reader.rule()?;
+            let line_end_rule_index = reader.rule()?;
+            let end_of_input_rule_index = reader.rule()?;
+            let state = DFAState { nfa_states: HashMap::new(), is_accepting, rule_index, line_end_rule_index, end_of_input_rule_index };
+            states.insert(id, state);
+        }
+
+        let read_starts = |reader: &mut ByteReader| -> Result<Vec<DFAStateId>, String> {
+            (0..reader.count()?).map(|_| reader.state(&states)).collect()
+        };
+        let start_states = read_starts(&mut reader)?;
+        let line_start_states = read_starts(&mut reader)?;
+        if start_states.is_empty() || start_states.len() != line_start_states.len() {
+            return Err("Invalid DFA data: mismatched start states".to_string());
+        }
+
+        let mut classes: Vec<(char, char)> = Vec::new();
+        for _ in 0..reader.count()? {
+            let low = char::from_u32(reader.u32()?).ok_or("Invalid DFA data: bad class character")?;
+            let high = char::from_u32(reader.u32()?).ok_or("Invalid DFA data: bad class character")?;
+            if high < low || classes.last().is_some_and(|&(_, last_high)| last_high >= low) {
+                return Err("Invalid DFA data: classes out of order".to_string());
+            }
+            classes.push((low, high));
+        }
+
+        let mut transitions = HashMap::new();
+        for _ in 0..reader.count()? {
+            let from = reader.state(&states)?;
+            let class = reader.usize()?;
+            if class >= classes.len() {
+                return Err(format!("Invalid DFA data: unknown class {}", class));
+            }
+            let to = reader.state(&states)?;
+            transitions.insert((from, class), to);
+        }
+
+        let priorities = (0..reader.count()?)
+            .map(|_| reader.u32().map(|priority| priority as i32))
+            .collect::<Result<Vec<i32>, String>>()?;
+
+        let mut trailing = Vec::new();
+        for _ in 0..reader.count()? {
+            let tag = reader.byte()?;
+            let length = reader.usize()?;
+            trailing.push(match tag {
+                0 => None,
+                1 => Some(TrailingSplit::Head(length)),
+                2 => Some(TrailingSplit::Tail(length)),
+                _ => return Err("Invalid DFA data: bad trailing context".to_string()),
+            });
+        }
+
+        let shortest = (0..reader.count()?)
+            .map(|_| reader.byte().map(|shortest| shortest != 0))
+            .collect::<Result<Vec<bool>, String>>()?;
+
+        if reader.pos != bytes.len() {
+            return Err("Invalid DFA data: trailing bytes".to_string());
+        }
+
+        let next_state_id = states.keys().map(|id| id.0 + 1).max().unwrap_or(0);
+        Ok(DFA {
+            states,
+            start_states,
+            line_start_states,
+            classes,
+            transitions,
+            priorities,
+            trailing,
+            shortest,
+            state_index: HashMap::new(),
+            next_state_id,
+        })
+    }
+
+    /// Tokenizes `input` directly against the DFA. Each entry is the lexeme, its line
+    /// and column, its byte offset, and the index of the winning rule; unmatched
+    /// characters are skipped and the list ends with an empty EOF marker.
+    pub fn simulate(&self, input: &str) -> Vec<Match> {
+        self.simulate_with(input, 1)
+    }
+
+    /// Like `simulate`, with a tab advancing the column to the next tab stop every
+    /// `tab_width` columns rather than by one
+    pub fn simulate_with(&self, input: &str, tab_width: usize) -> Vec<Match> {
+        drop_unmatched(scan(input, tab_width.max(1), RuleTables::default(), None, |condition, rest, at_line_start| {
+            self.longest_match(condition, rest, at_line_start)
+        }))
+        .collect()
+    }
+
+    /// Like `simulate`, for input that starts at `line` and `column` of a larger file, as
+    /// when lexing an embedded fragment
+    pub fn simulate_at(&self, input: &str, line: usize, column: usize) -> Vec<Match> {
+        let matches = scan(input, 1, RuleTables::default(), None, |condition, rest, at_line_start| {
+            self.longest_match(condition, rest, at_line_start)
+        });
+        drop_unmatched(matches.starting_at(0, line, column)).collect()
+    }
+
+    /// Like `simulate`, also returning each character no rule matched with its line and
+    /// column, in input order, where `simulate` skips them silently
+    pub fn simulate_with_skipped(&self, input: &str) -> (Vec<Match>, Vec<(char, usize, usize)>) {
+        let matches = scan(input, 1, RuleTables::default(), None, |condition, rest, at_line_start| {
+            self.longest_match(condition, rest, at_line_start)
+        });
+        let (matches, unmatched): (Vec<Match>, Vec<Match>) =
+            matches.partition(|(lexeme, _, _, _, rule_index)| rule_index.is_some() || lexeme.is_empty());
+        let skipped = unmatched
+            .into_iter()
+            .filter_map(|(lexeme, line, column, _, _)| Some((lexeme.chars().next()?, line, column)))
+            .collect();
+        (matches, skipped)
+    }
+
+    /// Like `simulate`, reading the whole input from `reader` first
+    pub fn simulate_reader(&self, reader: impl Read) -> io::Result<Vec<Match>> {
+        let input = io::read_to_string(reader)?;
+        Ok(self.simulate(&input))
+    }
+
+    /// Tokenizes `input` and applies each rule's action from `spec`, the way the
+    /// generated lexer does. SKIP matches are dropped, ERR matches become `ERROR`
+    /// tokens carrying the message, and the list ends with an `EOF` token. BEGIN
+    /// actions switch start condition, which `simulate` never does.
+    pub fn tokenize(&self, spec: &Spec, input: &str) -> Vec<Token> {
+        self.token_iter(spec, input).collect()
+    }
+
+    /// Like `tokenize`, with a tab advancing the column to the next tab stop every
+    /// `tab_width` columns, as in `simulate_with`
+    pub fn tokenize_with(&self, spec: &Spec, input: &str, tab_width: usize) -> Vec<Token> {
+        self.relex_from(spec, input, 0, 1, 1, tab_width).collect()
+    }
+
+    /// Like `tokenize`, matching each token only when the iterator asks for the next one
+    pub fn token_iter<'a>(&'a self, spec: &'a Spec, input: &'a str) -> impl Iterator<Item = Token> + 'a {
+        self.relex_from(spec, input, 0, 1, 1, 1)
+    }
+
+    /// Like `token_iter`, starting at byte offset `start` of `input`, which is at `line`
+    /// and `column`, for re-lexing after an edit. Tokens carry their offsets and positions
+    /// in the whole input. Tokens don't depend on what came before, so any token boundary
+    /// in `INITIAL` gives the same tokens as a full pass; lexing restarts in `INITIAL`.
+    /// Tabs move to the next stop every `tab_width` columns, so pass 1 to count them as one.
+    ///
+    /// Panics if `start` is past the end of `input` or inside a character.
+    pub fn relex_from<'a>(
+        &'a self,
+        spec: &'a Spec,
+        input: &'a str,
+        start: usize,
+        line: usize,
+        column: usize,
+        tab_width: usize,
+    ) -> impl Iterator<Item = Token> + 'a {
+        self.scan_tokens(spec, input, start, (line, column), tab_width.max(1), false)
+    }
+
+    /// Like `tokenize`, for input that isn't UTF-8 text. Each byte is one character, the
+    /// one with the same value, so `\xff` matches the byte 0xff and columns count bytes,
+    /// tabs included. Build the DFA with `build_byte_dfa` for `.` and negated classes to
+    /// cover every byte. Offsets index `input`, and lexemes hold their bytes as those characters, so
+    /// `&input[token.start..token.end]` gives the bytes themselves.
+    pub fn tokenize_bytes(&self, spec: &Spec, input: &[u8]) -> Vec<Token> {
+        let text: String = input.iter().map(|&byte| char::from(byte)).collect();
+        // The offset in `input` of each character boundary of `text`
+        let mut offsets = vec![0; text.len() + 1];
+        for (index, (offset, _)) in text.char_indices().enumerate() {
+            offsets[offset] = index;
+        }
+        offsets[text.len()] = input.len();
+
+        self.scan_tokens(spec, &text, 0, (1, 1), 1, true)
+            .map(|mut token| {
+                token.start = offsets[token.start];
+                token.end = offsets[token.end];
+                token.capture = token.capture.map(|(start, end)| (offsets[start], offsets[end]));
+                token
+            })
+            .collect()
+    }
+
+    fn scan_tokens<'a>(
+        &'a self,
+        spec: &'a Spec,
+        input: &'a str,
+        start: usize,
+        (line, column): (usize, usize),
+        tab_width: usize,
+        bytes: bool,
+    ) -> impl Iterator<Item = Token> + 'a {
+        let recover: Option<Box<dyn Fn(usize, char) -> bool>> = (spec.unmatched == Unmatched::Recover).then(|| {
+            let start_chars: Vec<Vec<(char, char)>> =
+                (0..self.start_states.len()).map(|condition| self.start_chars(condition)).collect();
+            Box::new(move |condition: usize, ch| in_ranges(&start_chars[condition], ch)) as Box<dyn Fn(usize, char) -> bool>
+        });
+        let matches = scan(input, tab_width, RuleTables::new(spec), recover, |condition, rest, at_line_start| {
+            self.longest_match(condition, rest, at_line_start)
+        });
+        apply_actions(spec, tab_width, bytes, matches.starting_at(start, line, column))
+    }
+
+    /// The characters with a transition out of a start state of `condition`, i.e. those
+    /// some token can begin with, as sorted runs
+    pub fn start_chars(&self, condition: usize) -> Vec<(char, char)> {
+        let starts = [&self.start_states[condition], &self.line_start_states[condition]];
+        let runs = self
+            .transitions
+            .keys()
+            .filter(|(from_state, _)| starts.contains(&from_state))
+            .map(|(_, class)| self.classes[*class])
+            .collect();
+        merge_ranges(runs)
+    }
+
+    /// The name of the token a match ending in `state` produces under `spec`: the token
+    /// name of its rule (the first, for a rule that makes several), or `ERROR` for an
+    /// `(ERR)` rule. `None` for states that accept nothing and for rules that skip
+    /// without `keep` or switch state. A `$` rule's state counts as accepting, though it
+    /// only matches when a newline or end of input follows.
+    pub fn state_token_name<'a>(&self, state: &DFAStateId, spec: &'a Spec) -> Option<&'a str> {
+        match self.rule_action(state, spec)?.1 {
+            Action::Token { name, .. } => Some(name),
+            Action::Tokens { tokens, .. } => tokens.first().map(|(name, _)| name.as_str()),
+            Action::Error(_) => Some("ERROR"),
+            Action::Skip(name) => name.as_deref(),
+            Action::Begin(_) | Action::Push(_) | Action::Pop => None,
+        }
+    }
+
+    /// Every accepting state in id order, with the index of the rule it accepts and
+    /// that rule's action in `spec`
+    pub fn accepting_states<'a>(&self, spec: &'a Spec) -> Vec<(DFAStateId, usize, &'a Action)> {
+        let mut ids: Vec<&DFAStateId> = self.states.keys().collect();
+        ids.sort_by_key(|id| id.0);
+        ids.into_iter()
+            .filter_map(|id| self.rule_action(id, spec).map(|(rule_index, action)| (id.clone(), rule_index, action)))
+            .collect()
+    }
+
+    // The rule `state` accepts, preferring the one that needs no `$` or `\z`, and its action
+    fn rule_action<'a>(&self, state: &DFAStateId, spec: &'a Spec) -> Option<(usize, &'a Action)> {
+        let state = self.states.get(state)?;
+        let rule_index = state.rule_index.or(state.line_end_rule_index).or(state.end_of_input_rule_index)?;
+        Some((rule_index, &spec.rules.get(rule_index)?.action))
+    }
+
+    // The byte length of the longest non-empty match at the start of `input` and its
+    // rule. See the type docs for how the rule is chosen; no match gives (0, None).
+    fn longest_match(&self, condition: usize, input: &str, at_line_start: bool) -> (usize, Option<usize>) {
+        let starts = if at_line_start { &self.line_start_states } else { &self.start_states };
+        let mut current_state = &starts[condition];
+        // A start state accepting is an empty match, which is never a token
+        let mut last_accepting_pos = 0;
+        let mut last_accepting_rule = None;
+
+        for (pos, ch) in input.char_indices() {
+            if let Some(next_state_id) = self.next_state(current_state, ch) {
+                current_state = next_state_id;
+
+                let end = pos + ch.len_utf8();
+                if let Some(rule_index) = self.accepting_rule(current_state, input[end..].chars().next()) {
+                    last_accepting_pos = end;
+                    last_accepting_rule = Some(rule_index);
+                    if self.shortest.get(rule_index) == Some(&true) {
+                        break;
                     }
                 }
+            } else {
+                break;
             }
         }
+
+        (without_context(input, last_accepting_pos, last_accepting_rule, &self.trailing), last_accepting_rule)
     }
 
+    // The winning rule in a state, given the character that follows the match
+    fn accepting_rule(&self, state_id: &DFAStateId, next: Option<char>) -> Option<usize> {
+        let state = self.states.get(state_id)?;
+        let at_line_end = matches!(next, None | Some('\n'));
+
+        let rule = match (state.rule_index, state.line_end_rule_index) {
+            (Some(rule), Some(line_end_rule)) if at_line_end => best_rule([rule, line_end_rule], &self.priorities),
+            (None, Some(line_end_rule)) if at_line_end => Some(line_end_rule),
+            (rule, _) => rule,
+        };
+        match state.end_of_input_rule_index {
+            Some(end_rule) if next.is_none() => best_rule(rule.into_iter().chain([end_rule]), &self.priorities),
+            _ => rule,
+        }
+    }
+}
+
+// The positions in `conditions` less the rules that can never win because a rule with
+// the same regex and build options and higher precedence is active in the same
+// condition. Shared regexes are common across rules with different actions or macros,
+// and each copy would otherwise be carried through every DFA state.
+fn without_duplicates(nfas: &[(NFA, usize)], conditions: &[Vec<usize>], priorities: &[i32]) -> Vec<Vec<usize>> {
+    // Group rules built from equal regexes, comparing only those of the same size. NFAs
+    // not built from a single regex are each their own group.
+    let mut buckets: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    let mut group = Vec::with_capacity(nfas.len());
+    for (position, (nfa, _)) in nfas.iter().enumerate() {
+        let Some(source) = nfa.source() else {
+            group.push(position);
+            continue;
+        };
+        let bucket = buckets.entry((nfa.states.len(), nfa.transitions.len())).or_default();
+        let first = bucket.iter().copied().find(|&other| nfas[other].0.source() == Some(source));
+        if first.is_none() {
+            bucket.push(position);
+        }
+        group.push(first.unwrap_or(position));
+    }
+
+    conditions
+        .iter()
+        .map(|active| {
+            let mut best: HashMap<usize, usize> = HashMap::new();
+            for &position in active {
+                let kept = best.entry(group[position]).or_insert(position);
+                if best_rule([nfas[*kept].1, nfas[position].1], priorities) == Some(nfas[position].1) {
+                    *kept = position;
+                }
+            }
+            active.iter().copied().filter(|position| best[&group[*position]] == *position).collect()
+        })
+        .collect()
+}
+
+// Header of the `to_bytes` format, with its version
+const DFA_MAGIC: &[u8] = b"DLEXDFA5";
+
+// Little-endian cursor over `to_bytes` output
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ByteReader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("Invalid DFA data: unexpected end")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut word = [0; 4];
+        for byte in &mut word {
+            *byte = self.byte()?;
+        }
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        self.u32().map(|value| value as usize)
+    }
+
+    // A length prefix, bounded by what is left so bad data cannot ask for huge loops
+    fn count(&mut self) -> Result<usize, String> {
+        let count = self.usize()?;
+        if count > self.bytes.len() - self.pos {
+            return Err("Invalid DFA data: length out of range".to_string());
+        }
+        Ok(count)
+    }
+
+    fn rule(&mut self) -> Result<Option<usize>, String> {
+        self.u32().map(|rule| (rule != u32::MAX).then_some(rule as usize))
+    }
+
+    // A state id, which must name one of the states already read
+    fn state(&mut self, states: &HashMap<DFAStateId, DFAState>) -> Result<DFAStateId, String> {
+        let id = DFAStateId(self.usize()?);
+        if !states.contains_key(&id) {
+            return Err(format!("Invalid DFA data: unknown state {}", id.0));
+        }
+        Ok(id)
+    }
+}
+
+// Labels inclusive runs of characters, e.g. "a-z,_"; a run of two is written out
+pub(crate) fn runs_label(runs: &[(char, char)]) -> String {
+    let mut parts = Vec::new();
+    for &(low, high) in runs {
+        match high as u32 - low as u32 {
+            0 => parts.push(display_char(low)),
+            1 => {
+                parts.push(display_char(low));
+                parts.push(display_char(high));
+            }
+            _ => parts.push(format!("{}-{}", display_char(low), display_char(high))),
+        }
+    }
+    parts.join(",")
+}
+
+fn display_char(ch: char) -> String {
+    match ch {
+        ' ' => "' '".to_string(),
+        ',' | '-' => format!("'{}'", ch),
+        _ => ch.escape_debug().to_string(),
+    }
+}
+
+pub(crate) fn dot_escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// The characters some transition consumes, split into runs that no transition tells
+// apart, so that large ranges like `\p{L}` are moved on once and stored as one run
+fn alphabet_classes(nfas: &[(NFA, usize)]) -> Vec<(char, char)> {
+    let mut consumed = vec![('\n', '\n'), ('\t', '\t')];
+    for (_, transition) in nfas.iter().flat_map(|(nfa, _)| nfa.transitions.keys()) {
+        match transition {
+            Transition::Char(ch) => consumed.push((*ch, *ch)),
+            Transition::Range(low, high) => consumed.push((*low, *high)),
+            _ => {}
+        }
+    }
+    split_runs(consumed)
+}
+
+// The characters in `ranges`, which may overlap, as sorted runs cut at every range's ends
+fn split_runs(ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    let mut boundaries: Vec<char> = ranges.iter().flat_map(|&(low, high)| [Some(low), char_after(high)]).flatten().collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut runs = Vec::new();
+    for (low, high) in merge_ranges(ranges) {
+        let mut start = low;
+        let inner = boundaries.partition_point(|&boundary| boundary <= low);
+        for &boundary in boundaries[inner..].iter().take_while(|&&boundary| boundary <= high) {
+            runs.push((start, char_before(boundary)));
+            start = boundary;
+        }
+        runs.push((start, high));
+    }
+    runs
+}
+
+// Whether `low` directly follows `high`, stepping over the surrogates that are not chars
+fn adjacent(high: char, low: char) -> bool {
+    char_after(high) == Some(low)
+}
+
+fn check_accepting(
+    nfa_states: &HashMap<usize, HashSet<NFAStateId>>,
+    nfas: &[(NFA, usize)],
+    priorities: &[i32],
+) -> (bool, Option<usize>) {
+    let mut accepting_rules = Vec::new();
+
+    for (nfa_index, (nfa, rule_index)) in nfas.iter().enumerate() {
+        if let Some(current_nfa_states) = nfa_states.get(&nfa_index)
+            && nfa.accept_states.iter().any(|accept_state| current_nfa_states.contains(accept_state))
+        {
+            accepting_rules.push(*rule_index);
+        }
+    }
+
+    // Priority decides between the accepting rules first, then line order
+    let best_rule_index = best_rule(accepting_rules, priorities);
     (best_rule_index.is_some(), best_rule_index)
 }
+
+// The best rule accepting once the zero-width `anchor` ('$' or '\z') has been passed
+fn check_anchor_accepting(
+    nfa_states: &HashMap<usize, HashSet<NFAStateId>>,
+    nfas: &[(NFA, usize)],
+    priorities: &[i32],
+    anchor: Transition,
+) -> Option<usize> {
+    let mut accepting_rules = Vec::new();
+
+    for (nfa_index, (nfa, rule_index)) in nfas.iter().enumerate() {
+        if let Some(current_nfa_states) = nfa_states.get(&nfa_index) {
+            let moved = nfa.move_on(current_nfa_states, anchor.clone());
+            let closure = nfa.epsilon_closure(&moved);
+            if nfa.accept_states.iter().any(|accept_state| closure.contains(accept_state)) {
+                accepting_rules.push(*rule_index);
+            }
+        }
+    }
+
+    best_rule(accepting_rules, priorities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_dfa, parse_spec};
+
+    fn names(spec: &str, input: &str) -> Vec<String> {
+        let spec = parse_spec(spec).unwrap();
+        build_dfa(&spec).unwrap().tokenize(&spec, input).into_iter().map(|token| token.name).collect()
+    }
+
+    fn spans(spec: &str, input: &str) -> Vec<(String, usize, usize)> {
+        let spec = parse_spec(spec).unwrap();
+        build_dfa(&spec).unwrap().tokenize(&spec, input).into_iter().map(|token| (token.name, token.start, token.end)).collect()
+    }
+
+    fn span(name: &str, start: usize, end: usize) -> (String, usize, usize) {
+        (name.to_string(), start, end)
+    }
+
+    // The DFA straight out of subset construction, before states merge
+    fn constructed(spec: &crate::Spec) -> DFA {
+        let nfas = crate::rule_nfas(spec).unwrap();
+        DFA::from_nfas_with(nfas, &crate::condition_rules(spec), &crate::rule_priorities(spec), None, spec.alphabet.as_deref()).unwrap()
+    }
+
+    #[test]
+    fn tab_and_newline_match() {
+        let spec = "[a-z]+ WORD true\n\\n NL false\n\\t+ TAB false\n";
+        assert_eq!(names(spec, "ab\n\t\tcd"), ["WORD", "NL", "TAB", "WORD", "EOF"]);
+    }
+
+    #[test]
+    fn minimizing_keeps_the_tokens() {
+        let spec = parse_spec("if IF false\nin IN false\nint INT false\ninterface IFACE false\n[a-z]+ ID true\n\\_+ (SKIP)\n").unwrap();
+        let dfa = constructed(&spec);
+        let minimized = dfa.minimize();
+        assert!(minimized.states.len() < dfa.states.len(), "{} states, {} before", minimized.states.len(), dfa.states.len());
+        let input = "if in int interface inter i ifs intx interfaces";
+        assert_eq!(minimized.tokenize(&spec, input), dfa.tokenize(&spec, input));
+        let names: Vec<String> = minimized.tokenize(&spec, input).into_iter().map(|token| token.name).collect();
+        assert_eq!(names, ["IF", "IN", "INT", "IFACE", "ID", "ID", "ID", "ID", "ID", "EOF"]);
+    }
+
+    #[test]
+    fn large_spec_builds_quickly() {
+        // Hundreds of keywords sharing prefixes, as a quadratic state lookup would struggle with
+        let mut spec: String = (0..100).map(|index| format!("kw{}x{} KW{} false\n", index, index * 7, index)).collect();
+        spec.push_str("[a-z][a-z0-9]* ID true\n[0-9]+ NUM true\n\\_+ (SKIP)\n");
+        let spec = parse_spec(&spec).unwrap();
+        let start = std::time::Instant::now();
+        let dfa = build_dfa(&spec).unwrap();
+        // Well under a second even unoptimized; the limit leaves room for a slow machine
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "took {:?}", start.elapsed());
+        assert_eq!(dfa.tokenize(&spec, "kw42x294 kw42x29 7").into_iter().map(|token| token.name).collect::<Vec<_>>(), ["KW42", "ID", "NUM", "EOF"]);
+    }
+
+    #[test]
+    fn shorthand_classes() {
+        let spec = "\\w+ WORD true\n\\d+ NUM true\n\\s+ WS false\n";
+        assert_eq!(names(spec, "abc 123"), ["WORD", "WS", "WORD", "EOF"]);
+        // `\w` matches digits too, so `\d+` only wins the tie when listed first
+        let spec = "\\d+ NUM true\n\\w+ WORD true\n\\s+ WS false\n";
+        assert_eq!(names(spec, "abc 123"), ["WORD", "WS", "NUM", "EOF"]);
+        // Inside brackets, and negated
+        assert_eq!(names("[\\d_]+ NUM true\n\\D+ OTHER true\n", "1_2ab"), ["NUM", "OTHER", "EOF"]);
+    }
+
+    #[test]
+    fn unicode_escape_matches() {
+        assert_eq!(names("\\u{00E9} E true\n", "é"), ["E", "EOF"]);
+    }
+
+    #[test]
+    fn line_start_comment() {
+        let spec = "^#.* COMMENT true\n\\#[0-9]+ REF true\n[a-z]+ ID true\n[\\_\\n]+ (SKIP)\n";
+        // Only a `#` in the first column starts a comment, and it stops at the newline
+        assert_eq!(names(spec, "# note\na #1\n#2 b"), ["COMMENT", "ID", "REF", "COMMENT", "EOF"]);
+    }
+
+    #[test]
+    fn dot_has_a_node_per_state_and_an_edge_per_pair() {
+        let dot = crate::compile_spec("[a-z]+ ID true\n[0-9]+ NUM true\n").unwrap().to_dot();
+        let nodes: Vec<&str> = dot.lines().filter(|line| line.contains("[shape=") && !line.contains("point")).collect();
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains(" -> ") && !line.contains("start")).collect();
+        assert_eq!(nodes.len(), 3, "{}", dot);
+        assert_eq!(nodes.iter().filter(|node| node.contains("doublecircle")).count(), 2);
+        // Each run of letters or digits is one labelled edge, not one per character
+        assert_eq!(edges.len(), 4, "{}", dot);
+        assert_eq!(edges.iter().filter(|edge| edge.contains("label=\"a-z\"")).count(), 2);
+    }
+
+    #[test]
+    fn overlapping_ranges_lead_to_the_right_states() {
+        // 'h'-'m' is in both ranges, so after it either rule can still finish
+        let spec = "[a-m]x AX true\n[h-z]y ZY true\n\\_+ (SKIP)\n. ANY true\n";
+        assert_eq!(names(spec, "hx hy ax zy ay zx"), ["AX", "ZY", "AX", "ZY", "ANY", "ANY", "ANY", "ANY", "EOF"]);
+        // `.` is a range edge too, and still stops short of a newline
+        assert_eq!(names(spec, "a\nb"), ["ANY", "ANY", "EOF"]);
+    }
+
+    #[test]
+    fn quoted_string_is_lexed_in_its_own_state() {
+        let spec = "%state STRING\n\\\" (BEGIN STRING)\n<STRING>[^\\\"]+ STR true\n<STRING>\\\" (BEGIN INITIAL)\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+        let spec = parse_spec(spec).unwrap();
+        let tokens = build_dfa(&spec).unwrap().tokenize(&spec, "say \"hi there\" x");
+        let lexemes: Vec<(&str, Option<&str>)> = tokens.iter().map(|token| (token.name.as_str(), token.lexeme.as_deref())).collect();
+        // Inside the quotes the space is part of the string and `ID` doesn't apply
+        assert_eq!(lexemes, [("ID", Some("say")), ("STR", Some("hi there")), ("ID", Some("x")), ("EOF", None)]);
+    }
+
+    #[test]
+    fn higher_priority_keyword_beats_an_earlier_rule() {
+        let spec = "[a-z]+ ID true\nif IF false (PRIO 1)\n\\_+ (SKIP)\n";
+        assert_eq!(names(spec, "if iff"), ["IF", "ID", "EOF"]);
+
+        let dfa = build_dfa(&parse_spec(spec).unwrap()).unwrap();
+        let rules: Vec<(String, Option<usize>)> = dfa.simulate("if iff").into_iter().map(|(lexeme, _, _, _, rule)| (lexeme, rule)).collect();
+        assert_eq!(rules[0], ("if".to_string(), Some(1)));
+        assert_eq!(rules[2], ("iff".to_string(), Some(0)));
+    }
+
+    #[test]
+    fn dead_states_go_and_tokens_stay() {
+        // `[^\s\S]` matches nothing, so after an `a` no rule can finish
+        let spec = parse_spec("a[^\\s\\S] X true\nb Y true\n").unwrap();
+        let dfa = constructed(&spec);
+        let mut pruned = dfa.clone();
+        pruned.remove_dead_states();
+        assert!(pruned.states.len() < dfa.states.len(), "{} states, {} before", pruned.states.len(), dfa.states.len());
+        let input = "abacb";
+        assert_eq!(pruned.tokenize(&spec, input), dfa.tokenize(&spec, input));
+        assert_eq!(names("a[^\\s\\S] X true\nb Y true\n", input), ["Y", "Y", "EOF"]);
+    }
+
+    #[test]
+    fn case_insensitive_keyword_matches_any_case() {
+        let spec = "then THEN true (CI)\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+        assert_eq!(names(spec, "Then THEN then thenx"), ["THEN", "THEN", "THEN", "ID", "EOF"]);
+        // `[a-z]+` keeps its case, so it can't take the longer match from the `T`
+        assert_eq!(names(spec, "Thenx"), ["THEN", "ID", "EOF"]);
+        // The directive sets it for every rule
+        assert_eq!(names("%case-insensitive\nthen THEN true\n[a-z]+ ID true\n\\_+ (SKIP)\n", "THEN Thenx"), ["THEN", "ID", "EOF"]);
+    }
+
+    #[test]
+    fn dotall_comment_spans_lines() {
+        let input = "/* a\nb */ x";
+        let spec = "\\/\\*.*?\\*\\/ COMMENT true (DOTALL)\n[a-z] ID true\n[\\_\\n]+ (SKIP)\n";
+        assert_eq!(spans(spec, input), [span("COMMENT", 0, 9), span("ID", 10, 11), span("EOF", 11, 11)]);
+        // A negated class follows the flag too
+        let spec = "\\/\\*[^*]*\\*\\/ COMMENT true (DOTALL)\n[a-z] ID true\n[\\_\\n]+ (SKIP)\n";
+        assert_eq!(spans(spec, input), [span("COMMENT", 0, 9), span("ID", 10, 11), span("EOF", 11, 11)]);
+        // Without it the comment can't get past the newline
+        let spec = "\\/\\*[^*]*\\*\\/ COMMENT true\n[a-z] ID true\n[\\_\\n]+ (SKIP)\n";
+        assert_eq!(names(spec, input), ["ID", "ID", "ID", "EOF"]);
+    }
+
+    #[test]
+    fn empty_branch() {
+        let spec = "(ab|)c X true\n[a-z] ID true\n";
+        assert_eq!(names(spec, "abc"), ["X", "EOF"]);
+        assert_eq!(names(spec, "c"), ["X", "EOF"]);
+        assert_eq!(names(spec, "ac"), ["ID", "X", "EOF"]);
+    }
+
+    #[test]
+    fn saved_dfa_lexes_the_same() {
+        let spec = "%state STR\n\" (BEGIN STR)\n<STR>\" (BEGIN INITIAL)\n<STR>[^\"]+ TEXT true\nab AB true\n[a-z] CHAR true\n";
+        let spec = parse_spec(spec).unwrap();
+        let dfa = build_dfa(&spec).unwrap();
+        let bytes = dfa.to_bytes();
+        let loaded = super::DFA::from_bytes(&bytes).unwrap();
+        let input = "abcd \"ab cd\" abce";
+        let tokens = loaded.tokenize(&spec, input);
+        let names: Vec<&str> = tokens.iter().map(|token| token.name.as_str()).collect();
+        assert_eq!(names, ["AB", "CHAR", "CHAR", "TEXT", "AB", "CHAR", "CHAR", "EOF"]);
+        assert_eq!(tokens, dfa.tokenize(&spec, input));
+        assert_eq!(loaded.to_bytes(), bytes);
+
+        // Every cut short copy is an error rather than a panic
+        for end in 0..bytes.len() {
+            assert!(super::DFA::from_bytes(&bytes[..end]).is_err(), "{} bytes", end);
+        }
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert_eq!(super::DFA::from_bytes(&wrong_magic).unwrap_err(), "Not a serialized DFA");
+    }
+
+    #[test]
+    fn skipped_characters_keep_byte_offsets() {
+        // Nothing matches 'é' or '日', which take two and three bytes
+        let spec = "[a-z]+ ID true\n";
+        assert_eq!(spans(spec, "ab\u{e9}cd\u{65e5}e"), [span("ID", 0, 2), span("ID", 4, 6), span("ID", 9, 10), span("EOF", 10, 10)]);
+        let spec = parse_spec(spec).unwrap();
+        let columns: Vec<usize> = build_dfa(&spec).unwrap().tokenize(&spec, "ab\u{e9}cd\u{65e5}e").into_iter().map(|token| token.column).collect();
+        assert_eq!(columns, [1, 4, 7, 8]);
+    }
+
+    #[test]
+    fn tabs_move_to_the_next_stop() {
+        let spec = parse_spec("[a-z]+ ID true\n[\\t\\_]+ (SKIP)\n").unwrap();
+        let dfa = build_dfa(&spec).unwrap();
+        let input = "\tab c\td";
+        let columns: Vec<usize> = dfa.simulate_with(input, 4).into_iter().map(|(_, _, column, ..)| column).collect();
+        assert_eq!(columns, [1, 5, 7, 8, 9, 13, 14]);
+        let columns: Vec<usize> = dfa.tokenize_with(&spec, input, 4).into_iter().map(|token| token.column).collect();
+        assert_eq!(columns, [5, 8, 13, 14]);
+        // A width of 1 counts a tab as one column, like any other character
+        let columns: Vec<usize> = dfa.tokenize(&spec, input).into_iter().map(|token| token.column).collect();
+        assert_eq!(columns, [2, 5, 7, 8]);
+    }
+
+    #[test]
+    fn empty_match_is_never_a_token() {
+        // `a*` matches nothing at ' ' and '-', which are skipped rather than stalling,
+        // and ties go to the rule listed first
+        let spec = "a* A true\nab X true\n[a-z]+ Y true\n";
+        assert_eq!(spans(spec, "ab aa-"), [span("X", 0, 2), span("A", 3, 5), span("EOF", 6, 6)]);
+    }
+
+    #[test]
+    fn state_token_names_follow_the_action() {
+        let spec = parse_spec("ab AB true\n[0-9]+ (ERR) \"digits\"\n\\_+ (SKIP)\n").unwrap();
+        let dfa = build_dfa(&spec).unwrap();
+        let after = |input: &str| {
+            let start = dfa.start_states[0].clone();
+            input.chars().try_fold(start, |state, ch| dfa.next_state(&state, ch).cloned()).unwrap()
+        };
+        assert_eq!(dfa.state_token_name(&after(""), &spec), None);
+        assert_eq!(dfa.state_token_name(&after("a"), &spec), None);
+        assert_eq!(dfa.state_token_name(&after("ab"), &spec), Some("AB"));
+        assert_eq!(dfa.state_token_name(&after("12"), &spec), Some("ERROR"));
+        assert_eq!(dfa.state_token_name(&after("  "), &spec), None);
+    }
+
+    #[test]
+    fn trailing_context_is_left_unconsumed() {
+        let spec = "ab/cd AB true\n[a-z] CHAR true\n";
+        assert_eq!(spans(spec, "abcd"), [span("AB", 0, 2), span("CHAR", 2, 3), span("CHAR", 3, 4), span("EOF", 4, 4)]);
+        // Without the `cd` after it, `ab` is not an AB
+        assert_eq!(names(spec, "abce"), ["CHAR", "CHAR", "CHAR", "CHAR", "EOF"]);
+        // An escaped slash is just a slash
+        assert_eq!(spans("ab\\/cd AB true\n", "ab/cd"), [span("AB", 0, 5), span("EOF", 5, 5)]);
+    }
+
+    #[test]
+    fn duplicate_rule_keeps_earlier_precedence() {
+        assert_eq!(names("[a-z]+ FIRST true\n[a-z]+ SECOND true\n\\  WS false\n", "ab cd"), ["FIRST", "WS", "FIRST", "EOF"]);
+        // A priority on the later copy still lets it win
+        assert_eq!(names("[a-z]+ FIRST true\n[a-z]+ SECOND true (PRIO 1)\n", "ab"), ["SECOND", "EOF"]);
+        // Options are part of what makes rules the same
+        assert_eq!(names("ab FIRST true\nab SECOND true (CI)\n", "AB"), ["SECOND", "EOF"]);
+    }
+
+    #[test]
+    fn letter_property_is_stored_as_runs() {
+        let spec = parse_spec("\\p{L}+ WORD true\n").unwrap();
+        let dfa = build_dfa(&spec).unwrap();
+        // One transition per run of letters out of each state, not one per letter
+        assert!(dfa.transitions.len() < 2 * 1000, "{} transitions", dfa.transitions.len());
+        assert_eq!(spans("\\p{L}+ WORD true\n", "café élan"), [span("WORD", 0, 5), span("WORD", 6, 11), span("EOF", 11, 11)]);
+    }
+
+    #[test]
+    fn negated_property_covers_all_of_unicode() {
+        // '€' and '—' are outside printable ASCII but are not letters either
+        let spec = "[^\\p{L}\\_]+ PUNCT true\n\\P{L}+ OTHER true\n";
+        assert_eq!(spans(spec, "a€—b"), [span("PUNCT", 1, 7), span("EOF", 8, 8)]);
+        assert_eq!(spans(spec, "é€ é"), [span("OTHER", 2, 6), span("EOF", 8, 8)]);
+        assert_eq!(spans(spec, "€\n"), [span("PUNCT", 0, 3), span("EOF", 4, 4)]);
+    }
+
+    #[test]
+    fn negated_property_in_a_class_follows_dotall() {
+        assert_eq!(spans("[\\P{L}]+ X true (DOTALL)\n", "1\n2"), [span("X", 0, 3), span("EOF", 3, 3)]);
+        assert_eq!(spans("\\P{L}+ X true (DOTALL)\n", "1\n2"), [span("X", 0, 3), span("EOF", 3, 3)]);
+        // Without the flag '\n' is left out, as for `.`
+        assert_eq!(spans("[\\P{L}]+ X true\n", "1\n2"), [span("X", 0, 1), span("X", 2, 3), span("EOF", 3, 3)]);
+    }
+
+    #[test]
+    fn negated_escape_in_a_class_follows_dotall() {
+        assert_eq!(spans("[\\D]+ X true (DOTALL)\n", "a\nb"), [span("X", 0, 3), span("EOF", 3, 3)]);
+        assert_eq!(spans("\\D+ X true (DOTALL)\n", "a\nb"), [span("X", 0, 3), span("EOF", 3, 3)]);
+        // Without the flag '\n' is left out, as for `.`
+        assert_eq!(spans("[\\D]+ X true\n", "a\nb"), [span("X", 0, 1), span("X", 2, 3), span("EOF", 3, 3)]);
+    }
+
+    #[test]
+    fn relexing_mid_line_matches_a_full_pass() {
+        let spec = parse_spec("[a-zé]+ ID true\n[0-9]+ NUM true\n[\\_\\n]+ (SKIP)\n").unwrap();
+        let dfa = build_dfa(&spec).unwrap();
+        let input = "let x 42\nfoé bar 7\nbaz";
+        let start = input.find("bar").unwrap();
+        let tail: Vec<_> = dfa.tokenize(&spec, input).into_iter().filter(|token| token.start >= start).collect();
+        // `bar` is at column 5 of line 2, past the two bytes of 'é'
+        let relexed: Vec<_> = dfa.relex_from(&spec, input, start, 2, 5, 1).collect();
+        assert_eq!(relexed, tail);
+        assert_eq!((relexed[0].line, relexed[0].column, relexed[0].start), (2, 5, 14));
+    }
+
+    #[test]
+    fn end_of_input_rule_fires_only_at_the_end() {
+        let spec = "end\\z LAST true\nend END true\n[a-z]+ ID true\n[\\_\\n]+ (SKIP)\n";
+        assert_eq!(spans(spec, "end x end"), [span("END", 0, 3), span("ID", 4, 5), span("LAST", 6, 9), span("EOF", 9, 9)]);
+        // Unlike `$`, a newline after it is not the end
+        assert_eq!(names(spec, "end\nend\n"), ["END", "END", "EOF"]);
+    }
+
+    #[test]
+    fn skipped_characters_are_reported() {
+        let dfa = crate::compile_spec("[a-z]+ ID true\n").unwrap();
+        let (matches, skipped) = dfa.simulate_with_skipped("ab@c");
+        let lexemes: Vec<String> = matches.into_iter().map(|(lexeme, ..)| lexeme).collect();
+        assert_eq!(lexemes, ["ab", "c", ""]);
+        assert_eq!(skipped, [('@', 1, 3)]);
+        // `simulate` still drops it
+        assert_eq!(dfa.simulate("ab@c").len(), 3);
+    }
+
+    #[test]
+    fn control_character_escapes() {
+        let spec = "\\r\\n NL true\n[a-z]+ ID true\n";
+        assert_eq!(spans(spec, "a\r\nb"), [span("ID", 0, 1), span("NL", 1, 3), span("ID", 3, 4), span("EOF", 4, 4)]);
+        assert_eq!(names("[\\f\\v\\0]+ CTRL true\n", "\x0c\x0b\0"), ["CTRL", "EOF"]);
+    }
+
+    #[test]
+    fn lazy_star_stops_at_the_first_closing_quote() {
+        assert_eq!(spans("\\\".*?\\\" STR true\n", "\"a\"\"b\""), [span("STR", 0, 3), span("STR", 3, 6), span("EOF", 6, 6)]);
+        // Greedy, the longest match takes both strings and what's between them
+        assert_eq!(spans("\\\".*\\\" STR true\n", "\"a\"\"b\""), [span("STR", 0, 6), span("EOF", 6, 6)]);
+    }
+
+    #[test]
+    fn merged_overlay_adds_keywords() {
+        let base = crate::compile_spec("[a-z]+ ID true\n\\_+ (SKIP)\n").unwrap();
+        let overlay = crate::compile_spec("let LET false\nfn FN false\n").unwrap();
+        let merged = super::DFA::merge(&base, &overlay, 2);
+        // The overlay's rules come after the base's in the spec used with the result
+        let spec = parse_spec("[a-z]+ ID true\n\\_+ (SKIP)\nlet LET false\nfn FN false\n").unwrap();
+        let names: Vec<String> = merged.tokenize(&spec, "let x fn lets").into_iter().map(|token| token.name).collect();
+        assert_eq!(names, ["LET", "ID", "FN", "ID", "EOF"]);
+
+        // Overlay rule indices start at the offset
+        let rules: Vec<Option<usize>> = super::DFA::merge(&base, &overlay, 5).simulate("fn x").into_iter().map(|(.., rule)| rule).collect();
+        assert_eq!(rules, [Some(6), Some(1), Some(0), None]);
+
+        // At equal length the overlay wins, though the base rule comes first
+        let overlay = crate::compile_spec("[a-z]+ WORD true\n").unwrap();
+        let rules: Vec<Option<usize>> = super::DFA::merge(&base, &overlay, 2).simulate("ab").into_iter().map(|(.., rule)| rule).collect();
+        assert_eq!(rules, [Some(2), None]);
+    }
+
+    #[test]
+    fn alphabet_limits_construction() {
+        let rules = "[0-9]+ NUM true\n[a-z]+ ID true\n\\_+ (SKIP)\n[^\\n] ANY true\n";
+        let full = constructed(&parse_spec(rules).unwrap());
+        let spec = parse_spec(&format!("%alphabet a-z0-9\\_\n{}", rules)).unwrap();
+        let limited = constructed(&spec);
+        // Without the alphabet, the characters only `ANY` takes need states and transitions of their own
+        assert!(limited.states.len() < full.states.len(), "{} states, {} without the alphabet", limited.states.len(), full.states.len());
+        assert!(limited.transitions.len() < full.transitions.len(), "{} transitions, {} without", limited.transitions.len(), full.transitions.len());
+        // Characters outside it are left unmatched
+        assert_eq!(names(&format!("%alphabet a-z0-9\\_\n{}", rules), "ab 12-"), ["ID", "NUM", "EOF"]);
+        assert_eq!(names(rules, "ab 12-"), ["ID", "NUM", "ANY", "EOF"]);
+    }
+
+    #[test]
+    fn nested_comment_is_skipped_whole() {
+        let spec = "%state COMMENT\n\\/\\* (PUSH COMMENT)\n<COMMENT>\\/\\* (PUSH COMMENT)\n<COMMENT>\\*\\/ (POP)\n<COMMENT>[^*\\/]+|[*\\/] (SKIP)\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+        // Only the inner `*/` pops back to a comment, so `c` isn't an ID
+        let input = "x /* a /* b */ c */ y";
+        assert_eq!(spans(spec, input), [span("ID", 0, 1), span("ID", 20, 21), span("EOF", 21, 21)]);
+        assert_eq!(names(spec, "/* /* */ */"), ["EOF"]);
+    }
+
+    #[test]
+    fn capture_reports_the_group() {
+        let spec = parse_spec("\\#(?<hex>[0-9a-f]{6}) COLOR true\n").unwrap();
+        let tokens = build_dfa(&spec).unwrap().tokenize(&spec, "x #abcdef");
+        let color = tokens.iter().find(|token| token.name == "COLOR").unwrap();
+        assert_eq!((color.start, color.end), (2, 9));
+        assert_eq!(color.capture.map(|(start, end)| &"x #abcdef"[start..end]), Some("abcdef"));
+    }
+
+    #[test]
+    fn bytes_need_not_be_utf8() {
+        let spec = parse_spec("[a-z]+ WORD true\n\\xff+ FF true\n[^a-z\\xff]+ OTHER true\n").unwrap();
+        let input = b"ab\xff\xff\xc3(cd";
+        let tokens = crate::build_byte_dfa(&spec).unwrap().tokenize_bytes(&spec, input);
+        let spans: Vec<(&str, &[u8])> = tokens.iter().map(|token| (token.name.as_str(), &input[token.start..token.end])).collect();
+        assert_eq!(spans, [("WORD", &b"ab"[..]), ("FF", b"\xff\xff"), ("OTHER", b"\xc3("), ("WORD", b"cd"), ("EOF", b"")]);
+        // Columns count bytes
+        assert_eq!(tokens[3].column, 7);
+    }
+    #[test]
+    fn posix_class_matches_a_word() {
+        assert_eq!(spans("[[:alpha:]]+ WORD true\n", "Hello, world"), [span("WORD", 0, 5), span("WORD", 7, 12), span("EOF", 12, 12)]);
+    }
+
+}
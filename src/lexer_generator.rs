@@ -1,221 +1,1353 @@
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use crate::spec_parser::{Spec, Action};
-use crate::regex_parser::parse_regex;
-use crate::nfa::NFA;
-use crate::dfa::DFA;
+use std::time::{Duration, Instant};
+use crate::{build_dfa_with, record_phase};
+use crate::spec_parser::{Spec, Action, Unmatched};
+use crate::dfa::{DFA, DFAStateId};
+use crate::regex_parser::TrailingSplit;
 
-pub fn generate_lexer(spec: &Spec) -> Result<(), String> {
-    // Build NFAs for each rule
-    let mut nfas = Vec::new();
+/// Shape of the generated lexer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputKind {
+    /// A standalone program that tokenizes the file named on its command line
+    #[default]
+    Binary,
+    /// A module exposing `pub fn tokenize(input: &str) -> Vec<Token>` and the lazy
+    /// `pub fn tokens(input: &str) -> Tokens`, an iterator over the same tokens,
+    /// compiled as an rlib. `tokenize_at` and `tokens_at` also take the line and column
+    /// the input starts at. Each `Token` has a `kind: TokenKind`, an enum of the spec's
+    /// token names plus `Error` and `Eof`.
+    Library,
+}
 
-    for (index, rule) in spec.rules.iter().enumerate() {
-        let regex_ast = parse_regex(&rule.regex)
-            .map_err(|e| format!("Error parsing regex '{}': {}", rule.regex, e))?;
+/// How a generated binary prints its tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenFormat {
+    /// One `NAME:lexeme [line,column-end_line,end_column] @start-end` line per token,
+    /// with the position of its last character and the byte offsets of the match
+    #[default]
+    Text,
+    /// JSON Lines: one object per token, with the keys `kind`, `lexeme`, `line`,
+    /// `column`, `end_line`, `end_column`, `start` and `end` (plus `channel` for a token
+    /// on another channel), and a `null` lexeme when the rule does not keep it
+    Json,
+    /// Length-prefixed binary records, for pipelines that would rather not parse text.
+    /// Each record is its length in bytes, then the kind id, line, column, end_line,
+    /// end_column, start and end, then the lexeme and the channel, each either 0 for
+    /// none or its length in bytes plus 1 followed by its UTF-8 bytes. Every number is
+    /// an unsigned LEB128 varint. Kind ids index into `record_kind_names`. The layout
+    /// is stable.
+    Records,
+}
 
-        let nfa = NFA::from_regex(&regex_ast);
-        nfas.push((nfa, index));
+/// How the generated lexer steps from one DFA state to the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// A static table per state, binary searched by character
+    #[default]
+    Table,
+    /// Nested `match` expressions on the state and the character, with character
+    /// runs as range patterns, which the compiler can turn into jump tables
+    Match,
+}
+
+/// Controls what `generate_lexer` produces and where
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    pub output: OutputKind,
+    pub format: TokenFormat, // Only used for `OutputKind::Binary`
+    pub source_path: PathBuf, // Where the generated source is written; the compiled lexer goes next to it, named after it
+    pub compile: bool, // Whether to build the source with `rustc` after writing it
+    pub tab_width: usize, // Columns between tab stops; 1 counts a tab as one column
+    pub backend: Backend,
+    pub max_states: Option<usize>, // Give up on a DFA with more states than this; unlimited if unset
+    pub no_std: bool, // Only used for `OutputKind::Library`: build on `core` and `alloc` instead of `std`
+}
+
+/// What `generate_lexer` left on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeneratedLexer {
+    /// The source and the binary or rlib `rustc` built from it
+    Compiled { source: PathBuf, output: PathBuf },
+    /// Only the source, because compilation was turned off
+    SourceOnly(PathBuf),
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            output: OutputKind::default(),
+            format: TokenFormat::default(),
+            source_path: PathBuf::from("lexer.rs"),
+            compile: true,
+            tab_width: 1,
+            backend: Backend::default(),
+            max_states: None,
+            no_std: false,
+        }
     }
+}
+
+/// Writes the lexer source for `spec` and, unless `options.compile` is off, compiles it
+/// with `rustc`
+pub fn generate_lexer(spec: &Spec, options: &GeneratorOptions) -> Result<GeneratedLexer, String> {
+    let dfa = build_dfa_with(spec, options.max_states)?;
+    write_lexer(spec, &dfa, options)
+}
+
+/// The source `generate_lexer` would write for `spec`, without touching the filesystem,
+/// for build scripts that write it to `OUT_DIR` and compile it themselves. `source_path`
+/// and `compile` in `options` are ignored.
+pub fn generate_source(spec: &Spec, options: &GeneratorOptions) -> Result<String, String> {
+    let dfa = build_dfa_with(spec, options.max_states)?;
+    generate_lexer_code(spec, &dfa, options)
+}
+
+/// Writes the library form of the lexer for `spec` to `out_path` without compiling it,
+/// for a build script. Write it into `OUT_DIR`, e.g. as
+/// `generate_into(&spec, &Path::new(&env::var("OUT_DIR")?).join("lexer.rs"))`, and
+/// bring it into a module with `mod lexer { include!(concat!(env!("OUT_DIR"), "/lexer.rs")); }`.
+pub fn generate_into(spec: &Spec, out_path: &Path) -> Result<(), String> {
+    let options = GeneratorOptions { output: OutputKind::Library, source_path: out_path.to_path_buf(), compile: false, ..GeneratorOptions::default() };
+    generate_lexer(spec, &options).map(|_| ())
+}
 
-    // Convert to DFA
-    let dfa = DFA::from_nfas(nfas);
+/// Like `generate_lexer`, for a DFA already built from `spec` with `build_dfa`
+pub fn write_lexer(spec: &Spec, dfa: &DFA, options: &GeneratorOptions) -> Result<GeneratedLexer, String> {
+    write_lexer_timed(spec, dfa, options, &mut Vec::new())
+}
 
+/// Like `write_lexer`, adding the wall-clock time of each phase to `timings` in the order
+/// they ran
+pub fn write_lexer_timed(
+    spec: &Spec,
+    dfa: &DFA,
+    options: &GeneratorOptions,
+    timings: &mut Vec<(&'static str, Duration)>,
+) -> Result<GeneratedLexer, String> {
     // Generate lexer source code
-    let lexer_code = generate_lexer_code(spec, &dfa)?;
+    let start = Instant::now();
+    let lexer_code = generate_lexer_code(spec, dfa, options)?;
+    let start = record_phase(timings, "generate code", start);
 
-    // Write lexer source code
-    fs::write("lexer.rs", lexer_code)
-        .map_err(|e| format!("Error writing lexer.rs: {}", e))?;
+    // Write lexer source code, making its directory if needed
+    let source_path = &options.source_path;
+    if let Some(dir) = source_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format!("Error creating {}: {}", dir.display(), e))?;
+    }
+    fs::write(source_path, lexer_code)
+        .map_err(|e| format!("Error writing {}: {}", source_path.display(), e))?;
+    let start = record_phase(timings, "write source", start);
+
+    if !options.compile {
+        return Ok(GeneratedLexer::SourceOnly(source_path.clone()));
+    }
+
+    // Compile the lexer next to its source
+    let stem = source_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("lexer");
+    let output_path = match options.output {
+        OutputKind::Binary => source_path.with_file_name(stem),
+        OutputKind::Library => source_path.with_file_name(format!("lib{}.rlib", stem)),
+    };
+    let mut command = Command::new("rustc");
+    command.arg(source_path);
+    if options.output == OutputKind::Library {
+        command.args(["--crate-type", "lib"]);
+    }
+    command.arg("-o").arg(&output_path);
 
-    // Compile the lexer
-    let output = Command::new("rustc")
-        .args(&["lexer.rs", "-o", "lexer"])
+    let output = command
         .output()
-        .map_err(|e| format!("Error compiling lexer: {}", e))?;
+        .map_err(|e| format!("Error running rustc on {}: {}", source_path.display(), e))?;
+    record_phase(timings, "rustc", start);
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(format!("Compilation failed: {}", stderr));
     }
 
-    Ok(())
+    Ok(GeneratedLexer::Compiled { source: source_path.clone(), output: output_path })
+}
+
+/// Runs a lexer binary built by `generate_lexer` on the file at `input_path`, returning
+/// what it wrote to stdout and stderr. A lexer that exits with an error still gives its
+/// output, since error tokens and messages are worth checking too; only failing to start
+/// it at all, as when the binary is missing, is an `Err`.
+pub fn run_generated_lexer(binary_path: impl AsRef<Path>, input_path: impl AsRef<Path>) -> Result<(String, String), String> {
+    let binary_path = binary_path.as_ref();
+    let output = Command::new(binary_path)
+        .arg(input_path.as_ref())
+        .output()
+        .map_err(|e| format!("Error running {}: {}", binary_path.display(), e))?;
+    Ok((String::from_utf8_lossy(&output.stdout).into_owned(), String::from_utf8_lossy(&output.stderr).into_owned()))
 }
 
-fn generate_lexer_code(spec: &Spec, dfa: &DFA) -> Result<String, String> {
+fn generate_lexer_code(spec: &Spec, dfa: &DFA, options: &GeneratorOptions) -> Result<String, String> {
     let mut code = String::new();
+    let (output, format, no_std) = (options.output, options.format, options.no_std);
+    if no_std && output != OutputKind::Library {
+        return Err("Only a library lexer can be no_std".to_string());
+    }
+
+    match output {
+        OutputKind::Binary => generate_main(&mut code, format),
+        OutputKind::Library => {
+            if no_std {
+                generate_no_std_prelude(&mut code, spec.unmatched);
+            }
+            generate_token_enum(&mut code, spec, no_std);
+        }
+    }
+
+    generate_tables(&mut code, dfa, options.backend);
+    if spec.unmatched == Unmatched::Recover {
+        generate_start_chars(&mut code, dfa);
+    }
+    generate_tokenize(&mut code, spec, output, format, options.tab_width.max(1), no_std);
+    generate_helpers(&mut code, spec, output, format, options.backend, dfa);
+
+    Ok(code)
+}
 
+fn generate_main(code: &mut String, format: TokenFormat) {
     // Add imports and basic structure
     code.push_str("use std::env;\n");
     code.push_str("use std::fs;\n");
-    code.push_str("use std::collections::HashMap;\n");
+    code.push_str("use std::io;\n");
+    if format == TokenFormat::Records {
+        code.push_str("use std::io::Write;\n");
+    }
     code.push_str("use std::process;\n\n");
 
     // Generate DFA transition table
     code.push_str("fn main() {\n");
     code.push_str("    let args: Vec<String> = env::args().collect();\n");
-    code.push_str("    if args.len() != 2 {\n");
-    code.push_str("        eprintln!(\"Usage: {} <input_file>\", args[0]);\n");
-    code.push_str("        process::exit(1);\n");
+    code.push_str("    let mut input_file = None;\n");
+    code.push_str("    // Where the input starts, for a fragment of a larger file\n");
+    code.push_str("    let (mut line, mut column) = (1, 1);\n");
+    code.push_str("    let mut raw = false;\n");
+    code.push_str("    let mut rest = args.iter().skip(1);\n");
+    code.push_str("    while let Some(arg) = rest.next() {\n");
+    code.push_str("        match arg.as_str() {\n");
+    code.push_str("            \"--start-line\" | \"--start-column\" => {\n");
+    code.push_str("                let Some(value) = rest.next().and_then(|value| value.parse().ok()).filter(|&value| value > 0) else {\n");
+    code.push_str("                    usage(&args[0]);\n");
+    code.push_str("                };\n");
+    code.push_str("                if arg == \"--start-line\" { line = value } else { column = value }\n");
+    code.push_str("            }\n");
+    code.push_str("            \"--raw\" => raw = true,\n");
+    code.push_str("            _ if input_file.is_none() => input_file = Some(arg.as_str()),\n");
+    code.push_str("            _ => usage(&args[0]),\n");
+    code.push_str("        }\n");
     code.push_str("    }\n\n");
 
-    code.push_str("    let input_file = &args[1];\n");
-    code.push_str("    let input = match fs::read_to_string(input_file) {\n");
-    code.push_str("        Ok(content) => content,\n");
+    code.push_str("    // Read stdin when there is no file argument or it is '-'\n");
+    code.push_str("    let input = match input_file {\n");
+    code.push_str("        None | Some(\"-\") => io::read_to_string(io::stdin()),\n");
+    code.push_str("        Some(input_file) => fs::read_to_string(input_file),\n");
+    code.push_str("    };\n");
+    code.push_str("    let input = match input {\n");
+    code.push_str("        Ok(content) if raw => content,\n");
+    code.push_str("        Ok(content) => normalize_input(&content),\n");
     code.push_str("        Err(err) => {\n");
-    code.push_str("            eprintln!(\"Error reading input file: {}\", err);\n");
+    code.push_str("            eprintln!(\"Error reading input: {}\", err);\n");
     code.push_str("            process::exit(1);\n");
     code.push_str("        }\n");
     code.push_str("    };\n\n");
 
-    code.push_str("    let tokens = tokenize(&input);\n");
-    code.push_str("    for token in tokens {\n");
-    code.push_str("        println!(\"{}\", token);\n");
+    if format == TokenFormat::Records {
+        code.push_str("    let mut stdout = io::BufWriter::new(io::stdout().lock());\n");
+        code.push_str("    for token in tokens_at(&input, line, column) {\n");
+        code.push_str("        if stdout.write_all(&token).is_err() {\n");
+        code.push_str("            process::exit(1);\n");
+        code.push_str("        }\n");
+        code.push_str("    }\n");
+        code.push_str("    if stdout.flush().is_err() {\n");
+        code.push_str("        process::exit(1);\n");
+        code.push_str("    }\n");
+    } else {
+        code.push_str("    for token in tokens_at(&input, line, column) {\n");
+        code.push_str("        println!(\"{}\", token);\n");
+        code.push_str("    }\n");
+    }
+    code.push_str("}\n\n");
+
+    code.push_str("fn usage(program: &str) -> ! {\n");
+    code.push_str("    eprintln!(\"Usage: {} [--start-line <n>] [--start-column <n>] [--raw] [<input_file> | -]\", program);\n");
+    code.push_str("    process::exit(1);\n");
+    code.push_str("}\n\n");
+
+    code.push_str(NORMALIZE_INPUT);
+    code.push('\n');
+}
+
+// `normalize_input` is written once, for both dragonlex and the lexers it generates
+include!("normalize_input.rs");
+const NORMALIZE_INPUT: &str = include_str!("normalize_input.rs");
+
+// The crate attributes and imports of a library without `std`. Tokens own their lexemes,
+// so it still needs `alloc`; `format!` is only used for unmatched input errors.
+fn generate_no_std_prelude(code: &mut String, unmatched: Unmatched) {
+    code.push_str("#![no_std]\n\n");
+    code.push_str("extern crate alloc;\n\n");
+    if unmatched != Unmatched::Skip {
+        code.push_str("use alloc::format;\n");
+    }
+    code.push_str("use alloc::string::{String, ToString};\n");
+    code.push_str("use alloc::vec::Vec;\n\n");
+}
+
+fn generate_token_enum(code: &mut String, spec: &Spec, no_std: bool) {
+    let kinds = token_kinds(spec);
+    let fmt = if no_std { "core::fmt" } else { "std::fmt" };
+
+    code.push_str("#[allow(non_camel_case_types)]\n");
+    code.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]\n");
+    code.push_str("pub enum TokenKind {\n");
+    for (_, variant) in &kinds {
+        code.push_str(&format!("    {},\n", variant));
+    }
+    code.push_str("    Error,\n");
+    code.push_str("    Eof,\n");
+    code.push_str("}\n\n");
+
+    // Display gives back the names used in the spec
+    code.push_str(&format!("impl {}::Display for TokenKind {{\n", fmt));
+    code.push_str(&format!("    fn fmt(&self, f: &mut {}::Formatter) -> {}::Result {{\n", fmt, fmt));
+    code.push_str("        let name = match self {\n");
+    for (name, variant) in &kinds {
+        code.push_str(&format!("            TokenKind::{} => \"{}\",\n", variant, escape_string(name)));
+    }
+    code.push_str("            TokenKind::Error => \"ERROR\",\n");
+    code.push_str("            TokenKind::Eof => \"EOF\",\n");
+    code.push_str("        };\n");
+    code.push_str("        f.write_str(name)\n");
     code.push_str("    }\n");
     code.push_str("}\n\n");
 
-    // Generate tokenize function
-    code.push_str("fn tokenize(input: &str) -> Vec<String> {\n");
-    code.push_str("    let mut tokens = Vec::new();\n");
-    code.push_str("    let mut line = 1;\n");
-    code.push_str("    let mut column = 1;\n");
-    code.push_str("    let mut pos = 0;\n");
-    code.push_str("    let chars: Vec<char> = input.chars().collect();\n\n");
-
-    // Generate transition table
-    code.push_str("    let mut transitions = HashMap::new();\n");
-    for ((from_state, ch), to_state) in &dfa.transitions {
-        code.push_str(&format!(
-            "    transitions.insert(({}, '{}'), {});\n",
-            from_state.0, escape_char(*ch), to_state.0
-        ));
+    code.push_str("#[derive(Debug, Clone, PartialEq)]\n");
+    code.push_str("pub struct Token {\n");
+    code.push_str("    pub kind: TokenKind,\n");
+    code.push_str("    pub lexeme: Option<String>, // Only kept when the rule asks for it; the message for errors\n");
+    code.push_str("    pub line: usize,\n");
+    code.push_str("    pub column: usize,\n");
+    code.push_str("    pub end_line: usize, // Position of the last character, the start for EOF\n");
+    code.push_str("    pub end_column: usize,\n");
+    code.push_str("    pub start: usize, // Byte offsets of the matched text in the input, end exclusive\n");
+    code.push_str("    pub end: usize,\n");
+    if has_channels(spec) {
+        code.push_str("    pub channel: Option<&'static str>, // From the rule's (CHANNEL name); None is the default channel\n");
+    }
+    code.push_str("}\n\n");
+}
+
+// The tables are statics indexed by state, so they are built once at compile time
+fn generate_tables(code: &mut String, dfa: &DFA, backend: Backend) {
+    let state_count = dfa.states.len();
+
+    // Each state's transitions as runs of characters, sorted
+    let rows: Vec<Vec<(char, char, usize)>> = (0..state_count)
+        .map(|state| {
+            let runs = dfa.transition_runs(&DFAStateId(state));
+            runs.into_iter().map(|(low, high, to_state)| (low, high, to_state.0)).collect()
+        })
+        .collect();
+    match backend {
+        Backend::Table => generate_transition_table(code, &rows),
+        Backend::Match => generate_transition_match(code, &rows),
     }
-    code.push_str("\n");
 
     // Generate accepting states
-    code.push_str("    let mut accepting_states = HashMap::new();\n");
-    for (state_id, state) in &dfa.states {
-        if state.is_accepting {
-            if let Some(rule_index) = state.rule_index {
-                code.push_str(&format!(
-                    "    accepting_states.insert({}, {});\n",
-                    state_id.0, rule_index
-                ));
+    code.push_str(&format!("static ACCEPTING_STATES: [Option<usize>; {}] = [\n", state_count));
+    for state_id in 0..state_count {
+        code.push_str(&format!("    {:?},\n", dfa.states[&DFAStateId(state_id)].rule_index));
+    }
+    code.push_str("];\n\n");
+
+    // Generate states that only accept before a newline or end of input ('$' rules)
+    code.push_str(&format!("static LINE_END_STATES: [Option<usize>; {}] = [\n", state_count));
+    for state_id in 0..state_count {
+        code.push_str(&format!("    {:?},\n", dfa.states[&DFAStateId(state_id)].line_end_rule_index));
+    }
+    code.push_str("];\n\n");
+
+    // Generate states that only accept at the end of input ('\z' rules)
+    if has_end_of_input(dfa) {
+        code.push_str(&format!("static END_OF_INPUT_STATES: [Option<usize>; {}] = [\n", state_count));
+        for state_id in 0..state_count {
+            code.push_str(&format!("    {:?},\n", dfa.states[&DFAStateId(state_id)].end_of_input_rule_index));
+        }
+        code.push_str("];\n\n");
+    }
+
+    // Generate start states, one per start condition
+    let ids = |states: &[DFAStateId]| states.iter().map(|state| state.0.to_string()).collect::<Vec<String>>().join(", ");
+    let condition_count = dfa.start_states.len();
+    code.push_str(&format!("static START_STATES: [usize; {}] = [{}];\n", condition_count, ids(&dfa.start_states)));
+    code.push_str(&format!("static LINE_START_STATES: [usize; {}] = [{}];\n\n", condition_count, ids(&dfa.line_start_states)));
+
+    // Generate rule priorities, which decide ties between ACCEPTING and LINE_END rules
+    let priorities: Vec<String> = dfa.priorities.iter().map(|priority| priority.to_string()).collect();
+    code.push_str(&format!("static PRIORITIES: [i32; {}] = [{}];\n\n", priorities.len(), priorities.join(", ")));
+
+    // Generate the rules that stop at their shortest match, when there are any
+    if has_shortest(dfa) {
+        let shortest: Vec<String> = dfa.shortest.iter().map(|shortest| shortest.to_string()).collect();
+        code.push_str(&format!("static SHORTEST: [bool; {}] = [{}];\n\n", shortest.len(), shortest.join(", ")));
+    }
+}
+
+// Generate transition table, each state's row of (low, high, next) runs sorted for binary search
+fn generate_transition_table(code: &mut String, rows: &[Vec<(char, char, usize)>]) {
+    code.push_str(&format!("static TRANSITIONS: [&[(char, char, usize)]; {}] = [\n", rows.len()));
+    for row in rows {
+        let entries: Vec<String> = row
+            .iter()
+            .map(|(low, high, to_state)| format!("('{}', '{}', {})", escape_char(*low), escape_char(*high), to_state))
+            .collect();
+        code.push_str(&format!("    &[{}],\n", entries.join(", ")));
+    }
+    code.push_str("];\n\n");
+}
+
+// The same transitions as one `match` per state, each run a range pattern
+fn generate_transition_match(code: &mut String, rows: &[Vec<(char, char, usize)>]) {
+    code.push_str("fn next_state(state: usize, ch: char) -> Option<usize> {\n");
+    code.push_str("    match state {\n");
+    for (state, runs) in rows.iter().enumerate() {
+        if runs.is_empty() {
+            continue;
+        }
+
+        // One arm per target state, listing its runs in character order
+        let mut targets: Vec<usize> = Vec::new();
+        for &(_, _, target) in runs {
+            if !targets.contains(&target) {
+                targets.push(target);
             }
         }
+        code.push_str(&format!("        {} => match ch {{\n", state));
+        for target in targets {
+            let patterns: Vec<String> = runs
+                .iter()
+                .filter(|&&(_, _, run_target)| run_target == target)
+                .map(|&(low, high, _)| match low == high {
+                    true => format!("'{}'", escape_char(low)),
+                    false => format!("'{}'..='{}'", escape_char(low), escape_char(high)),
+                })
+                .collect();
+            code.push_str(&format!("            {} => Some({}),\n", patterns.join(" | "), target));
+        }
+        code.push_str("            _ => None,\n");
+        code.push_str("        },\n");
     }
-    code.push_str("\n");
+    code.push_str("        _ => None,\n");
+    code.push_str("    }\n");
+    code.push_str("}\n\n");
+}
+
+// Characters a token can begin with in each start condition, where recovery resumes
+fn generate_start_chars(code: &mut String, dfa: &DFA) {
+    let condition_count = dfa.start_states.len();
+    code.push_str(&format!("static START_CHARS: [&[(char, char)]; {}] = [\n", condition_count));
+    for condition in 0..condition_count {
+        let chars: Vec<String> = dfa
+            .start_chars(condition)
+            .iter()
+            .map(|(low, high)| format!("('{}', '{}')", escape_char(*low), escape_char(*high)))
+            .collect();
+        code.push_str(&format!("    &[{}],\n", chars.join(", ")));
+    }
+    code.push_str("];\n\n");
+}
+
+fn generate_tokenize(code: &mut String, spec: &Spec, output: OutputKind, format: TokenFormat, tab_width: usize, no_std: bool) {
+    let records = output == OutputKind::Binary && format == TokenFormat::Records;
+    let (visibility, item) = match output {
+        OutputKind::Binary if records => ("", "Vec<u8>"),
+        OutputKind::Binary => ("", "String"),
+        OutputKind::Library => ("pub ", "Token"),
+    };
 
     // Generate rule actions
-    code.push_str("    let rules = vec![\n");
+    let kinds = token_kinds(spec);
+    if records {
+        let ids: Vec<String> = record_kind_names(spec).iter().enumerate().map(|(id, name)| format!("{} {}", id, name)).collect();
+        code.push_str(&format!("// Record kind ids: {}\n", ids.join(", ")));
+    }
+    code.push_str("static RULES: &[RuleAction] = &[\n");
     for rule in &spec.rules {
-        match &rule.action {
-            Action::Skip => {
-                code.push_str("        RuleAction::Skip,\n");
+        // A kept skip comes out like a token that keeps its lexeme
+        let kept_skip;
+        let action = match &rule.action {
+            Action::Skip(Some(name)) => {
+                kept_skip = Action::Token { name: name.clone(), keep_lexeme: true, value: None, channel: None };
+                &kept_skip
+            }
+            action => action,
+        };
+        match action {
+            Action::Skip(_) => {
+                code.push_str("    RuleAction::Skip,\n");
             }
             Action::Error(msg) => {
-                code.push_str(&format!("        RuleAction::Error(\"{}\".to_string()),\n", escape_string(msg)));
-            }
-            Action::Token { name, keep_lexeme } => {
-                code.push_str(&format!(
-                    "        RuleAction::Token {{ name: \"{}\".to_string(), keep_lexeme: {} }},\n",
-                    name, keep_lexeme
-                ));
-            }
-        }
-    }
-    code.push_str("    ];\n\n");
-
-    // Main tokenization loop
-    code.push_str("    while pos < chars.len() {\n");
-    code.push_str("        let (token_length, rule_index) = longest_match(&chars[pos..], &transitions, &accepting_states);\n\n");
-
-    code.push_str("        if token_length > 0 {\n");
-    code.push_str("            let lexeme: String = chars[pos..pos + token_length].iter().collect();\n");
-    code.push_str("            \n");
-    code.push_str("            if let Some(rule_idx) = rule_index {\n");
-    code.push_str("                match &rules[rule_idx] {\n");
-    code.push_str("                    RuleAction::Skip => {},\n");
-    code.push_str("                    RuleAction::Error(msg) => {\n");
-    code.push_str("                        eprintln!(\"{}\", msg);\n");
-    code.push_str("                    },\n");
-    code.push_str("                    RuleAction::Token { name, keep_lexeme } => {\n");
-    code.push_str("                        let token_str = if *keep_lexeme {\n");
-    code.push_str("                            format!(\"{}:{} [{},{}]\", name, lexeme, line, column)\n");
-    code.push_str("                        } else {\n");
-    code.push_str("                            format!(\"{} [{},{}]\", name, line, column)\n");
-    code.push_str("                        };\n");
-    code.push_str("                        tokens.push(token_str);\n");
-    code.push_str("                    },\n");
-    code.push_str("                }\n");
-    code.push_str("            }\n\n");
-
-    code.push_str("            // Update position\n");
-    code.push_str("            for i in pos..pos + token_length {\n");
-    code.push_str("                if chars[i] == '\\n' {\n");
-    code.push_str("                    line += 1;\n");
-    code.push_str("                    column = 1;\n");
-    code.push_str("                } else {\n");
-    code.push_str("                    column += 1;\n");
-    code.push_str("                }\n");
-    code.push_str("            }\n");
-    code.push_str("            pos += token_length;\n");
-    code.push_str("        } else {\n");
-    code.push_str("            // No match found, skip character\n");
-    code.push_str("            if chars[pos] == '\\n' {\n");
-    code.push_str("                line += 1;\n");
-    code.push_str("                column = 1;\n");
+                code.push_str(&format!("    RuleAction::Error(\"{}\"),\n", escape_string(msg)));
+            }
+            Action::Begin(condition) => {
+                code.push_str(&format!("    RuleAction::Begin({}), // {}\n", condition, spec.conditions[*condition]));
+            }
+            Action::Push(condition) => {
+                code.push_str(&format!("    RuleAction::Push({}), // {}\n", condition, spec.conditions[*condition]));
+            }
+            Action::Pop => {
+                code.push_str("    RuleAction::Pop,\n");
+            }
+            Action::Token { name, value: Some(value), .. } => match output {
+                OutputKind::Binary if records => code.push_str(&format!(
+                    "    RuleAction::Value {{ kind: {}, value: \"{}\" }}, // {}\n",
+                    record_kind(&kinds, name),
+                    escape_string(value),
+                    name
+                )),
+                OutputKind::Binary => code.push_str(&format!(
+                    "    RuleAction::Value {{ name: \"{}\", value: \"{}\" }},\n",
+                    escape_string(name),
+                    escape_string(value)
+                )),
+                OutputKind::Library => code.push_str(&format!(
+                    "    RuleAction::Value {{ kind: TokenKind::{}, value: \"{}\" }},\n",
+                    kinds.iter().find(|(kind_name, _)| kind_name == name).map_or("", |(_, variant)| variant),
+                    escape_string(value)
+                )),
+            },
+            Action::Token { name, keep_lexeme, value: None, .. } => match output {
+                OutputKind::Binary if records => code.push_str(&format!(
+                    "    RuleAction::Token {{ kind: {}, keep_lexeme: {} }}, // {}\n",
+                    record_kind(&kinds, name),
+                    keep_lexeme,
+                    name
+                )),
+                OutputKind::Binary => code.push_str(&format!(
+                    "    RuleAction::Token {{ name: \"{}\", keep_lexeme: {} }},\n",
+                    escape_string(name),
+                    keep_lexeme
+                )),
+                OutputKind::Library => code.push_str(&format!(
+                    "    RuleAction::Token {{ kind: TokenKind::{}, keep_lexeme: {} }},\n",
+                    kinds.iter().find(|(kind_name, _)| kind_name == name).map_or("", |(_, variant)| variant),
+                    keep_lexeme
+                )),
+            },
+            Action::Tokens { tokens, .. } => {
+                let entries: Vec<String> = tokens
+                    .iter()
+                    .map(|(name, keep_lexeme)| match output {
+                        OutputKind::Binary if records => format!("({}, {})", record_kind(&kinds, name), keep_lexeme),
+                        OutputKind::Binary => format!("(\"{}\", {})", escape_string(name), keep_lexeme),
+                        OutputKind::Library => format!(
+                            "(TokenKind::{}, {})",
+                            kinds.iter().find(|(kind_name, _)| kind_name == name).map_or("", |(_, variant)| variant),
+                            keep_lexeme
+                        ),
+                    })
+                    .collect();
+                code.push_str(&format!("    RuleAction::Tokens(&[{}]),", entries.join(", ")));
+                if records {
+                    let names: Vec<&str> = tokens.iter().map(|(name, _)| name.as_str()).collect();
+                    code.push_str(&format!(" // {}", names.join(", ")));
+                }
+                code.push('\n');
+            }
+        }
+    }
+    code.push_str("];\n\n");
+
+    let channels = has_channels(spec);
+    let multiple = has_multiple_tokens(spec);
+    let stack = has_state_stack(spec);
+    let collections = if no_std { "alloc::collections" } else { "std::collections" };
+    if channels {
+        // The channel of each rule's tokens, `None` for the default one
+        let entries: Vec<String> = spec
+            .rules
+            .iter()
+            .map(|rule| match &rule.action {
+                Action::Token { channel: Some(channel), .. } | Action::Tokens { channel: Some(channel), .. } => {
+                    format!("Some(\"{}\")", channel)
+                }
+                _ => "None".to_string(),
+            })
+            .collect();
+        code.push_str(&format!("static CHANNELS: [Option<&str>; {}] = [{}];\n\n", entries.len(), entries.join(", ")));
+    }
+
+    // `<<EOF>>` rule of each start condition, only when the spec has any
+    let eof_rules = crate::token::eof_rules(spec);
+    let has_eof_rules = eof_rules.iter().any(Option::is_some);
+    if has_eof_rules {
+        let entries: Vec<String> = eof_rules
+            .iter()
+            .map(|rule| rule.map_or("None".to_string(), |rule| format!("Some({})", rule)))
+            .collect();
+        code.push_str(&format!("static EOF_RULES: [Option<usize>; {}] = [{}];\n\n", eof_rules.len(), entries.join(", ")));
+    }
+
+    // The scanner state lives in an iterator, so each token is matched when it is asked for
+    code.push_str("/// Lazily yields the tokens of an input, ending with EOF\n");
+    code.push_str(&format!("{}struct Tokens<'a> {{\n", visibility));
+    code.push_str("    input: &'a str,\n");
+    code.push_str("    pos: usize, // Byte offset of the next character\n");
+    code.push_str("    line: usize,\n");
+    code.push_str("    column: usize,\n");
+    code.push_str("    condition: usize, // Current start condition, INITIAL first\n");
+    code.push_str("    done: bool,\n");
+    if multiple {
+        code.push_str(&format!(
+            "    pending: {}::VecDeque<{}>, // The rest of the tokens of a rule that makes several\n",
+            collections, item
+        ));
+    }
+    if stack {
+        code.push_str("    stack: Vec<usize>, // Conditions to go back to on (POP), from (PUSH ...)\n");
+    }
+    code.push_str("}\n\n");
+
+    // The binary always goes through `tokens_at`, with the position from its arguments
+    if output == OutputKind::Library {
+        code.push_str("pub fn tokens(input: &str) -> Tokens<'_> {\n");
+        code.push_str("    tokens_at(input, 1, 1)\n");
+        code.push_str("}\n\n");
+    }
+
+    code.push_str("/// The tokens of input that starts at `line` and `column` of a larger file, as for a fragment\n");
+    code.push_str(&format!("{}fn tokens_at(input: &str, line: usize, column: usize) -> Tokens<'_> {{\n", visibility));
+    let mut fields = String::from("input, pos: 0, line, column, condition: 0, done: false");
+    if multiple {
+        fields.push_str(&format!(", pending: {}::VecDeque::new()", collections));
+    }
+    if stack {
+        fields.push_str(", stack: Vec::new()");
+    }
+    code.push_str(&format!("    Tokens {{ {} }}\n", fields));
+    code.push_str("}\n\n");
+
+    if output == OutputKind::Library {
+        code.push_str("pub fn tokenize(input: &str) -> Vec<Token> {\n");
+        code.push_str("    tokens(input).collect()\n");
+        code.push_str("}\n\n");
+
+        code.push_str("pub fn tokenize_at(input: &str, line: usize, column: usize) -> Vec<Token> {\n");
+        code.push_str("    tokens_at(input, line, column).collect()\n");
+        code.push_str("}\n\n");
+    }
+
+    code.push_str("impl Tokens<'_> {\n");
+    code.push_str("    // Moves past `consumed`, returning the line and column of its last character\n");
+    code.push_str("    fn advance(&mut self, consumed: &str) -> (usize, usize) {\n");
+    code.push_str("        let mut last = (self.line, self.column);\n");
+    code.push_str("        for ch in consumed.chars() {\n");
+    code.push_str("            last = (self.line, self.column);\n");
+    code.push_str("            if ch == '\\n' {\n");
+    code.push_str("                self.line += 1;\n");
+    code.push_str("                self.column = 1;\n");
+    if tab_width > 1 {
+        code.push_str("            } else if ch == '\\t' {\n");
+        code.push_str(&format!("                self.column += {} - (self.column - 1) % {};\n", tab_width, tab_width));
+    }
     code.push_str("            } else {\n");
-    code.push_str("                column += 1;\n");
+    code.push_str("                self.column += 1;\n");
     code.push_str("            }\n");
-    code.push_str("            pos += 1;\n");
     code.push_str("        }\n");
-    code.push_str("    }\n\n");
+    code.push_str("        self.pos += consumed.len();\n");
+    code.push_str("        last\n");
+    code.push_str("    }\n");
+    code.push_str("}\n\n");
+
+    code.push_str("impl Iterator for Tokens<'_> {\n");
+    code.push_str(&format!("    type Item = {};\n\n", item));
+    code.push_str(&format!("    fn next(&mut self) -> Option<{}> {{\n", item));
 
-    code.push_str("    // Add EOF token\n");
-    code.push_str("    tokens.push(format!(\"EOF [{},{}]\", line, column));\n");
-    code.push_str("    tokens\n");
+    if multiple {
+        code.push_str("        // Tokens left over from a rule that makes several come first\n");
+        code.push_str("        if let Some(token) = self.pending.pop_front() {\n");
+        code.push_str("            return Some(token);\n");
+        code.push_str("        }\n\n");
+    }
+
+    // Main tokenization loop, running until a match produces a token
+    code.push_str("        while self.pos < self.input.len() {\n");
+    code.push_str("            let rest = &self.input[self.pos..];\n");
+    code.push_str("            let start_line = self.line;\n");
+    code.push_str("            let start_column = self.column;\n");
+    code.push_str("            let start = self.pos;\n");
+    code.push_str("            let (token_length, rule_index) = longest_match(rest, self.condition, start_column == 1);\n\n");
+
+    code.push_str("            if token_length > 0 {\n");
+    code.push_str("                let lexeme = &rest[..token_length];\n\n");
+
+    code.push_str("                // Advance the cursor past the lexeme; tokens report where they started and ended\n");
+    code.push_str("                let (end_line, end_column) = self.advance(lexeme);\n\n");
+
+    code.push_str("                if let Some(rule_idx) = rule_index {\n");
+    code.push_str("                    match &RULES[rule_idx] {\n");
+    code.push_str("                        RuleAction::Skip => {},\n");
+    code.push_str("                        RuleAction::Begin(next) => self.condition = *next,\n");
+    if stack {
+        generate_stack_arms(code, output, channels);
+    }
+    generate_action_arms(code, output, format, has_fixed_values(spec), channels, multiple, "                        ");
+    code.push_str("                    }\n");
+    code.push_str("                }\n");
+    code.push_str("            } else {\n");
+    match spec.unmatched {
+        Unmatched::Recover => generate_recovery(code, output, format, channels),
+        unmatched => generate_skip_char(code, unmatched, output, format, channels),
+    }
+    code.push_str("            }\n");
+    code.push_str("        }\n\n");
+
+    if spec.eof_token {
+        code.push_str("        // Add EOF token, once\n");
+    } else {
+        code.push_str("        // End of input, with no EOF token under %no-eof\n");
+    }
+    code.push_str("        if self.done {\n");
+    code.push_str("            return None;\n");
+    code.push_str("        }\n");
+    code.push_str("        self.done = true;\n");
+    if has_eof_rules {
+        // An `<<EOF>>` rule replaces the EOF token with its own action
+        code.push_str("        if let Some(rule_idx) = EOF_RULES[self.condition] {\n");
+        code.push_str("            let (lexeme, start_line, start_column, start) = (\"\", self.line, self.column, self.pos);\n");
+        code.push_str("            let (end_line, end_column) = (start_line, start_column);\n");
+        code.push_str("            match &RULES[rule_idx] {\n");
+        if stack {
+            code.push_str("                RuleAction::Skip | RuleAction::Begin(_) | RuleAction::Push(_) | RuleAction::Pop => {},\n");
+        } else {
+            code.push_str("                RuleAction::Skip | RuleAction::Begin(_) => {},\n");
+        }
+        generate_action_arms(code, output, format, has_fixed_values(spec), channels, multiple, "                ");
+        code.push_str("            }\n");
+        code.push_str("            return None;\n");
+        code.push_str("        }\n");
+    }
+    match output {
+        _ if !spec.eof_token => code.push_str("        None\n"),
+        OutputKind::Binary => match format {
+            TokenFormat::Text => code.push_str("        Some(format!(\"EOF [{},{}-{},{}] @{}-{}\", self.line, self.column, self.line, self.column, self.pos, self.pos))\n"),
+            TokenFormat::Json => {
+                code.push_str(r#"        Some(format!("{{\"kind\":\"EOF\",\"lexeme\":null,\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", self.line, self.column, self.line, self.column, self.pos, self.pos))"#);
+                code.push('\n');
+            }
+            TokenFormat::Records => code.push_str("        Some(record(0, None, None, [self.line, self.column, self.line, self.column, self.pos, self.pos]))\n"),
+        },
+        OutputKind::Library => code.push_str(&format!(
+            "        Some(Token {{ kind: TokenKind::Eof, lexeme: None, line: self.line, column: self.column, end_line: self.line, end_column: self.column, start: self.pos, end: self.pos{} }})\n",
+            default_channel(channels)
+        )),
+    }
+    code.push_str("    }\n");
     code.push_str("}\n\n");
+}
+
+// Whether any rule puts its tokens on a channel, which needs `CHANNELS`
+fn has_channels(spec: &Spec) -> bool {
+    spec.rules
+        .iter()
+        .any(|rule| matches!(rule.action, Action::Token { channel: Some(_), .. } | Action::Tokens { channel: Some(_), .. }))
+}
+
+// The end of a library `Token` literal for a token on the default channel, when tokens have one
+fn default_channel(channels: bool) -> &'static str {
+    if channels { ", channel: None" } else { "" }
+}
+
+// Whether any rule is anchored with `\z`, which needs `END_OF_INPUT_STATES`
+fn has_end_of_input(dfa: &DFA) -> bool {
+    dfa.states.values().any(|state| state.end_of_input_rule_index.is_some())
+}
+
+// Whether any rule is marked `(SHORTEST)`, which needs `SHORTEST`
+fn has_shortest(dfa: &DFA) -> bool {
+    dfa.shortest.contains(&true)
+}
+
+// Whether any rule replaces its lexeme with a fixed value, which needs `RuleAction::Value`
+fn has_fixed_values(spec: &Spec) -> bool {
+    spec.rules.iter().any(|rule| matches!(rule.action, Action::Token { value: Some(_), .. }))
+}
+
+// Whether any rule uses `(PUSH ...)` or `(POP)`, which needs a stack of start conditions
+fn has_state_stack(spec: &Spec) -> bool {
+    spec.rules.iter().any(|rule| matches!(rule.action, Action::Push(_) | Action::Pop))
+}
+
+// The main loop's arms for `(PUSH ...)` and `(POP)`. A pop with nothing pushed leaves the
+// condition as it is and is reported like an unmatched character.
+fn generate_stack_arms(code: &mut String, output: OutputKind, channels: bool) {
+    code.push_str("                        RuleAction::Push(next) => {\n");
+    code.push_str("                            self.stack.push(self.condition);\n");
+    code.push_str("                            self.condition = *next;\n");
+    code.push_str("                        }\n");
+    code.push_str("                        RuleAction::Pop => match self.stack.pop() {\n");
+    code.push_str("                            Some(previous) => self.condition = previous,\n");
+    match output {
+        OutputKind::Binary => code.push_str(
+            "                            None => eprintln!(\"(POP) with no state to return to [{},{}]\", start_line, start_column),\n",
+        ),
+        OutputKind::Library => code.push_str(&format!(
+            "                            None => return Some(Token {{ kind: TokenKind::Error, lexeme: Some(\"(POP) with no state to return to\".to_string()), line: start_line, column: start_column, end_line, end_column, start, end: self.pos{} }}),\n",
+            default_channel(channels)
+        )),
+    }
+    code.push_str("                        },\n");
+}
+
+// Whether any rule makes several tokens, which needs `RuleAction::Tokens` and a queue for
+// the ones after the first
+fn has_multiple_tokens(spec: &Spec) -> bool {
+    spec.rules.iter().any(|rule| matches!(rule.action, Action::Tokens { .. }))
+}
+
+// The match arms for the rule actions that produce something, shared by the main loop
+// and `<<EOF>>` rules; the token spans `start_line`, `start_column` and `start` to `end_line`,
+// `end_column` and `self.pos`. With `channels`, tokens take their channel from `rule_idx`.
+fn generate_action_arms(
+    code: &mut String,
+    output: OutputKind,
+    format: TokenFormat,
+    values: bool,
+    channels: bool,
+    multiple: bool,
+    indent: &str,
+) {
+    let mut lines: Vec<&str> = Vec::new();
+    match output {
+        OutputKind::Binary if format == TokenFormat::Records => {
+            lines.push("RuleAction::Error(msg) => {");
+            lines.push("    eprintln!(\"{}\", msg);");
+            lines.push("},");
+            lines.push("RuleAction::Token { kind, keep_lexeme } => {");
+            lines.push("    let lexeme = if *keep_lexeme { Some(lexeme) } else { None };");
+            if channels {
+                lines.push("    return Some(record(*kind, lexeme, CHANNELS[rule_idx], [start_line, start_column, end_line, end_column, start, self.pos]));");
+            } else {
+                lines.push("    return Some(record(*kind, lexeme, None, [start_line, start_column, end_line, end_column, start, self.pos]));");
+            }
+            lines.push("},");
+            if values {
+                lines.push("RuleAction::Value { kind, value } => {");
+                if channels {
+                    lines.push("    return Some(record(*kind, Some(value), CHANNELS[rule_idx], [start_line, start_column, end_line, end_column, start, self.pos]));");
+                } else {
+                    lines.push("    return Some(record(*kind, Some(value), None, [start_line, start_column, end_line, end_column, start, self.pos]));");
+                }
+                lines.push("},");
+            }
+            if multiple {
+                lines.push("RuleAction::Tokens(tokens) => {");
+                lines.push("    for (kind, keep_lexeme) in tokens.iter() {");
+                lines.push("        let lexeme = if *keep_lexeme { Some(lexeme) } else { None };");
+                if channels {
+                    lines.push("        self.pending.push_back(record(*kind, lexeme, CHANNELS[rule_idx], [start_line, start_column, end_line, end_column, start, self.pos]));");
+                } else {
+                    lines.push("        self.pending.push_back(record(*kind, lexeme, None, [start_line, start_column, end_line, end_column, start, self.pos]));");
+                }
+                lines.push("    }");
+                lines.push("    return self.pending.pop_front();");
+                lines.push("},");
+            }
+        }
+        OutputKind::Binary => {
+            lines.push("RuleAction::Error(msg) => {");
+            lines.push("    eprintln!(\"{}\", msg);");
+            lines.push("},");
+            lines.push("RuleAction::Token { name, keep_lexeme } => {");
+            lines.push("    let token_str = if *keep_lexeme {");
+            match format {
+                TokenFormat::Text => {
+                    lines.push("        format!(\"{}:{} [{},{}-{},{}] @{}-{}\", name, lexeme, start_line, start_column, end_line, end_column, start, self.pos)");
+                    lines.push("    } else {");
+                    lines.push("        format!(\"{} [{},{}-{},{}] @{}-{}\", name, start_line, start_column, end_line, end_column, start, self.pos)");
+                }
+                TokenFormat::Json => {
+                    lines.push(r#"        format!("{{\"kind\":\"{}\",\"lexeme\":\"{}\",\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(name), json_escape(lexeme), start_line, start_column, end_line, end_column, start, self.pos)"#);
+                    lines.push("    } else {");
+                    lines.push(r#"        format!("{{\"kind\":\"{}\",\"lexeme\":null,\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(name), start_line, start_column, end_line, end_column, start, self.pos)"#);
+                }
+                TokenFormat::Records => unreachable!("records have their own arms"),
+            }
+            lines.push("    };");
+            if channels {
+                lines.push("    return Some(with_channel(token_str, CHANNELS[rule_idx]));");
+            } else {
+                lines.push("    return Some(token_str);");
+            }
+            lines.push("},");
+            if values {
+                lines.push("RuleAction::Value { name, value } => {");
+                match (format, channels) {
+                    (TokenFormat::Text, false) => {
+                        lines.push("    return Some(format!(\"{}:{} [{},{}-{},{}] @{}-{}\", name, value, start_line, start_column, end_line, end_column, start, self.pos));");
+                    }
+                    (TokenFormat::Text, true) => {
+                        lines.push("    let token_str = format!(\"{}:{} [{},{}-{},{}] @{}-{}\", name, value, start_line, start_column, end_line, end_column, start, self.pos);");
+                        lines.push("    return Some(with_channel(token_str, CHANNELS[rule_idx]));");
+                    }
+                    (TokenFormat::Json, false) => {
+                        lines.push(r#"    return Some(format!("{{\"kind\":\"{}\",\"lexeme\":\"{}\",\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(name), json_escape(value), start_line, start_column, end_line, end_column, start, self.pos));"#);
+                    }
+                    (TokenFormat::Json, true) => {
+                        lines.push(r#"    let token_str = format!("{{\"kind\":\"{}\",\"lexeme\":\"{}\",\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(name), json_escape(value), start_line, start_column, end_line, end_column, start, self.pos);"#);
+                        lines.push("    return Some(with_channel(token_str, CHANNELS[rule_idx]));");
+                    }
+                    (TokenFormat::Records, _) => unreachable!("records have their own arms"),
+                }
+                lines.push("},");
+            }
+            if multiple {
+                lines.push("RuleAction::Tokens(tokens) => {");
+                lines.push("    for (name, keep_lexeme) in tokens.iter() {");
+                lines.push("        let token_str = if *keep_lexeme {");
+                match format {
+                    TokenFormat::Text => {
+                        lines.push("            format!(\"{}:{} [{},{}-{},{}] @{}-{}\", name, lexeme, start_line, start_column, end_line, end_column, start, self.pos)");
+                        lines.push("        } else {");
+                        lines.push("            format!(\"{} [{},{}-{},{}] @{}-{}\", name, start_line, start_column, end_line, end_column, start, self.pos)");
+                    }
+                    TokenFormat::Json => {
+                        lines.push(r#"            format!("{{\"kind\":\"{}\",\"lexeme\":\"{}\",\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(name), json_escape(lexeme), start_line, start_column, end_line, end_column, start, self.pos)"#);
+                        lines.push("        } else {");
+                        lines.push(r#"            format!("{{\"kind\":\"{}\",\"lexeme\":null,\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(name), start_line, start_column, end_line, end_column, start, self.pos)"#);
+                    }
+                    TokenFormat::Records => unreachable!("records have their own arms"),
+                }
+                lines.push("        };");
+                if channels {
+                    lines.push("        self.pending.push_back(with_channel(token_str, CHANNELS[rule_idx]));");
+                } else {
+                    lines.push("        self.pending.push_back(token_str);");
+                }
+                lines.push("    }");
+                lines.push("    return self.pending.pop_front();");
+                lines.push("},");
+            }
+        }
+        OutputKind::Library if channels => {
+            lines.push("RuleAction::Error(msg) => {");
+            lines.push("    return Some(Token { kind: TokenKind::Error, lexeme: Some(msg.to_string()), line: start_line, column: start_column, end_line, end_column, start, end: self.pos, channel: None });");
+            lines.push("},");
+            lines.push("RuleAction::Token { kind, keep_lexeme } => {");
+            lines.push("    let lexeme = if *keep_lexeme { Some(lexeme.to_string()) } else { None };");
+            lines.push("    return Some(Token { kind: *kind, lexeme, line: start_line, column: start_column, end_line, end_column, start, end: self.pos, channel: CHANNELS[rule_idx] });");
+            lines.push("},");
+            if values {
+                lines.push("RuleAction::Value { kind, value } => {");
+                lines.push("    return Some(Token { kind: *kind, lexeme: Some(value.to_string()), line: start_line, column: start_column, end_line, end_column, start, end: self.pos, channel: CHANNELS[rule_idx] });");
+                lines.push("},");
+            }
+            if multiple {
+                lines.push("RuleAction::Tokens(tokens) => {");
+                lines.push("    for (kind, keep_lexeme) in tokens.iter() {");
+                lines.push("        let lexeme = if *keep_lexeme { Some(lexeme.to_string()) } else { None };");
+                lines.push("        self.pending.push_back(Token { kind: *kind, lexeme, line: start_line, column: start_column, end_line, end_column, start, end: self.pos, channel: CHANNELS[rule_idx] });");
+                lines.push("    }");
+                lines.push("    return self.pending.pop_front();");
+                lines.push("},");
+            }
+        }
+        OutputKind::Library => {
+            lines.push("RuleAction::Error(msg) => {");
+            lines.push("    return Some(Token { kind: TokenKind::Error, lexeme: Some(msg.to_string()), line: start_line, column: start_column, end_line, end_column, start, end: self.pos });");
+            lines.push("},");
+            lines.push("RuleAction::Token { kind, keep_lexeme } => {");
+            lines.push("    let lexeme = if *keep_lexeme { Some(lexeme.to_string()) } else { None };");
+            lines.push("    return Some(Token { kind: *kind, lexeme, line: start_line, column: start_column, end_line, end_column, start, end: self.pos });");
+            lines.push("},");
+            if values {
+                lines.push("RuleAction::Value { kind, value } => {");
+                lines.push("    return Some(Token { kind: *kind, lexeme: Some(value.to_string()), line: start_line, column: start_column, end_line, end_column, start, end: self.pos });");
+                lines.push("},");
+            }
+            if multiple {
+                lines.push("RuleAction::Tokens(tokens) => {");
+                lines.push("    for (kind, keep_lexeme) in tokens.iter() {");
+                lines.push("        let lexeme = if *keep_lexeme { Some(lexeme.to_string()) } else { None };");
+                lines.push("        self.pending.push_back(Token { kind: *kind, lexeme, line: start_line, column: start_column, end_line, end_column, start, end: self.pos });");
+                lines.push("    }");
+                lines.push("    return self.pending.pop_front();");
+                lines.push("},");
+            }
+        }
+    }
+    for line in lines {
+        code.push_str(indent);
+        code.push_str(line);
+        code.push('\n');
+    }
+}
 
+// The no-match branch that steps over a single character
+fn generate_skip_char(code: &mut String, unmatched: Unmatched, output: OutputKind, format: TokenFormat, channels: bool) {
+    code.push_str("                // No match found, skip character\n");
+    code.push_str("                let ch = rest.chars().next().unwrap();\n");
+    // Only an error token needs to know where the character ends
+    if matches!((unmatched, output), (Unmatched::Error, _) | (Unmatched::Report, OutputKind::Library)) {
+        code.push_str("                let (end_line, end_column) = self.advance(&rest[..ch.len_utf8()]);\n");
+    } else {
+        code.push_str("                self.advance(&rest[..ch.len_utf8()]);\n");
+    }
+    match (unmatched, output) {
+        (Unmatched::Skip, _) | (Unmatched::Recover, _) => {}
+        (Unmatched::Report, OutputKind::Binary) => code.push_str(
+            "                eprintln!(\"Unexpected character '{}' [{},{}]\", ch.escape_debug(), start_line, start_column);\n",
+        ),
+        (Unmatched::Error, OutputKind::Binary) => match format {
+            TokenFormat::Text => code.push_str(
+                "                return Some(format!(\"ERROR:{} [{},{}-{},{}] @{}-{}\", ch.escape_debug(), start_line, start_column, end_line, end_column, start, self.pos));\n",
+            ),
+            TokenFormat::Json => {
+                code.push_str(r#"                return Some(format!("{{\"kind\":\"ERROR\",\"lexeme\":\"{}\",\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(&ch.to_string()), start_line, start_column, end_line, end_column, start, self.pos));"#);
+                code.push('\n');
+            }
+            TokenFormat::Records => code.push_str(
+                "                return Some(record(1, Some(&rest[..ch.len_utf8()]), None, [start_line, start_column, end_line, end_column, start, self.pos]));\n",
+            ),
+        },
+        (_, OutputKind::Library) => code.push_str(&format!(
+            "                return Some(Token {{ kind: TokenKind::Error, lexeme: Some(format!(\"Unexpected character '{{}}'\", ch.escape_debug())), line: start_line, column: start_column, end_line, end_column, start, end: self.pos{} }});\n",
+            default_channel(channels)
+        )),
+    }
+}
+
+// The no-match branch for `%unmatched recover`: skip up to the next character that can
+// start a token and emit one error for the whole run
+fn generate_recovery(code: &mut String, output: OutputKind, format: TokenFormat, channels: bool) {
+    code.push_str("                // No match found, skip ahead to a character that can start a token\n");
+    code.push_str("                let end = rest\n");
+    code.push_str("                    .char_indices()\n");
+    code.push_str("                    .skip(1)\n");
+    code.push_str("                    .find(|&(_, ch)| START_CHARS[self.condition].iter().any(|&(low, high)| low <= ch && ch <= high))\n");
+    code.push_str("                    .map_or(rest.len(), |(index, _)| index);\n");
+    code.push_str("                let skipped = &rest[..end];\n");
+    code.push_str("                let (end_line, end_column) = self.advance(skipped);\n");
+    match output {
+        OutputKind::Binary => match format {
+            TokenFormat::Text => code.push_str(
+                "                return Some(format!(\"ERROR:{} [{},{}-{},{}] @{}-{}\", skipped.escape_debug(), start_line, start_column, end_line, end_column, start, self.pos));\n",
+            ),
+            TokenFormat::Json => {
+                code.push_str(r#"                return Some(format!("{{\"kind\":\"ERROR\",\"lexeme\":\"{}\",\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"start\":{},\"end\":{}}}", json_escape(skipped), start_line, start_column, end_line, end_column, start, self.pos));"#);
+                code.push('\n');
+            }
+            TokenFormat::Records => code.push_str(
+                "                return Some(record(1, Some(skipped), None, [start_line, start_column, end_line, end_column, start, self.pos]));\n",
+            ),
+        },
+        OutputKind::Library => code.push_str(&format!(
+            "                return Some(Token {{ kind: TokenKind::Error, lexeme: Some(format!(\"Unexpected input '{{}}'\", skipped.escape_debug())), line: start_line, column: start_column, end_line, end_column, start, end: self.pos{} }});\n",
+            default_channel(channels)
+        )),
+    }
+}
+
+fn generate_helpers(code: &mut String, spec: &Spec, output: OutputKind, format: TokenFormat, backend: Backend, dfa: &DFA) {
+    let (values, channels) = (has_fixed_values(spec), has_channels(spec));
+    let trailing = &dfa.trailing;
     // Add helper types and functions
     code.push_str("#[derive(Debug, Clone)]\n");
     code.push_str("enum RuleAction {\n");
     code.push_str("    Skip,\n");
-    code.push_str("    Error(String),\n");
-    code.push_str("    Token { name: String, keep_lexeme: bool },\n");
+    code.push_str("    Error(&'static str),\n");
+    code.push_str("    Begin(usize),\n");
+    if has_state_stack(spec) {
+        code.push_str("    Push(usize),\n");
+        code.push_str("    Pop,\n");
+    }
+    let records = output == OutputKind::Binary && format == TokenFormat::Records;
+    match output {
+        OutputKind::Binary if records => code.push_str("    Token { kind: usize, keep_lexeme: bool },\n"),
+        OutputKind::Binary => code.push_str("    Token { name: &'static str, keep_lexeme: bool },\n"),
+        OutputKind::Library => code.push_str("    Token { kind: TokenKind, keep_lexeme: bool },\n"),
+    }
+    if values {
+        // Tokens whose lexeme is a fixed value from the spec
+        match output {
+            OutputKind::Binary if records => code.push_str("    Value { kind: usize, value: &'static str },\n"),
+            OutputKind::Binary => code.push_str("    Value { name: &'static str, value: &'static str },\n"),
+            OutputKind::Library => code.push_str("    Value { kind: TokenKind, value: &'static str },\n"),
+        }
+    }
+    if has_multiple_tokens(spec) {
+        // Several tokens from one match, each with whether it keeps the lexeme
+        match output {
+            OutputKind::Binary if records => code.push_str("    Tokens(&'static [(usize, bool)]),\n"),
+            OutputKind::Binary => code.push_str("    Tokens(&'static [(&'static str, bool)]),\n"),
+            OutputKind::Library => code.push_str("    Tokens(&'static [(TokenKind, bool)]),\n"),
+        }
+    }
     code.push_str("}\n\n");
 
+    code.push_str("// The byte length of the longest non-empty match at the start of `input` and its rule.\n");
+    code.push_str("// Ties go to the rule the DFA state settled on: highest priority, then the earliest.\n");
     code.push_str("fn longest_match(\n");
-    code.push_str("    input: &[char],\n");
-    code.push_str("    transitions: &HashMap<(usize, char), usize>,\n");
-    code.push_str("    accepting_states: &HashMap<usize, usize>\n");
+    code.push_str("    input: &str,\n");
+    code.push_str("    condition: usize,\n");
+    code.push_str("    at_line_start: bool\n");
     code.push_str(") -> (usize, Option<usize>) {\n");
-    code.push_str(&format!("    let mut current_state = {};\n", dfa.start_state.0));
+    code.push_str("    let mut current_state = if at_line_start { LINE_START_STATES[condition] } else { START_STATES[condition] };\n");
+    code.push_str("    // A start state accepting is an empty match, which is never a token\n");
     code.push_str("    let mut last_accepting_pos = 0;\n");
     code.push_str("    let mut last_accepting_rule = None;\n\n");
 
-    code.push_str("    // Check if start state is accepting\n");
-    code.push_str("    if let Some(&rule_index) = accepting_states.get(&current_state) {\n");
-    code.push_str("        last_accepting_pos = 0;\n");
-    code.push_str("        last_accepting_rule = Some(rule_index);\n");
-    code.push_str("    }\n\n");
-
-    code.push_str("    for (pos, &ch) in input.iter().enumerate() {\n");
-    code.push_str("        if let Some(&next_state) = transitions.get(&(current_state, ch)) {\n");
-    code.push_str("            current_state = next_state;\n");
-    code.push_str("            \n");
-    code.push_str("            if let Some(&rule_index) = accepting_states.get(&current_state) {\n");
-    code.push_str("                last_accepting_pos = pos + 1;\n");
+    code.push_str("    for (pos, ch) in input.char_indices() {\n");
+    match backend {
+        Backend::Table => {
+            code.push_str("        let row = TRANSITIONS[current_state];\n");
+            code.push_str("        let index = row.partition_point(|&(_, high, _)| high < ch);\n");
+            code.push_str("        if let Some(&(_, _, next)) = row.get(index).filter(|&&(low, _, _)| low <= ch) {\n");
+            code.push_str("            current_state = next;\n");
+        }
+        Backend::Match => {
+            code.push_str("        if let Some(next) = next_state(current_state, ch) {\n");
+            code.push_str("            current_state = next;\n");
+        }
+    }
+    code.push_str("            let end = pos + ch.len_utf8();\n");
+    code.push_str("            if let Some(rule_index) = accepting_rule(current_state, input[end..].chars().next()) {\n");
+    code.push_str("                last_accepting_pos = end;\n");
     code.push_str("                last_accepting_rule = Some(rule_index);\n");
+    if has_shortest(dfa) {
+        code.push_str("                if SHORTEST[rule_index] {\n");
+        code.push_str("                    break;\n");
+        code.push_str("                }\n");
+    }
     code.push_str("            }\n");
     code.push_str("        } else {\n");
     code.push_str("            break;\n");
     code.push_str("        }\n");
     code.push_str("    }\n\n");
 
-    code.push_str("    (last_accepting_pos, last_accepting_rule)\n");
+    let has_trailing = trailing.iter().any(Option::is_some);
+    if has_trailing {
+        code.push_str("    // Rules with trailing context leave the context unconsumed\n");
+        code.push_str("    let token_length = last_accepting_rule.map_or(0, |rule| without_context(&input[..last_accepting_pos], rule));\n");
+        code.push_str("    (token_length, last_accepting_rule)\n");
+    } else {
+        code.push_str("    (last_accepting_pos, last_accepting_rule)\n");
+    }
+    code.push_str("}\n\n");
+
+    if has_trailing {
+        generate_without_context(code, trailing);
+    }
+
+    code.push_str("fn accepting_rule(state: usize, next: Option<char>) -> Option<usize> {\n");
+    code.push_str("    let rule = ACCEPTING_STATES[state];\n");
+    if has_end_of_input(dfa) {
+        code.push_str("    let rule = match LINE_END_STATES[state] {\n");
+        code.push_str("        Some(line_end_rule) if matches!(next, None | Some('\\n')) => Some(rule.map_or(line_end_rule, |rule| better_rule(rule, line_end_rule))),\n");
+        code.push_str("        _ => rule,\n");
+        code.push_str("    };\n");
+        code.push_str("    match END_OF_INPUT_STATES[state] {\n");
+        code.push_str("        Some(end_rule) if next.is_none() => Some(rule.map_or(end_rule, |rule| better_rule(rule, end_rule))),\n");
+        code.push_str("        _ => rule,\n");
+        code.push_str("    }\n");
+        code.push_str("}\n\n");
+
+        code.push_str("// Higher priority wins, then the earlier rule\n");
+        code.push_str("fn better_rule(rule: usize, other: usize) -> usize {\n");
+        code.push_str("    if (PRIORITIES[rule], other) > (PRIORITIES[other], rule) { rule } else { other }\n");
+        code.push_str("}\n");
+    } else {
+        code.push_str("    match LINE_END_STATES[state] {\n");
+        code.push_str("        Some(line_end_rule) if matches!(next, None | Some('\\n')) => {\n");
+        code.push_str("            Some(rule.map_or(line_end_rule, |rule| {\n");
+        code.push_str("                // Higher priority wins, then the earlier rule\n");
+        code.push_str("                if (PRIORITIES[rule], line_end_rule) > (PRIORITIES[line_end_rule], rule) { rule } else { line_end_rule }\n");
+        code.push_str("            }))\n");
+        code.push_str("        }\n");
+        code.push_str("        _ => rule,\n");
+        code.push_str("    }\n");
+        code.push_str("}\n");
+    }
+
+    if output == OutputKind::Binary && channels && !records {
+        generate_with_channel(code, format);
+    }
+    if output == OutputKind::Binary && format == TokenFormat::Json {
+        generate_json_escape(code);
+    }
+    if records {
+        generate_record(code);
+    }
+}
+
+// Marks a printed token with its channel, leaving tokens on the default channel as they are
+fn generate_with_channel(code: &mut String, format: TokenFormat) {
+    code.push_str("\nfn with_channel(token: String, channel: Option<&str>) -> String {\n");
+    code.push_str("    match channel {\n");
+    match format {
+        TokenFormat::Text => code.push_str("        Some(channel) => format!(\"{} ({})\", token, channel),\n"),
+        TokenFormat::Json => {
+            code.push_str(r#"        Some(channel) => format!("{},\"channel\":\"{}\"}}", &token[..token.len() - 1], channel),"#);
+            code.push('\n');
+        }
+        TokenFormat::Records => unreachable!("records carry their channel themselves"),
+    }
+    code.push_str("        None => token,\n");
+    code.push_str("    }\n");
     code.push_str("}\n");
+}
 
-    Ok(code)
+// The byte length of the token in a match of all of `r/s`, per rule, as in `TrailingSplit::token_length`
+fn generate_without_context(code: &mut String, trailing: &[Option<TrailingSplit>]) {
+    code.push_str("fn without_context(matched: &str, rule: usize) -> usize {\n");
+    code.push_str("    match rule {\n");
+    for (rule, split) in trailing.iter().enumerate() {
+        match split {
+            Some(TrailingSplit::Head(count)) => code.push_str(&format!(
+                "        {} => matched.char_indices().nth({}).map_or(matched.len(), |(index, _)| index),\n",
+                rule, count
+            )),
+            Some(TrailingSplit::Tail(count)) if *count > 0 => code.push_str(&format!(
+                "        {} => matched.char_indices().rev().nth({}).map_or(0, |(index, _)| index),\n",
+                rule,
+                count - 1
+            )),
+            _ => {}
+        }
+    }
+    code.push_str("        _ => matched.len(),\n");
+    code.push_str("    }\n");
+    code.push_str("}\n\n");
+}
+
+// Encodes a token as a record, laid out as `TokenFormat::Records` describes
+fn generate_record(code: &mut String) {
+    code.push_str("\n// A length-prefixed record: kind, line, column, end_line, end_column, start and end, then\n");
+    code.push_str("// the lexeme and the channel as 0 for none or their length plus 1 and their bytes. Every\n");
+    code.push_str("// number is an unsigned LEB128 varint, and the layout is stable.\n");
+    code.push_str("fn record(kind: usize, lexeme: Option<&str>, channel: Option<&str>, span: [usize; 6]) -> Vec<u8> {\n");
+    code.push_str("    let mut fields = Vec::new();\n");
+    code.push_str("    push_varint(&mut fields, kind);\n");
+    code.push_str("    for value in span {\n");
+    code.push_str("        push_varint(&mut fields, value);\n");
+    code.push_str("    }\n");
+    code.push_str("    for text in [lexeme, channel] {\n");
+    code.push_str("        match text {\n");
+    code.push_str("            Some(text) => {\n");
+    code.push_str("                push_varint(&mut fields, text.len() + 1);\n");
+    code.push_str("                fields.extend_from_slice(text.as_bytes());\n");
+    code.push_str("            }\n");
+    code.push_str("            None => fields.push(0),\n");
+    code.push_str("        }\n");
+    code.push_str("    }\n\n");
+    code.push_str("    let mut record = Vec::with_capacity(fields.len() + 2);\n");
+    code.push_str("    push_varint(&mut record, fields.len());\n");
+    code.push_str("    record.extend(fields);\n");
+    code.push_str("    record\n");
+    code.push_str("}\n\n");
+    code.push_str("fn push_varint(bytes: &mut Vec<u8>, mut value: usize) {\n");
+    code.push_str("    while value >= 0x80 {\n");
+    code.push_str("        bytes.push(value as u8 | 0x80);\n");
+    code.push_str("        value >>= 7;\n");
+    code.push_str("    }\n");
+    code.push_str("    bytes.push(value as u8);\n");
+    code.push_str("}\n");
+}
+
+// Escapes a lexeme at runtime for use inside a JSON string
+fn generate_json_escape(code: &mut String) {
+    code.push_str("\nfn json_escape(s: &str) -> String {\n");
+    code.push_str("    let mut escaped = String::new();\n");
+    code.push_str("    for ch in s.chars() {\n");
+    code.push_str("        match ch {\n");
+    code.push_str(r#"            '"' => escaped.push_str("\\\""),"#);
+    code.push('\n');
+    code.push_str(r#"            '\\' => escaped.push_str("\\\\"),"#);
+    code.push('\n');
+    code.push_str(r#"            '\n' => escaped.push_str("\\n"),"#);
+    code.push('\n');
+    code.push_str(r#"            '\r' => escaped.push_str("\\r"),"#);
+    code.push('\n');
+    code.push_str(r#"            '\t' => escaped.push_str("\\t"),"#);
+    code.push('\n');
+    code.push_str(r#"            _ if (ch as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", ch as u32)),"#);
+    code.push('\n');
+    code.push_str("            _ => escaped.push(ch),\n");
+    code.push_str("        }\n");
+    code.push_str("    }\n");
+    code.push_str("    escaped\n");
+    code.push_str("}\n");
+}
+
+/// The token kind names of `spec` in the order of their ids in `TokenFormat::Records`
+/// records: EOF, ERROR, then each distinct token name in rule order
+pub fn record_kind_names(spec: &Spec) -> Vec<String> {
+    ["EOF", "ERROR"].into_iter().map(String::from).chain(token_kinds(spec).into_iter().map(|(name, _)| name)).collect()
+}
+
+// Rust keywords, reserved words included, which no variant can be named
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn", "for",
+    "gen", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+    "static", "struct", "super", "trait", "true", "try", "type", "unsafe", "use", "where", "while", "abstract",
+    "become", "box", "do", "final", "macro", "override", "priv", "typeof", "unsized", "virtual", "yield", "_",
+];
+
+// The record kind id of the token name `name`, after EOF and ERROR
+fn record_kind(kinds: &[(String, String)], name: &str) -> usize {
+    kinds.iter().position(|(kind_name, _)| kind_name == name).map_or(0, |position| position + 2)
+}
+
+// Distinct token names in rule order, each paired with its enum variant. Names that
+// sanitize to a variant already taken get a numeric suffix, so every name keeps its own.
+pub(crate) fn token_kinds(spec: &Spec) -> Vec<(String, String)> {
+    let mut kinds: Vec<(String, String)> = Vec::new();
+    for rule in &spec.rules {
+        for name in token_names(&rule.action) {
+            if kinds.iter().any(|(kind_name, _)| kind_name == name) {
+                continue;
+            }
+            let base = variant_name(name);
+            let mut variant = base.clone();
+            let mut suffix = 2;
+            while kinds.iter().any(|(_, taken)| *taken == variant) {
+                variant = format!("{}_{}", base, suffix);
+                suffix += 1;
+            }
+            kinds.push((name.clone(), variant));
+        }
+    }
+    kinds
+}
+
+// The token names an action produces
+pub(crate) fn token_names(action: &Action) -> Vec<&String> {
+    match action {
+        Action::Token { name, .. } | Action::Skip(Some(name)) => vec![name],
+        Action::Tokens { tokens, .. } => tokens.iter().map(|(name, _)| name).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Token names become enum variants, so anything that is not an identifier is replaced
+fn variant_name(name: &str) -> String {
+    let mut variant: String = name
+        .chars()
+        .map(|ch| if ch.is_alphanumeric() || ch == '_' { ch } else { '_' })
+        .collect();
+    if variant.starts_with(|ch: char| ch.is_ascii_digit()) {
+        variant.insert(0, '_');
+    }
+    // Keep clear of keywords and the variants the generator adds itself
+    if RUST_KEYWORDS.contains(&variant.as_str()) || variant == "Error" || variant == "Eof" {
+        variant.push('_');
+    }
+    variant
 }
 
 fn escape_char(ch: char) -> String {
@@ -226,6 +1358,7 @@ fn escape_char(ch: char) -> String {
         '\\' => "\\\\".to_string(),
         '\'' => "\\'".to_string(),
         '"' => "\\\"".to_string(),
+        _ if ch.is_control() => format!("\\u{{{:x}}}", ch as u32),
         _ => ch.to_string(),
     }
 }
@@ -237,3 +1370,28 @@ fn escape_string(s: &str) -> String {
         .replace('\t', "\\t")
         .replace('\r', "\\r")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec_parser::parse_spec;
+
+    fn code(spec: &str, format: TokenFormat) -> String {
+        let spec = parse_spec(spec).unwrap();
+        generate_lexer_code(&spec, &crate::build_dfa(&spec).unwrap(), &GeneratorOptions { format, ..GeneratorOptions::default() }).unwrap()
+    }
+
+    #[test]
+    fn stray_characters_are_reported_on_request() {
+        assert!(code("%unmatched report\n[a-z]+ WORD true\n", TokenFormat::Text).contains("eprintln!(\"Unexpected character"));
+        assert!(!code("[a-z]+ WORD true\n", TokenFormat::Text).contains("Unexpected character"));
+    }
+
+    #[test]
+    fn json_lines_escape_lexemes() {
+        let json = code("[a-z]+ WORD true\n; SEMI false\n", TokenFormat::Json);
+        assert!(json.contains("json_escape(lexeme)"));
+        assert!(json.contains("fn json_escape(s: &str) -> String"));
+        assert!(!code("[a-z]+ WORD true\n", TokenFormat::Text).contains("json_escape"));
+    }
+}
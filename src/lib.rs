@@ -0,0 +1,386 @@
+//! dragonlex turns a spec of regex rules into a lexer, either by generating
+//! standalone Rust source or by running the compiled DFA in-process.
+
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod regex_parser;
+pub mod nfa;
+pub mod dfa;
+pub mod lexer_generator;
+pub mod spec_parser;
+pub mod token;
+
+pub use spec_parser::{parse_spec, parse_spec_from_path, Action, Rule, Spec, SpecError, SpecErrorKind, Unmatched, DEFAULT_CHANNEL, EOF_RULE};
+pub use regex_parser::{parse_regex, parse_regex_with_warnings, RegexError, RegexNode, TrailingSplit};
+pub use nfa::{BuildOptions, NFA};
+pub use dfa::DFA;
+pub use token::{Match, Token};
+pub use lexer_generator::{generate_into, generate_lexer, generate_source, normalize_input, record_kind_names, run_generated_lexer, write_lexer, write_lexer_timed, Backend, GeneratedLexer, GeneratorOptions, OutputKind, TokenFormat};
+
+use std::time::{Duration, Instant};
+use regex_parser::in_ranges;
+use lexer_generator::{token_kinds, token_names};
+
+/// Parses a spec and compiles its rules into a minimal DFA, ready for `DFA::simulate`.
+pub fn compile_spec(content: &str) -> Result<DFA, String> {
+    let spec = parse_spec(content).map_err(|err| err.to_string())?;
+    build_dfa(&spec)
+}
+
+/// Compiles the rules of an already parsed spec into a minimal DFA. Accepting
+/// states carry the index of the rule in `spec.rules`.
+pub fn build_dfa(spec: &Spec) -> Result<DFA, String> {
+    build_dfa_with(spec, None)
+}
+
+/// Like `build_dfa`, failing if subset construction makes more than `max_states` states
+pub fn build_dfa_with(spec: &Spec, max_states: Option<usize>) -> Result<DFA, String> {
+    build_dfa_timed(spec, max_states, &mut Vec::new())
+}
+
+/// Like `build_dfa_with`, adding the wall-clock time of each phase to `timings` in the
+/// order they ran
+pub fn build_dfa_timed(spec: &Spec, max_states: Option<usize>, timings: &mut Vec<(&'static str, Duration)>) -> Result<DFA, String> {
+    compile(spec, max_states, false, timings)
+}
+
+/// Like `build_dfa`, for `DFA::tokenize_bytes`: each character of a regex stands for
+/// the byte of the same value, and `.` and negated classes cover all 256 bytes,
+/// newline still left out unless `(DOTALL)`
+pub fn build_byte_dfa(spec: &Spec) -> Result<DFA, String> {
+    compile(spec, None, true, &mut Vec::new())
+}
+
+fn compile(spec: &Spec, max_states: Option<usize>, bytes: bool, timings: &mut Vec<(&'static str, Duration)>) -> Result<DFA, String> {
+    let start = Instant::now();
+    let asts = parse_rules(spec).map_err(|errors| errors.join("\n"))?;
+    let start = record_phase(timings, "parse regexes", start);
+    let nfas = asts_to_nfas(spec, &asts, bytes);
+    let start = record_phase(timings, "build NFAs", start);
+    let mut dfa = DFA::from_nfas_with(nfas, &condition_rules(spec), &rule_priorities(spec), max_states, spec.alphabet.as_deref())?;
+    let start = record_phase(timings, "subset construction", start);
+    dfa.remove_dead_states();
+
+    // Convert to a minimal DFA
+    let dfa = dfa.minimize();
+    record_phase(timings, "minimize", start);
+    Ok(dfa)
+}
+
+// Adds the time since `start` to `timings` as `phase`, returning now as the start of the next one
+pub(crate) fn record_phase(timings: &mut Vec<(&'static str, Duration)>, phase: &'static str, start: Instant) -> Instant {
+    let now = Instant::now();
+    timings.push((phase, now - start));
+    now
+}
+
+/// Combines the rules of a spec into one NFA for `NFA::simulate`, skipping subset
+/// construction. Useful when `.` or large negated classes make the DFA expensive.
+pub fn build_nfa(spec: &Spec) -> Result<NFA, String> {
+    Ok(NFA::combine(&rule_nfas(spec)?, &condition_rules(spec), &rule_priorities(spec)))
+}
+
+fn rule_priorities(spec: &Spec) -> Vec<i32> {
+    spec.rules.iter().map(|rule| rule.priority).collect()
+}
+
+/// Warnings for the rules of `spec` that can never match in `dfa`, analogous to
+/// flex's "rule cannot be matched"
+pub fn unmatchable_rule_warnings(spec: &Spec, dfa: &DFA) -> Vec<String> {
+    dfa.unmatchable_rules(spec.rules.len())
+        .into_iter()
+        .filter(|&index| !spec.rules[index].eof)
+        .map(|index| {
+            let rule = &spec.rules[index];
+            format!("Warning: rule on line {} ('{}') can never be matched", rule.line, rule.regex)
+        })
+        .collect()
+}
+
+// The positions in `rule_nfas` of the rules active in each start condition
+fn condition_rules(spec: &Spec) -> Vec<Vec<usize>> {
+    let matching_rules: Vec<&Rule> = spec.rules.iter().filter(|rule| !rule.eof).collect();
+    (0..spec.conditions.len())
+        .map(|condition| {
+            (0..matching_rules.len())
+                .filter(|&position| matching_rules[position].conditions.contains(&condition))
+                .collect()
+        })
+        .collect()
+}
+
+/// Parses the regex of every rule in `spec`, reporting all the malformed ones at once
+/// instead of stopping at the first
+pub fn validate(spec: &Spec) -> Result<(), Vec<String>> {
+    parse_rules(spec).map(|_| ())
+}
+
+/// Warnings for the regexes of `spec` that parse but are probably mistakes, such as
+/// `[a-ca-c]` listing characters twice, a character left out of the `%alphabet` or a
+/// regex like `a*` that matches the empty string. Rules that fail to parse are left to
+/// `validate`.
+pub fn regex_warnings(spec: &Spec) -> Vec<String> {
+    spec.rules
+        .iter()
+        .filter(|rule| !rule.eof)
+        .filter_map(|rule| {
+            let (regex_ast, mut warnings) = parse_regex_with_warnings(&rule.regex).ok()?;
+            if let Some(alphabet) = &spec.alphabet
+                && let Some(ch) = outside_alphabet(&regex_ast, alphabet, rule.case_insensitive)
+            {
+                warnings.push(format!("'{}' is not in the %alphabet, so it never matches", ch.escape_debug()));
+            }
+            if NFA::from_regex(&regex_ast).matches_empty() {
+                warnings.push("it matches the empty string, which is never a token".to_string());
+            }
+            Some((rule, warnings))
+        })
+        .flat_map(|(rule, warnings)| {
+            warnings
+                .into_iter()
+                .map(move |warning| format!("Warning: rule on line {} ('{}'): {}", rule.line, rule.regex, warning))
+        })
+        .collect()
+}
+
+// The first character a regex names, as a literal or class member, that is not in
+// `alphabet`. `.` and negated classes are meant to match whatever is left, so they pass.
+fn outside_alphabet(regex: &RegexNode, alphabet: &[(char, char)], case_insensitive: bool) -> Option<char> {
+    let allowed = |ch: char| {
+        in_ranges(alphabet, ch)
+            || (case_insensitive && (in_ranges(alphabet, ch.to_ascii_lowercase()) || in_ranges(alphabet, ch.to_ascii_uppercase())))
+    };
+    let mut pending = vec![regex];
+    while let Some(node) = pending.pop() {
+        match node {
+            RegexNode::Char(ch) if !allowed(*ch) => return Some(*ch),
+            RegexNode::CharClass(chars) => {
+                if let Some(ch) = chars.iter().find(|&&ch| !allowed(ch)) {
+                    return Some(*ch);
+                }
+            }
+            RegexNode::Ranges(ranges) => {
+                if let Some(ch) = ranges.iter().flat_map(|&(low, high)| low..=high).find(|&ch| !allowed(ch)) {
+                    return Some(ch);
+                }
+            }
+            RegexNode::Concatenation(left, right) | RegexNode::Alternation(left, right) | RegexNode::TrailingContext(left, right) => {
+                pending.push(right);
+                pending.push(left);
+            }
+            RegexNode::Kleene(inner)
+            | RegexNode::Plus(inner)
+            | RegexNode::Optional(inner)
+            | RegexNode::Repeat { node: inner, .. }
+            | RegexNode::Lazy(inner)
+            | RegexNode::Capture(_, inner) => pending.push(inner),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Warnings for token names that can't be variants of the generated library's
+/// `TokenKind` as they are, such as `foo-bar` or `fn`, naming the variant each gets
+/// instead. Text and JSON output use the names unchanged.
+pub fn token_name_warnings(spec: &Spec) -> Vec<String> {
+    token_kinds(spec)
+        .into_iter()
+        .filter(|(name, variant)| name != variant)
+        .filter_map(|(name, variant)| {
+            let rule = spec.rules.iter().find(|rule| token_names(&rule.action).contains(&&name))?;
+            Some(format!(
+                "Warning: rule on line {} ('{}'): token name '{}' can't be a TokenKind variant as it is, so it becomes '{}'",
+                rule.line, rule.regex, name, variant
+            ))
+        })
+        .collect()
+}
+
+/// Warnings for rules with the same regex as an earlier rule in one of its start
+/// conditions but a different action, where precedence quietly picks one of them. Copies
+/// with the same action are harmless and only built once.
+pub fn conflicting_rule_warnings(spec: &Spec) -> Vec<String> {
+    let Ok(asts) = parse_rules(spec) else {
+        return Vec::new();
+    };
+    let options = |index: usize| {
+        let rule = &spec.rules[index];
+        (rule.case_insensitive, rule.dotall, rule.shortest)
+    };
+
+    let mut warnings = Vec::new();
+    for (later, (index, ast)) in asts.iter().enumerate() {
+        let rule = &spec.rules[*index];
+        let earlier = asts[..later].iter().find(|(other_index, other_ast)| {
+            let other = &spec.rules[*other_index];
+            other_ast == ast
+                && options(*other_index) == options(*index)
+                && other.action != rule.action
+                && other.conditions.iter().any(|condition| rule.conditions.contains(condition))
+        });
+        if let Some((other_index, _)) = earlier {
+            warnings.push(format!(
+                "Warning: rules on lines {} and {} have the same regex ('{}') but different actions",
+                spec.rules[*other_index].line, rule.line, rule.regex
+            ));
+        }
+    }
+    warnings
+}
+
+// The regex AST of each rule with a regex, paired with its index, or an error for each
+// rule that fails to parse. `<<EOF>>` rules have no regex.
+fn parse_rules(spec: &Spec) -> Result<Vec<(usize, RegexNode)>, Vec<String>> {
+    let mut asts = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, rule) in spec.rules.iter().enumerate().filter(|(_, rule)| !rule.eof) {
+        match parse_regex(&rule.regex) {
+            Ok(regex_ast) => asts.push((index, regex_ast)),
+            Err(e) => {
+                let snippet = e.snippet(&rule.regex).replace('\n', "\n    ");
+                errors.push(format!("Error parsing regex '{}' on line {}: {}\n    {}", rule.regex, rule.line, e, snippet));
+            }
+        }
+    }
+
+    if errors.is_empty() { Ok(asts) } else { Err(errors) }
+}
+
+// Build NFAs for each rule, paired with the rule's index
+fn rule_nfas(spec: &Spec) -> Result<Vec<(NFA, usize)>, String> {
+    let asts = parse_rules(spec).map_err(|errors| errors.join("\n"))?;
+    Ok(asts_to_nfas(spec, &asts, false))
+}
+
+fn asts_to_nfas(spec: &Spec, asts: &[(usize, RegexNode)], bytes: bool) -> Vec<(NFA, usize)> {
+    asts.iter()
+        .map(|(index, regex_ast)| {
+            let rule = &spec.rules[*index];
+            let options = BuildOptions { case_insensitive: rule.case_insensitive, dotall: rule.dotall, shortest: rule.shortest, bytes };
+            (NFA::from_regex_with(regex_ast, options), *index)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyword_after_an_identifier_rule_warns() {
+        let spec = parse_spec("[a-z]+ ID true\nif IF false\n[0-9]+ NUM true\n").unwrap();
+        let dfa = build_dfa(&spec).unwrap();
+        assert_eq!(unmatchable_rule_warnings(&spec, &dfa), ["Warning: rule on line 2 ('if') can never be matched"]);
+        // Listed first, the keyword wins its tie
+        let spec = parse_spec("if IF false\n[a-z]+ ID true\n").unwrap();
+        assert!(unmatchable_rule_warnings(&spec, &build_dfa(&spec).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn validate_reports_every_bad_regex() {
+        let spec = parse_spec("a{2,1} ID true\n[0-9]+ NUM true\n(ab X true\n").unwrap();
+        let errors = validate(&spec).unwrap_err();
+        let first_lines: Vec<&str> = errors.iter().map(|error| error.lines().next().unwrap()).collect();
+        assert_eq!(first_lines, [
+            "Error parsing regex 'a{2,1}' on line 1: Invalid repetition {2,1}: minimum exceeds maximum at position 1",
+            "Error parsing regex '(ab' on line 3: Missing closing parenthesis at position 0",
+        ]);
+        assert!(validate(&parse_spec("[0-9]+ NUM true\n").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn state_limit_is_an_error() {
+        // `(a|b)*a(a|b){6}` needs a state for each of the last seven characters it has seen
+        let spec = parse_spec("(a|b)*a(a|b){6} X true\n").unwrap();
+        let err = build_dfa_with(&spec, Some(20)).unwrap_err();
+        assert!(err.starts_with("DFA has over 20 states"), "{}", err);
+        assert!(build_dfa_with(&spec, Some(1000)).is_ok());
+        assert!(build_dfa_with(&spec, None).is_ok());
+    }
+
+    #[test]
+    fn token_names_that_need_changing_warn() {
+        let spec = parse_spec("[a-z]+-[a-z]+ foo-bar true\n[0-9]+ NUM true\n").unwrap();
+        assert_eq!(
+            token_name_warnings(&spec),
+            ["Warning: rule on line 1 ('[a-z]+-[a-z]+'): token name 'foo-bar' can't be a TokenKind variant as it is, so it becomes 'foo_bar'"]
+        );
+        // Lexing in-process keeps the name as written
+        let names: Vec<String> = build_dfa(&spec).unwrap().tokenize(&spec, "ab-cd 12").into_iter().map(|token| token.name).collect();
+        assert_eq!(names, ["foo-bar", "NUM", "EOF"]);
+        assert!(token_name_warnings(&parse_spec("[0-9]+ foo_bar true\n").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn odd_regexes_build_or_fail() {
+        // Malformed inputs that end partway through something, then random ones over the
+        // characters the parser treats specially
+        let mut regexes: Vec<String> = ["\\", "[a-", "[a-\\", "[-", "[]", "[^]", "a{", "a{2,1}", "\\p{", "\\u{110000}", "[[:", "(?<", "a/b/c"]
+            .iter()
+            .map(|regex| regex.to_string())
+            .collect();
+        regexes.push("(".repeat(300) + &")".repeat(300));
+        regexes.push("[a&&".repeat(300));
+        let alphabet: Vec<char> = "ab\\[]^-(){},|*+?./$:&019dwsDpPuxzi\"é".chars().collect();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..2000 {
+            let mut next = || {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as usize
+            };
+            let len = next() % 16;
+            regexes.push((0..len).map(|_| alphabet[next() % alphabet.len()]).collect());
+        }
+
+        for regex in regexes {
+            // Either the spec is rejected or it builds, within the state limit so none can run away
+            if let Ok(spec) = parse_spec(&format!("{} X true\n", regex)) {
+                let _ = build_dfa_with(&spec, Some(2000));
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_class_members_warn_and_are_stored_once() {
+        let spec = parse_spec("[a-ca-c]+ X true\n[xy] Y true\n").unwrap();
+        assert_eq!(regex_warnings(&spec), ["Warning: rule on line 1 ('[a-ca-c]+'): Range a-c overlaps characters already in the class"]);
+        assert_eq!(parse_regex("[a-ca-c]").unwrap(), RegexNode::CharClass(vec!['a', 'b', 'c']));
+        assert_eq!(parse_regex("[0-90-9]").unwrap(), parse_regex("[0-9]").unwrap());
+    }
+
+    #[test]
+    fn same_regex_with_another_action_warns() {
+        let spec = parse_spec("dog NOUN true\ndog VERB true\n[a-z]+ WORD true\n").unwrap();
+        assert_eq!(conflicting_rule_warnings(&spec), ["Warning: rules on lines 1 and 2 have the same regex ('dog') but different actions"]);
+        // An exact copy is harmless, and is dropped in favour of the first
+        let spec = parse_spec("dog NOUN true\ndog NOUN true\n[a-z]+ WORD true\n").unwrap();
+        assert!(conflicting_rule_warnings(&spec).is_empty());
+        let rules: Vec<Option<usize>> = build_dfa(&spec).unwrap().simulate("dog").into_iter().map(|(_, _, _, _, rule)| rule).collect();
+        assert_eq!(rules, [Some(0), None]);
+        // Different start conditions never meet
+        let spec = parse_spec("%state S\ndog NOUN true\n<S>dog VERB true\n").unwrap();
+        assert!(conflicting_rule_warnings(&spec).is_empty());
+    }
+
+    #[test]
+    fn characters_outside_the_alphabet_warn() {
+        let spec = parse_spec("%alphabet a-z\n[a-z]+ ID true\nx|Y XY true\n[^a] NOT_A true\n").unwrap();
+        assert_eq!(regex_warnings(&spec), ["Warning: rule on line 3 ('x|Y'): 'Y' is not in the %alphabet, so it never matches"]);
+        // Under (CI) either case of a letter will do
+        assert!(regex_warnings(&parse_spec("%alphabet a-z\nx|Y XY true (CI)\n").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn nullable_rule_warns_and_lexing_terminates() {
+        let spec = parse_spec("[0-9]* NUM true\n[a-z]+ ID true\n").unwrap();
+        assert_eq!(regex_warnings(&spec), ["Warning: rule on line 1 ('[0-9]*'): it matches the empty string, which is never a token"]);
+
+        // No zero-length NUM comes out where no digit follows
+        let names: Vec<String> = build_dfa(&spec).unwrap().tokenize(&spec, "ab 12").into_iter().map(|token| token.name).collect();
+        assert_eq!(names, ["ID", "NUM", "EOF"]);
+    }
+}
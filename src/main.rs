@@ -1,52 +1,411 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, Write};
 use std::process;
+use std::time::{Duration, Instant};
 
-mod regex_parser;
-mod nfa;
-mod dfa;
-mod lexer_generator;
-mod spec_parser;
+use dragonlex::{
+    build_dfa_timed, conflicting_rule_warnings, parse_regex, parse_spec_from_path, normalize_input, regex_warnings, token_name_warnings, unmatchable_rule_warnings, validate,
+    write_lexer_timed,
+    Action, Backend, GeneratedLexer, GeneratorOptions, OutputKind, Rule, Spec, TokenFormat, DFA, NFA,
+};
 
-use spec_parser::parse_spec;
-use lexer_generator::generate_lexer;
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [--lib [--no-std]] [--json | --records] [--no-compile] [--strict] [--timing] [-o <output.rs>] [--tab-width <n>] [--backend table|match] [--max-states <n>] [--emit-dot <file.dot>] [--stats <input> [--raw] | --list-rules] [--save-dfa <file> | --load-dfa <file>] <spec_file>", program);
+    eprintln!("       {} --emit-nfa <regex>", program);
+    eprintln!("       {} --repl", program);
+    process::exit(1);
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <spec_file>", args[0]);
-        process::exit(1);
+    let mut options = GeneratorOptions::default();
+    let mut spec_file = None;
+    let mut dot_file = None;
+    let mut nfa_regex = None;
+    let mut save_dfa = None;
+    let mut load_dfa = None;
+    let mut stats_input = None;
+    let mut strict = false;
+    let mut timing = false;
+    let mut list_rules = false;
+    let mut interactive = false;
+    let mut raw = false;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--lib" => options.output = OutputKind::Library,
+            "--no-std" => options.no_std = true,
+            "--json" => options.format = TokenFormat::Json,
+            "--records" => options.format = TokenFormat::Records,
+            "--no-compile" => options.compile = false,
+            "--strict" => strict = true,
+            "--timing" => timing = true,
+            "--list-rules" => list_rules = true,
+            "--repl" => interactive = true,
+            "--raw" => raw = true,
+            "-o" | "--output" => match rest.next() {
+                Some(path) => options.source_path = path.into(),
+                None => usage(&args[0]),
+            },
+            "--tab-width" => match rest.next().and_then(|width| width.parse().ok()) {
+                Some(width) if width > 0 => options.tab_width = width,
+                _ => usage(&args[0]),
+            },
+            "--backend" => match rest.next().map(String::as_str) {
+                Some("table") => options.backend = Backend::Table,
+                Some("match") => options.backend = Backend::Match,
+                _ => usage(&args[0]),
+            },
+            "--max-states" => match rest.next().and_then(|count| count.parse().ok()) {
+                Some(count) => options.max_states = Some(count),
+                None => usage(&args[0]),
+            },
+            "--emit-dot" => match rest.next() {
+                Some(path) => dot_file = Some(path),
+                None => usage(&args[0]),
+            },
+            "--emit-nfa" => match rest.next() {
+                Some(regex) => nfa_regex = Some(regex),
+                None => usage(&args[0]),
+            },
+            "--stats" => match rest.next() {
+                Some(path) => stats_input = Some(path),
+                None => usage(&args[0]),
+            },
+            "--save-dfa" => match rest.next() {
+                Some(path) => save_dfa = Some(path),
+                None => usage(&args[0]),
+            },
+            "--load-dfa" => match rest.next() {
+                Some(path) => load_dfa = Some(path),
+                None => usage(&args[0]),
+            },
+            _ if spec_file.is_none() && !arg.starts_with('-') => spec_file = Some(arg),
+            _ => usage(&args[0]),
+        }
     }
 
-    let spec_file = &args[1];
+    // Print the Thompson NFA of one regex, which needs no spec
+    if let Some(regex) = nfa_regex {
+        match parse_regex(regex) {
+            Ok(regex_ast) => print!("{}", NFA::from_regex(&regex_ast).to_dot()),
+            Err(err) => {
+                eprintln!("Error parsing regex '{}': {}\n    {}", regex, err, err.snippet(regex).replace('\n', "\n    "));
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    // Reads spec file
-    let spec_content = match fs::read_to_string(spec_file) {
-        Ok(content) => content,
-        Err(err) => {
-            eprintln!("Error reading spec file '{}': {}", spec_file, err);
+    // Try one regex against lines typed in, which needs no spec either
+    if interactive {
+        if let Err(err) = repl(io::stdin().lock(), &mut io::stdout()) {
+            eprintln!("Error: {}", err);
             process::exit(1);
         }
+        return;
+    }
+
+    let Some(spec_file) = spec_file else {
+        usage(&args[0]);
     };
+    if save_dfa.is_some() && load_dfa.is_some() {
+        usage(&args[0]);
+    }
 
-    // Parse the spec
-    let spec = match parse_spec(&spec_content) {
+    // Read and parse the spec, along with any specs it includes
+    let mut timings = Vec::new();
+    let start = Instant::now();
+    let spec = match parse_spec_from_path(spec_file) {
         Ok(spec) => spec,
         Err(err) => {
             eprintln!("Error parsing spec: {}", err);
             process::exit(1);
         }
     };
+    timings.push(("parse spec", start.elapsed()));
+
+    // Show the rules as parsed, without building anything
+    if list_rules {
+        print_rules(&spec);
+        if timing {
+            print_timings(&timings);
+        }
+        return;
+    }
+
+    let dfa = match load_dfa {
+        // A cached DFA skips regex parsing and subset construction
+        Some(dfa_file) => {
+            let start = Instant::now();
+            let dfa = load_cached_dfa(dfa_file, &spec);
+            timings.push(("load DFA", start.elapsed()));
+            dfa
+        }
+        None => {
+            let start = Instant::now();
+            // Report every malformed regex before giving up
+            if let Err(errors) = validate(&spec) {
+                for err in errors {
+                    eprintln!("{}", err);
+                }
+                process::exit(1);
+            }
+            for warning in regex_warnings(&spec) {
+                eprintln!("{}", warning);
+            }
+            // Under --strict, a rule shadowed by an identical one is an error
+            let conflicts = conflicting_rule_warnings(&spec);
+            for warning in &conflicts {
+                eprintln!("{}", warning);
+            }
+            if strict && !conflicts.is_empty() {
+                eprintln!("Error: rules with the same regex need the same action under --strict");
+                process::exit(1);
+            }
+            // Names only have to be identifiers to become the library's TokenKind
+            if options.output == OutputKind::Library {
+                let names = token_name_warnings(&spec);
+                for warning in &names {
+                    eprintln!("{}", warning);
+                }
+                if strict && !names.is_empty() {
+                    eprintln!("Error: token names need to be identifiers for --lib under --strict");
+                    process::exit(1);
+                }
+            }
+            timings.push(("check rules", start.elapsed()));
+
+            match build_dfa_timed(&spec, options.max_states, &mut timings) {
+                Ok(dfa) => dfa,
+                Err(err) => {
+                    eprintln!("Error building DFA: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+    if let Some(dfa_file) = save_dfa
+        && let Err(err) = fs::write(dfa_file, dfa.to_bytes())
+    {
+        eprintln!("Error writing '{}': {}", dfa_file, err);
+        process::exit(1);
+    }
+    for warning in unmatchable_rule_warnings(&spec, &dfa) {
+        eprintln!("{}", warning);
+    }
+
+    // Tokenize a sample input in-process and summarize it instead of generating a lexer
+    if let Some(input_file) = stats_input {
+        match fs::read_to_string(input_file) {
+            Ok(input) if raw => print_stats(&spec, &dfa, &input),
+            Ok(input) => print_stats(&spec, &dfa, &normalize_input(&input)),
+            Err(err) => {
+                eprintln!("Error reading '{}': {}", input_file, err);
+                process::exit(1);
+            }
+        }
+        if timing {
+            print_timings(&timings);
+        }
+        return;
+    }
+
+    // Write the DFA as GraphViz instead of generating a lexer
+    if let Some(dot_file) = dot_file {
+        if let Err(err) = fs::write(dot_file, dfa.to_dot()) {
+            eprintln!("Error writing '{}': {}", dot_file, err);
+            process::exit(1);
+        }
+        println!("DFA written to {}", dot_file);
+        if timing {
+            print_timings(&timings);
+        }
+        return;
+    }
 
     // Generate the lexer
-    match generate_lexer(&spec) {
-        Ok(_) => {
+    let generated = write_lexer_timed(&spec, &dfa, &options, &mut timings);
+    if timing {
+        print_timings(&timings);
+    }
+    match generated {
+        Ok(GeneratedLexer::Compiled { .. }) => {
             println!("Lexer generated successfully");
         }
+        Ok(GeneratedLexer::SourceOnly(source)) => {
+            println!("Lexer source written to {} (compilation skipped)", source.display());
+        }
         Err(err) => {
             eprintln!("Error generating lexer: {}", err);
             process::exit(1);
         }
     }
 }
+
+// Reads a regex, then lines to report its match at the start of, until an empty line asks
+// for the next regex. A regex that fails to parse is reported and asked for again.
+fn repl(input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut lines = input.lines();
+    let mut dfa = None;
+    loop {
+        write!(output, "{}", if dfa.is_some() { "input> " } else { "regex> " })?;
+        output.flush()?;
+        let Some(line) = lines.next().transpose()? else {
+            return writeln!(output);
+        };
+
+        match &dfa {
+            None if line.is_empty() => {}
+            None => match parse_regex(&line) {
+                Ok(regex_ast) => dfa = Some(DFA::from_nfas(vec![(NFA::from_regex(&regex_ast), 0)], &[vec![0]], &[0])),
+                Err(err) => writeln!(output, "Error parsing regex '{}': {}\n    {}", line, err, err.snippet(&line).replace('\n', "\n    "))?,
+            },
+            Some(_) if line.is_empty() => dfa = None,
+            // Only a match of the rule at the very start counts; `simulate` skips ahead otherwise
+            Some(dfa) => match dfa.simulate(&line).first() {
+                Some((lexeme, _, _, 0, Some(_))) => {
+                    let length = lexeme.chars().count();
+                    writeln!(output, "matches {} character{}: '{}'", length, if length == 1 { "" } else { "s" }, lexeme.escape_debug())?
+                }
+                _ => writeln!(output, "no match")?,
+            },
+        }
+    }
+}
+
+// Prints how long each phase took to stderr, one per line with the total last
+fn print_timings(timings: &[(&str, Duration)]) {
+    let width = timings.iter().map(|(phase, _)| phase.len()).max().unwrap_or(0).max("total".len());
+    for (phase, duration) in timings {
+        eprintln!("{:<width$}  {:>10.3} ms", phase, duration.as_secs_f64() * 1000.0);
+    }
+    let total: Duration = timings.iter().map(|(_, duration)| *duration).sum();
+    eprintln!("{:<width$}  {:>10.3} ms", "total", total.as_secs_f64() * 1000.0);
+}
+
+// Prints how many tokens of each name `input` produces, sorted by name, then the total
+// and how many of them were errors. The final EOF token is not counted.
+fn print_stats(spec: &Spec, dfa: &DFA, input: &str) {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for token in dfa.token_iter(spec, input).filter(|token| token.name != "EOF") {
+        *counts.entry(token.name).or_default() += 1;
+    }
+    let errors = counts.remove("ERROR").unwrap_or(0);
+    for (name, count) in &counts {
+        println!("{} {}", name, count);
+    }
+    println!("total {}", counts.values().sum::<usize>() + errors);
+    println!("errors {}", errors);
+}
+
+// Prints a table of the rules of `spec` with their line, start conditions, regex after
+// macro expansion, and action and modifiers written out in full, then the rule count
+fn print_rules(spec: &Spec) {
+    let rows: Vec<[String; 4]> = spec
+        .rules
+        .iter()
+        .map(|rule| {
+            let conditions: Vec<&str> = rule.conditions.iter().map(|&condition| spec.conditions[condition].as_str()).collect();
+            [rule.line.to_string(), conditions.join(","), rule.regex.clone(), describe_rule_action(rule, spec)]
+        })
+        .collect();
+    let header = ["line", "states", "regex", "action"].map(String::from);
+
+    let mut widths = [0; 3];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for [line, conditions, regex, action] in std::iter::once(&header).chain(&rows) {
+        println!("{:<w0$}  {:<w1$}  {:<w2$}  {}", line, conditions, regex, action, w0 = widths[0], w1 = widths[1], w2 = widths[2]);
+    }
+    println!("{} rule{}", rows.len(), if rows.len() == 1 { "" } else { "s" });
+}
+
+// A rule's action as it would be written in a spec, followed by its modifiers
+fn describe_rule_action(rule: &Rule, spec: &Spec) -> String {
+    let mut channel = None;
+    let mut text = match &rule.action {
+        Action::Skip(None) => "(SKIP)".to_string(),
+        Action::Skip(Some(name)) => format!("(SKIP keep {})", name),
+        Action::Error(message) => format!("(ERR) \"{}\"", message),
+        Action::Begin(condition) => format!("(BEGIN {})", spec.conditions[*condition]),
+        Action::Push(condition) => format!("(PUSH {})", spec.conditions[*condition]),
+        Action::Pop => "(POP)".to_string(),
+        Action::Token { name, value: Some(value), channel: token_channel, .. } => {
+            channel = token_channel.as_ref();
+            format!("{} \"{}\"", name, value)
+        }
+        Action::Token { name, keep_lexeme, value: None, channel: token_channel } => {
+            channel = token_channel.as_ref();
+            format!("{} {}", name, keep_lexeme)
+        }
+        Action::Tokens { tokens, channel: tokens_channel } => {
+            channel = tokens_channel.as_ref();
+            let entries: Vec<String> = tokens.iter().map(|(name, keep_lexeme)| format!("{} {}", name, keep_lexeme)).collect();
+            entries.join(", ")
+        }
+    };
+    if rule.priority != 0 {
+        text.push_str(&format!(" (PRIO {})", rule.priority));
+    }
+    for (set, modifier) in [(rule.case_insensitive, "(CI)"), (rule.dotall, "(DOTALL)"), (rule.shortest, "(SHORTEST)")] {
+        if set {
+            text.push(' ');
+            text.push_str(modifier);
+        }
+    }
+    if let Some(channel) = channel {
+        text.push_str(&format!(" (CHANNEL {})", channel));
+    }
+    text
+}
+
+// Reads a DFA saved with `--save-dfa`, checking it was built for a spec with as many rules
+fn load_cached_dfa(dfa_file: &str, spec: &Spec) -> DFA {
+    let loaded = fs::read(dfa_file)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| DFA::from_bytes(&bytes));
+    match loaded {
+        Ok(dfa) if dfa.priorities.len() == spec.rules.len() => dfa,
+        Ok(dfa) => {
+            eprintln!(
+                "Error loading DFA '{}': built for {} rules, but the spec has {}",
+                dfa_file,
+                dfa.priorities.len(),
+                spec.rules.len()
+            );
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Error loading DFA '{}': {}", dfa_file, err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repl_reports_matches() {
+        // Only a match at the start of the line counts, an empty line asks for a new regex,
+        // and a bad regex is asked for again
+        let script = "a+b\naab\nb\nxab\n\n[a\nc\nc\n";
+        let mut output = Vec::new();
+        repl(script.as_bytes(), &mut output).unwrap();
+        let expected = "regex> input> matches 3 characters: 'aab'\n\
+            input> no match\n\
+            input> no match\n\
+            input> regex> Error parsing regex '[a': Missing closing bracket at position 0\n    [a\n    ^\n\
+            regex> input> matches 1 character: 'c'\n\
+            input> \n";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
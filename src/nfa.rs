@@ -1,21 +1,49 @@
 use std::collections::{HashMap, HashSet};
-use crate::regex_parser::RegexNode;
+use std::ops::RangeInclusive;
+use crate::dfa::{dot_escape, runs_label};
+use crate::regex_parser::{in_ranges, left_operands, merge_ranges, subtract_ranges, RegexNode, TrailingSplit};
+use crate::spec_parser::{Spec, Unmatched};
+use crate::token::{apply_actions, best_rule, drop_unmatched, scan, without_context, Match, RuleTables, Token};
 
+/// Identifies a state within one NFA
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct StateId(pub usize);
 
+/// Label on an NFA edge
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Transition {
     Char(char),
+    Range(char, char), // Any character from the first to the second, inclusive
     Epsilon,
+    LineStart, // Zero-width, only taken when the match begins at column 1
+    LineEnd,   // Zero-width, only taken when followed by a newline or end of input
+    EndOfInput, // Zero-width, only taken at the end of input
 }
 
-#[derive(Debug, Clone)]
+/// Per-rule settings that change what a regex matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuildOptions {
+    pub case_insensitive: bool, // ASCII letters match either case
+    pub dotall: bool, // '.' and negated classes also match newline
+    pub shortest: bool, // Matching stops as soon as the rule accepts, rather than at the longest match
+    pub bytes: bool, // Each character stands for the byte of the same value, so '.' and negated classes cover all 256
+}
+
+/// Thompson-style NFA built from a single rule's regex
+#[derive(Debug, Clone, PartialEq)]
 pub struct NFA {
     pub states: HashSet<StateId>,
     pub start_state: StateId,
     pub accept_states: HashSet<StateId>,
+    pub accept_rules: HashMap<StateId, usize>, // Rule index each accept state belongs to
     pub transitions: HashMap<(StateId, Transition), HashSet<StateId>>,
+    ranges: HashMap<StateId, Vec<(char, char)>>, // Range labels leaving each state, for move_on_char
+    condition_starts: Vec<StateId>, // Start state per start condition, for combined NFAs
+    priorities: Vec<i32>, // Rule priorities, for combined NFAs
+    trailing: Vec<Option<TrailingSplit>>, // Trailing context of each rule, a single NFA's being rule 0
+    shortest: Vec<bool>, // Rules that take their shortest match, likewise
+    options: BuildOptions, // How the regex being built is interpreted
+    source: Option<(String, BuildOptions)>, // What a single-rule NFA was built from, so rules built alike can share one
     next_state_id: usize,
 }
 
@@ -25,7 +53,15 @@ impl NFA {
             states: HashSet::new(),
             start_state: StateId(0),
             accept_states: HashSet::new(),
+            accept_rules: HashMap::new(),
             transitions: HashMap::new(),
+            ranges: HashMap::new(),
+            condition_starts: Vec::new(),
+            priorities: Vec::new(),
+            trailing: Vec::new(),
+            shortest: Vec::new(),
+            options: BuildOptions::default(),
+            source: None,
             next_state_id: 0,
         }
     }
@@ -38,58 +74,319 @@ impl NFA {
     }
 
     fn add_transition(&mut self, from: StateId, transition: Transition, to: StateId) {
+        if let Transition::Range(low, high) = transition {
+            let ranges = self.ranges.entry(from.clone()).or_default();
+            if !ranges.contains(&(low, high)) {
+                ranges.push((low, high));
+            }
+        }
         self.transitions
             .entry((from, transition))
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(to);
     }
 
+    /// Builds an NFA with a single start and accept state that recognizes `regex`
     pub fn from_regex(regex: &RegexNode) -> Self {
+        Self::from_regex_with(regex, BuildOptions::default())
+    }
+
+    /// Like `from_regex`, interpreting the regex according to `options`
+    pub fn from_regex_with(regex: &RegexNode, options: BuildOptions) -> Self {
         let mut nfa = NFA::new();
+        nfa.options = options;
         let start = nfa.new_state();
         let accept = nfa.new_state();
 
         nfa.start_state = start.clone();
         nfa.accept_states.insert(accept.clone());
+        nfa.accept_rules.insert(accept.clone(), 0);
+        nfa.trailing = vec![regex.trailing_split()];
+        nfa.shortest = vec![options.shortest || regex.has_lazy()];
 
         nfa.build_nfa(regex, start, accept);
+        // Kept printed, which is as good as the node for telling regexes apart, since
+        // cloning and comparing nodes recurses down the whole of a long literal
+        nfa.source = Some((regex.to_string(), options));
         nfa
     }
 
+    // The regex and options a single-rule NFA was built from
+    pub(crate) fn source(&self) -> Option<&(String, BuildOptions)> {
+        self.source.as_ref()
+    }
+
+    /// Whether the regex matches the empty string, with its start state reaching an
+    /// accept state by ε-transitions alone
+    pub fn matches_empty(&self) -> bool {
+        let start = HashSet::from([self.start_state.clone()]);
+        self.epsilon_closure(&start).iter().any(|state| self.accept_states.contains(state))
+    }
+
+    /// Joins one NFA per rule under a fresh start state per start condition, so all
+    /// rules can be simulated at once. Each NFA is paired with its rule index, which its
+    /// accept states take, and `conditions` and `priorities` are as for `DFA::from_nfas`.
+    pub fn combine(nfas: &[(NFA, usize)], conditions: &[Vec<usize>], priorities: &[i32]) -> Self {
+        let mut combined = NFA::new();
+        combined.priorities = priorities.to_vec();
+        combined.condition_starts = conditions.iter().map(|_| combined.new_state()).collect();
+        combined.start_state = combined.condition_starts[0].clone();
+
+        combined.trailing = trailing_splits(nfas);
+        combined.shortest = shortest_rules(nfas);
+
+        for (nfa_index, (nfa, rule_index)) in nfas.iter().enumerate() {
+            let offset = combined.next_state_id;
+            let shift = |state: &StateId| StateId(state.0 + offset);
+            combined.next_state_id += nfa.next_state_id;
+
+            combined.states.extend(nfa.states.iter().map(shift));
+            for ((from, transition), targets) in &nfa.transitions {
+                for to in targets {
+                    combined.add_transition(shift(from), transition.clone(), shift(to));
+                }
+            }
+            for accept in &nfa.accept_states {
+                combined.accept_states.insert(shift(accept));
+                combined.accept_rules.insert(shift(accept), *rule_index);
+            }
+
+            for (condition, active) in conditions.iter().enumerate() {
+                if active.contains(&nfa_index) {
+                    let start = combined.condition_starts[condition].clone();
+                    combined.add_transition(start, Transition::Epsilon, shift(&nfa.start_state));
+                }
+            }
+        }
+
+        combined
+    }
+
+    /// Renders the NFA in GraphViz dot format, epsilon edges included. Character and
+    /// range edges between the same two states are merged into one labelled edge, and
+    /// the accept states of a combined NFA are labelled with their rule.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        dot.push_str("digraph NFA {\n");
+        dot.push_str("    rankdir=LR;\n");
+
+        let starts = match self.condition_starts.is_empty() {
+            true => std::slice::from_ref(&self.start_state),
+            false => &self.condition_starts[..],
+        };
+        for (condition, start_state) in starts.iter().enumerate() {
+            // Extra start conditions are told apart by their index, as in `DFA::to_dot`
+            let suffix = if condition == 0 { String::new() } else { format!("_{}", condition) };
+            dot.push_str(&format!("    start{} [shape=point];\n", suffix));
+            dot.push_str(&format!("    start{} -> {}", suffix, start_state.0));
+            dot.push_str(&if condition == 0 { ";\n".to_string() } else { format!(" [label=\"<{}>\"];\n", condition) });
+        }
+
+        let mut state_ids: Vec<&StateId> = self.states.iter().collect();
+        state_ids.sort_by_key(|id| id.0);
+        for state_id in state_ids {
+            let mut label = state_id.0.to_string();
+            let shape = if self.accept_states.contains(state_id) {
+                if !self.condition_starts.is_empty()
+                    && let Some(rule_index) = self.accept_rules.get(state_id)
+                {
+                    label.push_str(&format!("\\nrule {}", rule_index));
+                }
+                "doublecircle"
+            } else {
+                "circle"
+            };
+            dot.push_str(&format!("    {} [shape={}, label=\"{}\"];\n", state_id.0, shape, label));
+        }
+
+        // Character edges are grouped per state pair; the zero-width ones stay separate
+        let mut runs: HashMap<(usize, usize), Vec<(char, char)>> = HashMap::new();
+        let mut edges: Vec<(usize, usize, String)> = Vec::new();
+        for ((from_state, transition), targets) in &self.transitions {
+            for to_state in targets {
+                let label = match transition {
+                    Transition::Char(ch) => {
+                        runs.entry((from_state.0, to_state.0)).or_default().push((*ch, *ch));
+                        continue;
+                    }
+                    Transition::Range(low, high) => {
+                        runs.entry((from_state.0, to_state.0)).or_default().push((*low, *high));
+                        continue;
+                    }
+                    Transition::Epsilon => "ε".to_string(),
+                    Transition::LineStart => "^".to_string(),
+                    Transition::LineEnd => "$".to_string(),
+                    Transition::EndOfInput => "\\z".to_string(),
+                };
+                edges.push((from_state.0, to_state.0, label));
+            }
+        }
+        for ((from_state, to_state), mut pair_runs) in runs {
+            edges.push((from_state, to_state, runs_label(&merge_runs(&mut pair_runs))));
+        }
+        edges.sort();
+        for (from_state, to_state, label) in edges {
+            dot.push_str(&format!("    {} -> {} [label=\"{}\"];\n", from_state, to_state, dot_escape(&label)));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Tokenizes `input` by running the NFA's active state sets directly, without
+    /// building a DFA. Matches `DFA::simulate`: longest match, ties to the lowest rule.
+    pub fn simulate(&self, input: &str) -> Vec<Match> {
+        drop_unmatched(scan(input, 1, RuleTables::default(), None, |condition, rest, at_line_start| {
+            self.longest_match(condition, rest, at_line_start)
+        }))
+        .collect()
+    }
+
+    /// Like `DFA::tokenize`, applying each rule's action from `spec`
+    pub fn tokenize(&self, spec: &Spec, input: &str) -> Vec<Token> {
+        let closures: Vec<HashSet<StateId>> = (0..self.condition_starts.len().max(1))
+            .map(|condition| self.start_closure(condition, true))
+            .collect();
+        let can_start = |condition: usize, ch| !self.move_on_char(&closures[condition], ch).is_empty();
+        let recover: Option<Box<dyn Fn(usize, char) -> bool>> =
+            (spec.unmatched == Unmatched::Recover).then(|| Box::new(can_start) as Box<dyn Fn(usize, char) -> bool>);
+        apply_actions(spec, 1, false, scan(input, 1, RuleTables::new(spec), recover, |condition, rest, at_line_start| {
+            self.longest_match(condition, rest, at_line_start)
+        }))
+        .collect()
+    }
+
+    // The states a match in `condition` begins in
+    fn start_closure(&self, condition: usize, at_line_start: bool) -> HashSet<StateId> {
+        let mut start_set = HashSet::new();
+        start_set.insert(self.condition_starts.get(condition).unwrap_or(&self.start_state).clone());
+        let mut closure = self.epsilon_closure(&start_set);
+        if at_line_start {
+            let past_anchor = self.move_on(&closure, Transition::LineStart);
+            closure.extend(self.epsilon_closure(&past_anchor));
+        }
+        closure
+    }
+
+    // The byte length of the longest non-empty match at the start of `input` and its rule
+    fn longest_match(&self, condition: usize, input: &str, at_line_start: bool) -> (usize, Option<usize>) {
+        let mut current = self.start_closure(condition, at_line_start);
+
+        // An empty match is never a token, as in `DFA::longest_match`
+        let mut last_accepting_pos = 0;
+        let mut last_accepting_rule = None;
+
+        for (pos, ch) in input.char_indices() {
+            let moved = self.move_on_char(&current, ch);
+            if moved.is_empty() {
+                break;
+            }
+            current = self.epsilon_closure(&moved);
+
+            let end = pos + ch.len_utf8();
+            if let Some(rule_index) = self.accepting_rule(&current, input[end..].chars().next()) {
+                last_accepting_pos = end;
+                last_accepting_rule = Some(rule_index);
+                if self.shortest.get(rule_index) == Some(&true) {
+                    break;
+                }
+            }
+        }
+
+        (without_context(input, last_accepting_pos, last_accepting_rule, &self.trailing), last_accepting_rule)
+    }
+
+    // The best rule accepting in `states`, given the character that follows the match
+    fn accepting_rule(&self, states: &HashSet<StateId>, next: Option<char>) -> Option<usize> {
+        let mut accepting: Vec<StateId> = states.iter().cloned().collect();
+        if matches!(next, None | Some('\n')) {
+            let past_anchor = self.move_on(states, Transition::LineEnd);
+            accepting.extend(self.epsilon_closure(&past_anchor));
+        }
+        if next.is_none() {
+            let past_anchor = self.move_on(states, Transition::EndOfInput);
+            accepting.extend(self.epsilon_closure(&past_anchor));
+        }
+
+        let rules = accepting.iter().filter_map(|state| self.accept_rules.get(state).copied());
+        best_rule(rules, &self.priorities)
+    }
+
     fn build_nfa(&mut self, regex: &RegexNode, start: StateId, accept: StateId) {
         match regex {
             RegexNode::Char(ch) => {
-                self.add_transition(start, Transition::Char(*ch), accept);
+                for ch in self.case_variants(*ch) {
+                    self.add_transition(start.clone(), Transition::Char(ch), accept.clone());
+                }
+            }
+            RegexNode::Dot if self.options.bytes => {
+                // Any byte but newline, unless dotall
+                self.add_transition(start.clone(), Transition::Range('\0', '\t'), accept.clone());
+                self.add_transition(start.clone(), Transition::Range('\u{b}', '\u{ff}'), accept.clone());
+                if self.options.dotall {
+                    self.add_transition(start, Transition::Char('\n'), accept);
+                }
             }
             RegexNode::Dot => {
-                // Match any character except newline
-                for ch in (32..127u8).map(|b| b as char) {
-                    if ch != '\n' {
-                        self.add_transition(start.clone(), Transition::Char(ch), accept.clone());
-                    }
+                // Any character but newline, unless dotall
+                self.add_transition(start.clone(), Transition::Range('\0', '\t'), accept.clone());
+                self.add_transition(start.clone(), Transition::Range('\u{b}', char::MAX), accept.clone());
+                if self.options.dotall {
+                    self.add_transition(start, Transition::Char('\n'), accept);
+                }
+            }
+            RegexNode::Empty => {
+                self.add_transition(start, Transition::Epsilon, accept);
+            }
+            RegexNode::LineStart => {
+                self.add_transition(start, Transition::LineStart, accept);
+            }
+            RegexNode::LineEnd => {
+                self.add_transition(start, Transition::LineEnd, accept);
+            }
+            RegexNode::EndOfInput => {
+                self.add_transition(start, Transition::EndOfInput, accept);
+            }
+            RegexNode::Concatenation(..) => {
+                // Walk the chain rather than recursing down it, since a long literal nests
+                // thousands deep. The states between the parts are made outermost first.
+                let parts = left_operands(regex, |node| match node {
+                    RegexNode::Concatenation(left, right) => Some((left, right)),
+                    _ => None,
+                });
+                let mut middles: Vec<StateId> = (1..parts.len()).map(|_| self.new_state()).collect();
+                middles.reverse();
+                let mut from = start;
+                for (part, to) in parts.iter().zip(middles.into_iter().chain([accept])) {
+                    self.build_nfa(part, from, to.clone());
+                    from = to;
                 }
             }
-            RegexNode::Concatenation(left, right) => {
+            RegexNode::TrailingContext(head, tail) => {
+                // Both sides are matched; only the token's length leaves out the context
                 let middle = self.new_state();
-                self.build_nfa(left, start, middle.clone());
-                self.build_nfa(right, middle, accept);
+                self.build_nfa(head, start, middle.clone());
+                self.build_nfa(tail, middle, accept);
             }
-            RegexNode::Alternation(left, right) => {
-                self.build_nfa(left, start.clone(), accept.clone());
-                self.build_nfa(right, start, accept);
+            RegexNode::Alternation(..) => {
+                let branches = left_operands(regex, |node| match node {
+                    RegexNode::Alternation(left, right) => Some((left, right)),
+                    _ => None,
+                });
+                // A list of plain keywords shares its prefixes, so `if|in|int` is one trie
+                // rather than a copy of the chain for each word
+                let words: Option<Vec<Vec<char>>> = branches.iter().map(|branch| literal_chars(branch)).collect();
+                match words {
+                    Some(words) if words.len() > 1 => self.build_trie(&words, start, accept),
+                    _ => {
+                        for branch in branches {
+                            self.build_nfa(branch, start.clone(), accept.clone());
+                        }
+                    }
+                }
             }
             RegexNode::Kleene(inner) => {
-                // ε-transition from start to accept (zero matches)
-                self.add_transition(start.clone(), Transition::Epsilon, accept.clone());
-
-                // Create loop for one or more matches
-                let loop_start = self.new_state();
-                let loop_end = self.new_state();
-
-                self.add_transition(start, Transition::Epsilon, loop_start.clone());
-                self.build_nfa(inner, loop_start.clone(), loop_end.clone());
-                self.add_transition(loop_end.clone(), Transition::Epsilon, accept);
-                self.add_transition(loop_end, Transition::Epsilon, loop_start);
+                self.build_kleene(inner, start, accept);
             }
             RegexNode::Plus(inner) => {
                 // One or more: equivalent to inner followed by inner*
@@ -106,24 +403,166 @@ impl NFA {
                 self.add_transition(loop_end.clone(), Transition::Epsilon, accept);
                 self.add_transition(loop_end, Transition::Epsilon, loop_start);
             }
+            RegexNode::Lazy(inner) => {
+                // Laziness only changes where the rule stops, through `shortest`
+                self.build_nfa(inner, start, accept);
+            }
+            RegexNode::Capture(_, inner) => {
+                // Where the group matched is worked out again after the match
+                self.build_nfa(inner, start, accept);
+            }
+            RegexNode::FoldCase(class) => {
+                // Just this class matches either case, as the whole rule does under (CI)
+                let options = self.options;
+                self.options.case_insensitive = true;
+                self.build_nfa(class, start, accept);
+                self.options = options;
+            }
             RegexNode::Optional(inner) => {
                 // Zero or one: ε-transition to accept (zero) or through inner (one)
                 self.add_transition(start.clone(), Transition::Epsilon, accept.clone());
                 self.build_nfa(inner, start, accept);
             }
+            RegexNode::Repeat { node, min, max } => {
+                // Required copies chained one after another
+                let mut current = start;
+                for _ in 0..*min {
+                    let next = self.new_state();
+                    self.build_nfa(node, current, next.clone());
+                    current = next;
+                }
+
+                match max {
+                    Some(max) => {
+                        // Optional copies, each of which may exit early to accept
+                        for _ in *min..*max {
+                            let next = self.new_state();
+                            self.add_transition(current.clone(), Transition::Epsilon, accept.clone());
+                            self.build_nfa(node, current, next.clone());
+                            current = next;
+                        }
+                        self.add_transition(current, Transition::Epsilon, accept);
+                    }
+                    None => {
+                        // Unbounded: finish with a Kleene tail
+                        self.build_kleene(node, current, accept);
+                    }
+                }
+            }
             RegexNode::CharClass(chars) => {
-                for &ch in chars {
-                    self.add_transition(start.clone(), Transition::Char(ch), accept.clone());
+                // Consecutive members share one edge, so a wide class like [一-龥] stays small
+                let members: Vec<(char, char)> = chars.iter().flat_map(|&ch| self.case_variants(ch)).map(|ch| (ch, ch)).collect();
+                for (low, high) in merge_ranges(members) {
+                    let transition = if low == high { Transition::Char(low) } else { Transition::Range(low, high) };
+                    self.add_transition(start.clone(), transition, accept.clone());
                 }
             }
-            RegexNode::NegatedCharClass(chars) => {
-                let excluded: HashSet<char> = chars.iter().cloned().collect();
-                for ch in (32..127u8).map(|b| b as char) {
-                    if !excluded.contains(&ch) && ch != '\n' {
+            RegexNode::Ranges(ranges) => {
+                for &(low, high) in ranges {
+                    self.add_transition(start.clone(), Transition::Range(low, high), accept.clone());
+                }
+                // Folding only concerns ASCII letters, which are single characters here
+                if self.options.case_insensitive {
+                    let folded = ranges
+                        .iter()
+                        .flat_map(|&(low, high)| (low..=high.min('z')).filter(char::is_ascii_alphabetic))
+                        .flat_map(|ch| self.case_variants(ch))
+                        .filter(|&ch| !ranges.iter().any(|&(low, high)| (low..=high).contains(&ch)));
+                    for ch in folded.collect::<Vec<char>>() {
                         self.add_transition(start.clone(), Transition::Char(ch), accept.clone());
                     }
                 }
             }
+            RegexNode::NegatedRanges(ranges) => {
+                let mut excluded = ranges.clone();
+                let letters = ('A'..='Z').chain('a'..='z').filter(|&ch| in_ranges(ranges, ch));
+                excluded.extend(letters.flat_map(|ch| self.case_variants(ch)).map(|ch| (ch, ch)));
+                if !self.options.dotall {
+                    excluded.push(('\n', '\n'));
+                }
+                let all = if self.options.bytes { ('\0', '\u{ff}') } else { ('\0', char::MAX) };
+                for (low, high) in subtract_ranges(&[all], &merge_ranges(excluded)) {
+                    self.add_transition(start.clone(), Transition::Range(low, high), accept.clone());
+                }
+            }
+            RegexNode::NegatedCharClass(chars) => {
+                let mut excluded: Vec<char> = chars.iter().flat_map(|&ch| self.case_variants(ch)).collect();
+                if !self.options.dotall {
+                    excluded.push('\n');
+                }
+
+                // One edge per run of characters left after the exclusions
+                let all = if self.options.bytes { '\0'..='\u{ff}' } else { '\0'..=char::MAX };
+                for (low, high) in runs_excluding(all, &excluded) {
+                    self.add_transition(start.clone(), Transition::Range(low, high), accept.clone());
+                }
+            }
+        }
+    }
+
+    // Zero or more matches of `inner` between `start` and `accept`
+    fn build_kleene(&mut self, inner: &RegexNode, start: StateId, accept: StateId) {
+        // ε-transition from start to accept (zero matches)
+        self.add_transition(start.clone(), Transition::Epsilon, accept.clone());
+
+        // Create loop for one or more matches
+        let loop_start = self.new_state();
+        let loop_end = self.new_state();
+
+        self.add_transition(start, Transition::Epsilon, loop_start.clone());
+        self.build_nfa(inner, loop_start.clone(), loop_end.clone());
+        self.add_transition(loop_end.clone(), Transition::Epsilon, accept);
+        self.add_transition(loop_end, Transition::Epsilon, loop_start);
+    }
+
+    // Builds `words`, an alternation of literal strings, as a trie between `start` and
+    // `accept`. The words share one state per distinct prefix. A word that isn't the prefix of
+    // another steps straight to `accept`; one that is leaves early by an ε-transition.
+    // Prefixes are compared folded when case doesn't matter.
+    fn build_trie(&mut self, words: &[Vec<char>], start: StateId, accept: StateId) {
+        // Each node is its children, by character, and whether a word ends there
+        let mut nodes: Vec<(Vec<(char, usize)>, bool)> = vec![(Vec::new(), false)];
+        for word in words {
+            let mut node = 0;
+            for &ch in word {
+                let key = if self.options.case_insensitive { ch.to_ascii_lowercase() } else { ch };
+                node = match nodes[node].0.iter().find(|(other, _)| *other == key) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push((Vec::new(), false));
+                        let child = nodes.len() - 1;
+                        nodes[node].0.push((key, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].1 = true;
+        }
+
+        let mut pending = vec![(0, start)];
+        while let Some((node, state)) = pending.pop() {
+            let (children, ends) = &nodes[node];
+            if *ends {
+                self.add_transition(state.clone(), Transition::Epsilon, accept.clone());
+            }
+            for &(ch, child) in children {
+                let to = if nodes[child].0.is_empty() { accept.clone() } else { self.new_state() };
+                for ch in self.case_variants(ch) {
+                    self.add_transition(state.clone(), Transition::Char(ch), to.clone());
+                }
+                if to != accept {
+                    pending.push((child, to));
+                }
+            }
+        }
+    }
+
+    // The characters `ch` stands for: both cases of an ASCII letter when folding
+    fn case_variants(&self, ch: char) -> Vec<char> {
+        if self.options.case_insensitive && ch.is_ascii_alphabetic() {
+            vec![ch.to_ascii_lowercase(), ch.to_ascii_uppercase()]
+        } else {
+            vec![ch]
         }
     }
 
@@ -146,10 +585,99 @@ impl NFA {
     }
 
     pub fn move_on_char(&self, states: &HashSet<StateId>, ch: char) -> HashSet<StateId> {
+        let mut result = self.move_on(states, Transition::Char(ch));
+
+        for state in states {
+            for &(low, high) in self.ranges.get(state).into_iter().flatten() {
+                if (low..=high).contains(&ch)
+                    && let Some(targets) = self.transitions.get(&(state.clone(), Transition::Range(low, high)))
+                {
+                    result.extend(targets.iter().cloned());
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Whether the regex matches all of `input`, rather than the longest prefix a lexer
+    /// would take. `^`, `$` and `\z` hold where they would in `input`: at its ends or
+    /// next to a newline. Trailing context is matched like the rest of the regex.
+    pub fn accepts(&self, input: &str) -> bool {
+        let mut states = HashSet::from([self.start_state.clone()]);
+        let mut previous = None;
+        let mut chars = input.chars().peekable();
+        loop {
+            let next = chars.peek().copied();
+            let mut anchors = Vec::new();
+            if matches!(previous, None | Some('\n')) {
+                anchors.push(Transition::LineStart);
+            }
+            if matches!(next, None | Some('\n')) {
+                anchors.push(Transition::LineEnd);
+            }
+            if next.is_none() {
+                anchors.push(Transition::EndOfInput);
+            }
+            states = self.closure_past(states, &anchors);
+
+            let Some(ch) = chars.next() else {
+                return states.iter().any(|state| self.accept_states.contains(state));
+            };
+            states = self.move_on_char(&states, ch);
+            if states.is_empty() {
+                return false;
+            }
+            previous = Some(ch);
+        }
+    }
+
+    // The ε-closure of `states`, also following any of `anchors`, which hold here
+    fn closure_past(&self, states: HashSet<StateId>, anchors: &[Transition]) -> HashSet<StateId> {
+        let mut states = self.epsilon_closure(&states);
+        loop {
+            let mut anchored = HashSet::new();
+            for anchor in anchors {
+                anchored.extend(self.move_on(&states, anchor.clone()));
+            }
+            anchored.retain(|state| !states.contains(state));
+            if anchored.is_empty() {
+                return states;
+            }
+            states.extend(self.epsilon_closure(&anchored));
+        }
+    }
+
+    // The byte offsets at which a match of the NFA starting at the beginning of `input` can
+    // end. Anchors count as passed, since they held for the match being picked apart.
+    pub(crate) fn match_ends(&self, input: &str) -> Vec<usize> {
+        let closure = |states: HashSet<StateId>| {
+            self.closure_past(states, &[Transition::LineStart, Transition::LineEnd, Transition::EndOfInput])
+        };
+        let accepts = |states: &HashSet<StateId>| states.iter().any(|state| self.accept_states.contains(state));
+
+        let mut states = closure(HashSet::from([self.start_state.clone()]));
+        let mut ends = Vec::new();
+        if accepts(&states) {
+            ends.push(0);
+        }
+        for (index, ch) in input.char_indices() {
+            states = closure(self.move_on_char(&states, ch));
+            if states.is_empty() {
+                break;
+            }
+            if accepts(&states) {
+                ends.push(index + ch.len_utf8());
+            }
+        }
+        ends
+    }
+
+    pub fn move_on(&self, states: &HashSet<StateId>, transition: Transition) -> HashSet<StateId> {
         let mut result = HashSet::new();
 
         for state in states {
-            if let Some(targets) = self.transitions.get(&(state.clone(), Transition::Char(ch))) {
+            if let Some(targets) = self.transitions.get(&(state.clone(), transition.clone())) {
                 result.extend(targets.iter().cloned());
             }
         }
@@ -157,3 +685,184 @@ impl NFA {
         result
     }
 }
+
+// The characters of a regex that is one literal string, such as a keyword, or `None`
+// for any other regex
+fn literal_chars(regex: &RegexNode) -> Option<Vec<char>> {
+    let parts = left_operands(regex, |node| match node {
+        RegexNode::Concatenation(left, right) => Some((left, right)),
+        _ => None,
+    });
+    parts
+        .into_iter()
+        .map(|part| match part {
+            RegexNode::Char(ch) => Some(*ch),
+            _ => None,
+        })
+        .collect()
+}
+
+// The trailing context of each rule, from the single-rule NFAs paired with their rule
+// indices, for `combine` and `DFA::from_nfas`
+pub(crate) fn trailing_splits(nfas: &[(NFA, usize)]) -> Vec<Option<TrailingSplit>> {
+    let mut splits = vec![None; nfas.iter().map(|(_, rule_index)| rule_index + 1).max().unwrap_or(0)];
+    for (nfa, rule_index) in nfas {
+        splits[*rule_index] = nfa.trailing.first().copied().flatten();
+    }
+    splits
+}
+
+// Which rules take their shortest match, from the single-rule NFAs paired with their rule
+// indices, for `combine` and `DFA::from_nfas`
+pub(crate) fn shortest_rules(nfas: &[(NFA, usize)]) -> Vec<bool> {
+    let mut shortest = vec![false; nfas.iter().map(|(_, rule_index)| rule_index + 1).max().unwrap_or(0)];
+    for (nfa, rule_index) in nfas {
+        shortest[*rule_index] = nfa.shortest.first().copied().unwrap_or(false);
+    }
+    shortest
+}
+
+// Sorts inclusive runs and joins the ones that overlap or touch
+fn merge_runs(runs: &mut [(char, char)]) -> Vec<(char, char)> {
+    runs.sort();
+    let mut merged: Vec<(char, char)> = Vec::new();
+    for &(low, high) in runs.iter() {
+        match merged.last_mut() {
+            Some((_, last_high)) if low as u32 <= *last_high as u32 + 1 => *last_high = (*last_high).max(high),
+            _ => merged.push((low, high)),
+        }
+    }
+    merged
+}
+
+// Splits `chars` minus `excluded` into maximal inclusive runs
+fn runs_excluding(chars: RangeInclusive<char>, excluded: &[char]) -> Vec<(char, char)> {
+    let excluded = merge_ranges(excluded.iter().map(|&ch| (ch, ch)).collect());
+    subtract_ranges(&[(*chars.start(), *chars.end())], &excluded)
+}
+
+impl Default for NFA {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex_parser::parse_regex;
+
+    fn nfa(regex: &str, options: BuildOptions) -> NFA {
+        NFA::from_regex_with(&parse_regex(regex).unwrap(), options)
+    }
+
+    #[test]
+    fn kleene_star_dot_loops_back() {
+        let dot = NFA::from_regex(&parse_regex("a*").unwrap()).to_dot();
+        let edges: Vec<&str> = dot.lines().map(str::trim).filter(|line| line.contains(" -> ") && !line.starts_with("start")).collect();
+        // Skip straight to the accept state, or go round the `a` as often as needed
+        assert_eq!(edges, [
+            "0 -> 1 [label=\"ε\"];",
+            "0 -> 2 [label=\"ε\"];",
+            "2 -> 3 [label=\"a\"];",
+            "3 -> 1 [label=\"ε\"];",
+            "3 -> 2 [label=\"ε\"];",
+        ]);
+        assert!(dot.contains("start -> 0;\n") && dot.contains("1 [shape=doublecircle"));
+    }
+
+    #[test]
+    fn long_literal_builds_and_matches() {
+        // A 5000-character literal nests 5000 concatenations deep
+        let literal: String = "abcdefghij".repeat(500);
+        let dfa = crate::compile_spec(&format!("{} LONG true\n[a-j] CHAR true\n", literal)).unwrap();
+        let matches: Vec<(usize, Option<usize>)> = dfa.simulate(&format!("{}a", literal)).into_iter().map(|(lexeme, .., rule)| (lexeme.len(), rule)).collect();
+        assert_eq!(matches, [(5000, Some(0)), (1, Some(1)), (0, None)]);
+    }
+
+    #[test]
+    fn fold_flag_applies_to_its_class() {
+        let folded = nfa("[\\ia-c]x", BuildOptions::default());
+        assert!(folded.accepts("ax"));
+        assert!(folded.accepts("Ax"));
+        assert!(folded.accepts("Cx"));
+        assert!(!folded.accepts("aX"));
+        assert!(!folded.accepts("Dx"));
+    }
+
+    #[test]
+    fn keyword_list_is_a_trie() {
+        let keywords = [
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn",
+            "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+            "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where", "while", "abstract", "become",
+            "box", "do", "final", "macro", "override", "priv", "typeof", "unsized", "virtual", "yield",
+        ];
+        assert_eq!(keywords.len(), 50);
+        let trie = nfa(&keywords.join("|"), BuildOptions::default());
+        // A bracketed last letter isn't a plain character, so this one is built branch by branch
+        let bracketed: Vec<String> = keywords.iter().map(|word| format!("{}[{}]", &word[..word.len() - 1], &word[word.len() - 1..])).collect();
+        let naive = nfa(&bracketed.join("|"), BuildOptions::default());
+        assert!(trie.states.len() < naive.states.len(), "{} states against {}", trie.states.len(), naive.states.len());
+
+        for word in keywords {
+            assert!(trie.accepts(word), "{}", word);
+            for end in 1..word.len() {
+                assert_eq!(trie.accepts(&word[..end]), naive.accepts(&word[..end]), "{}", &word[..end]);
+            }
+            assert!(!trie.accepts(&format!("{}x", word)));
+        }
+
+        // All of them are one rule, so the lexer gives every keyword the same token
+        let dfa = crate::compile_spec(&format!("{} KW true\n[a-zA-Z]+ ID true\n", keywords.join("|"))).unwrap();
+        let rules: Vec<Option<usize>> = dfa.simulate("while x match").into_iter().map(|(.., rule)| rule).collect();
+        assert_eq!(rules, [Some(0), Some(1), Some(0), None]);
+    }
+
+    #[test]
+    fn negated_class_folds_case() {
+        let folded = nfa("[^a]", BuildOptions { case_insensitive: true, ..BuildOptions::default() });
+        assert!(!folded.accepts("a"));
+        assert!(!folded.accepts("A"));
+        assert!(folded.accepts("b"));
+        assert!(folded.accepts("~"));
+        // The complement spans all of Unicode, not just printable ASCII
+        assert!(folded.accepts("é"));
+        assert!(folded.accepts("\t"));
+        assert!(!folded.accepts("\n"));
+
+        let exact = nfa("[^a]", BuildOptions::default());
+        assert!(exact.accepts("A"));
+
+        let dfa = crate::compile_spec("[^a] X true (CI)").unwrap();
+        let matches: Vec<(String, Option<usize>)> = dfa.simulate("aAb").into_iter().map(|(lexeme, _, _, _, rule)| (lexeme, rule)).collect();
+        assert_eq!(matches, [("b".to_string(), Some(0)), (String::new(), None)]);
+    }
+
+    #[test]
+    fn dot_matches_all_but_newline() {
+        let dot = nfa(".", BuildOptions::default());
+        assert!(dot.accepts("é"));
+        assert!(dot.accepts("\t"));
+        assert!(dot.accepts("\u{10FFFF}"));
+        assert!(!dot.accepts("\n"));
+        assert!(nfa(".", BuildOptions { dotall: true, ..BuildOptions::default() }).accepts("\n"));
+    }
+
+    #[test]
+    fn accepts_whole_strings_only() {
+        let star = nfa("a(b|c)*", BuildOptions::default());
+        assert!(star.accepts("a"));
+        assert!(star.accepts("abcbc"));
+        assert!(!star.accepts("abcbca"));
+        assert!(!star.accepts(""));
+        // The lexer takes the same string as one token
+        let dfa = crate::compile_spec("a(b|c)* X true").unwrap();
+        let lexemes: Vec<String> = dfa.simulate("abcbc").into_iter().map(|(lexeme, ..)| lexeme).collect();
+        assert_eq!(lexemes, ["abcbc", ""]);
+
+        let anchored = nfa("^a$", BuildOptions::default());
+        assert!(anchored.accepts("a"));
+        assert!(!anchored.accepts("aa"));
+    }
+}
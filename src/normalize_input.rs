@@ -0,0 +1,6 @@
+/// `input` without a leading byte order mark and with `\r\n` and lone `\r` line endings
+/// made `\n`, which is how the generated lexer reads its input unless run with `--raw`
+pub fn normalize_input(input: &str) -> String {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    input.replace("\r\n", "\n").replace('\r', "\n")
+}
@@ -1,24 +1,572 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Parsed form of a rule's regex
 #[derive(Debug, Clone, PartialEq)]
 pub enum RegexNode {
     Char(char),
     Dot,
+    Empty, // Matches the empty string, e.g. the empty branch of `(ab|)`
     Concatenation(Box<RegexNode>, Box<RegexNode>),
     Alternation(Box<RegexNode>, Box<RegexNode>),
     Kleene(Box<RegexNode>),
     Plus(Box<RegexNode>),
     Optional(Box<RegexNode>),
+    Repeat { node: Box<RegexNode>, min: usize, max: Option<usize> },
+    Lazy(Box<RegexNode>), // A quantifier followed by '?', as in `a*?`, which makes its rule take the shortest match
     CharClass(Vec<char>),
     NegatedCharClass(Vec<char>),
+    Ranges(Vec<(char, char)>), // Any character in the sorted, disjoint inclusive ranges, as from `\p{L}`
+    NegatedRanges(Vec<(char, char)>), // Any character outside the ranges, as from `\P{L}`
+    LineStart, // '^' at the very start of a regex
+    LineEnd,   // '$' at the very end of a regex
+    EndOfInput, // '\z' at the very end of a regex
+    TrailingContext(Box<RegexNode>, Box<RegexNode>), // 'r/s': r, only where s follows; s is not consumed
+    Capture(String, Box<RegexNode>), // '(?<name>r)': r, with where it matched reported on the token
+    FoldCase(Box<RegexNode>), // A class with `\i`, whose ASCII letters match either case when the NFA is built
+}
+
+/// Where the token ends within a match of a trailing context rule `r/s`, known from
+/// whichever side always matches the same number of characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingSplit {
+    Head(usize), // `r` is this many characters long
+    Tail(usize), // `s` is this many characters long
+}
+
+impl TrailingSplit {
+    /// The byte length of the token at the start of `matched`, a match of all of `r/s`
+    pub fn token_length(self, matched: &str) -> usize {
+        match self {
+            TrailingSplit::Head(count) => matched.char_indices().nth(count).map_or(matched.len(), |(index, _)| index),
+            TrailingSplit::Tail(0) => matched.len(),
+            TrailingSplit::Tail(count) => matched.char_indices().rev().nth(count - 1).map_or(0, |(index, _)| index),
+        }
+    }
+}
+
+// Long chains like `a*a*...a*` nest as deeply as they are long, so they are taken apart
+// with a stack rather than the recursive drop that would overflow it
+impl Drop for RegexNode {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut node) = pending.pop() {
+            node.take_children(&mut pending);
+        }
+    }
+}
+
+impl RegexNode {
+    // Moves the children of this node into `pending`, leaving empty nodes behind
+    fn take_children(&mut self, pending: &mut Vec<RegexNode>) {
+        match self {
+            RegexNode::Concatenation(left, right)
+            | RegexNode::Alternation(left, right)
+            | RegexNode::TrailingContext(left, right) => {
+                pending.push(std::mem::replace(left, RegexNode::Empty));
+                pending.push(std::mem::replace(right, RegexNode::Empty));
+            }
+            RegexNode::Kleene(node)
+            | RegexNode::Plus(node)
+            | RegexNode::Optional(node)
+            | RegexNode::Repeat { node, .. }
+            | RegexNode::Lazy(node)
+            | RegexNode::Capture(_, node)
+            | RegexNode::FoldCase(node) => {
+                pending.push(std::mem::replace(node, RegexNode::Empty));
+            }
+            _ => {}
+        }
+    }
+
+    // How deep building the NFA has to recurse into this node. The left operands of a
+    // chain of concatenations or alternations are built in a loop, so they don't count.
+    fn nesting(&self) -> usize {
+        let mut deepest = 0;
+        let mut pending = vec![(self, 0)];
+        while let Some((node, depth)) = pending.pop() {
+            deepest = deepest.max(depth);
+            match node {
+                RegexNode::Concatenation(left, right) | RegexNode::Alternation(left, right) => {
+                    pending.push((left, depth));
+                    pending.push((right, depth + 1));
+                }
+                RegexNode::TrailingContext(left, right) => {
+                    pending.push((left, depth + 1));
+                    pending.push((right, depth + 1));
+                }
+                RegexNode::Kleene(inner)
+                | RegexNode::Plus(inner)
+                | RegexNode::Optional(inner)
+                | RegexNode::Repeat { node: inner, .. }
+                | RegexNode::Lazy(inner)
+                | RegexNode::Capture(_, inner)
+                | RegexNode::FoldCase(inner) => {
+                    pending.push((inner, depth + 1));
+                }
+                _ => {}
+            }
+        }
+        deepest
+    }
+
+    // How many characters and classes the NFA holds copies of once repetitions are
+    // expanded, with a `{n,}` counted as its `n` copies and the loop after them
+    fn expanded_size(&self) -> usize {
+        match self {
+            RegexNode::Concatenation(..) => {
+                let parts = left_operands(self, |node| match node {
+                    RegexNode::Concatenation(left, right) => Some((left, right)),
+                    _ => None,
+                });
+                parts.iter().fold(0, |total, part| total.saturating_add(part.expanded_size()))
+            }
+            RegexNode::Alternation(..) => {
+                let branches = left_operands(self, |node| match node {
+                    RegexNode::Alternation(left, right) => Some((left, right)),
+                    _ => None,
+                });
+                branches.iter().fold(0, |total, branch| total.saturating_add(branch.expanded_size()))
+            }
+            RegexNode::TrailingContext(left, right) => left.expanded_size().saturating_add(right.expanded_size()),
+            RegexNode::Repeat { node, min, max } => node.expanded_size().saturating_mul(max.unwrap_or(min + 1)),
+            RegexNode::Kleene(inner)
+            | RegexNode::Plus(inner)
+            | RegexNode::Optional(inner)
+            | RegexNode::Lazy(inner)
+            | RegexNode::Capture(_, inner)
+            | RegexNode::FoldCase(inner) => inner.expanded_size(),
+            RegexNode::Empty | RegexNode::LineStart | RegexNode::LineEnd | RegexNode::EndOfInput => 0,
+            _ => 1,
+        }
+    }
+
+    /// Whether the regex has a lazy quantifier anywhere, which makes its rule take the
+    /// shortest match as `(SHORTEST)` does
+    pub fn has_lazy(&self) -> bool {
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            match node {
+                RegexNode::Lazy(_) => return true,
+                RegexNode::Concatenation(left, right) | RegexNode::Alternation(left, right) | RegexNode::TrailingContext(left, right) => {
+                    pending.push(left);
+                    pending.push(right);
+                }
+                RegexNode::Kleene(inner)
+                | RegexNode::Plus(inner)
+                | RegexNode::Optional(inner)
+                | RegexNode::Repeat { node: inner, .. }
+                | RegexNode::Capture(_, inner) => {
+                    pending.push(inner);
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// The regex around its capture group, as the parts before and after it and the
+    /// group itself, or `None` if there is no group at the top level. A match of the
+    /// regex is a match of the three in a row.
+    pub fn split_capture(&self) -> Option<(RegexNode, &RegexNode, RegexNode)> {
+        let operands = left_operands(self, |node| match node {
+            RegexNode::Concatenation(left, right) => Some((left, right)),
+            _ => None,
+        });
+        let position = operands.iter().position(|operand| matches!(operand, RegexNode::Capture(..)))?;
+        let join = |operands: &[&RegexNode]| {
+            operands.iter().fold(RegexNode::Empty, |joined, &operand| match joined {
+                RegexNode::Empty => operand.clone(),
+                joined => RegexNode::Concatenation(Box::new(joined), Box::new(operand.clone())),
+            })
+        };
+        let RegexNode::Capture(_, capture) = operands[position] else {
+            unreachable!("found as a capture");
+        };
+        Some((join(&operands[..position]), capture, join(&operands[position + 1..])))
+    }
+
+    /// How a match splits into token and trailing context, for a `r/s` regex
+    pub fn trailing_split(&self) -> Option<TrailingSplit> {
+        let RegexNode::TrailingContext(head, tail) = self else {
+            return None;
+        };
+        match tail.fixed_length() {
+            Some(length) => Some(TrailingSplit::Tail(length)),
+            None => head.fixed_length().map(TrailingSplit::Head),
+        }
+    }
+
+    // The number of characters every match has, if they all have the same
+    fn fixed_length(&self) -> Option<usize> {
+        match self {
+            RegexNode::Char(_)
+            | RegexNode::Dot
+            | RegexNode::CharClass(_)
+            | RegexNode::NegatedCharClass(_)
+            | RegexNode::Ranges(_)
+            | RegexNode::NegatedRanges(_) => Some(1),
+            RegexNode::Empty | RegexNode::LineStart | RegexNode::LineEnd | RegexNode::EndOfInput => Some(0),
+            RegexNode::Concatenation(..) => {
+                // Long literals nest deeply to the left, so walk down that side in a loop
+                let mut length = 0;
+                let mut node = self;
+                while let RegexNode::Concatenation(left, right) = node {
+                    length += right.fixed_length()?;
+                    node = left;
+                }
+                Some(length + node.fixed_length()?)
+            }
+            RegexNode::Alternation(left, right) => {
+                let length = left.fixed_length()?;
+                (right.fixed_length()? == length).then_some(length)
+            }
+            RegexNode::Kleene(inner) | RegexNode::Plus(inner) | RegexNode::Optional(inner) => {
+                (inner.fixed_length()? == 0).then_some(0)
+            }
+            RegexNode::Repeat { node, min, max } => {
+                let length = node.fixed_length()?;
+                (length == 0 || *max == Some(*min)).then_some(length * min)
+            }
+            RegexNode::Lazy(inner) | RegexNode::Capture(_, inner) | RegexNode::FoldCase(inner) => inner.fixed_length(),
+            RegexNode::TrailingContext(head, tail) => Some(head.fixed_length()? + tail.fixed_length()?),
+        }
+    }
+}
+
+/// Renders the node back into regex syntax that `parse_regex` turns into an equal node,
+/// with parentheses only where precedence needs them and runs of characters in a class
+/// written as ranges. Classes with Unicode ranges come out as `\p{..}` where they can.
+impl fmt::Display for RegexNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_regex(f, 3)
+    }
 }
 
-pub fn parse_regex(regex: &str) -> Result<RegexNode, String> {
+impl RegexNode {
+    // How loosely the node binds: anything looser than an operand allows needs parentheses
+    fn looseness(&self) -> u8 {
+        match self {
+            RegexNode::TrailingContext(..) => 3,
+            RegexNode::Alternation(..) => 2,
+            RegexNode::Concatenation(..) | RegexNode::Empty => 1, // Empty is a concatenation of nothing
+            _ => 0,
+        }
+    }
+
+    // Writes the node where it may be at most `loosest` loose without parentheses
+    fn write_regex(&self, f: &mut fmt::Formatter, loosest: u8) -> fmt::Result {
+        if self.looseness() > loosest {
+            f.write_str("(")?;
+            self.write_regex(f, 2)?;
+            return f.write_str(")");
+        }
+
+        match self {
+            RegexNode::Char(ch) => write_char(f, *ch, false),
+            RegexNode::Dot => f.write_str("."),
+            RegexNode::Empty => Ok(()),
+            RegexNode::Concatenation(..) => {
+                let operands = left_operands(self, |node| match node {
+                    RegexNode::Concatenation(left, right) => Some((left, right)),
+                    _ => None,
+                });
+                for operand in operands {
+                    operand.write_regex(f, 0)?;
+                }
+                Ok(())
+            }
+            RegexNode::Alternation(..) => {
+                let operands = left_operands(self, |node| match node {
+                    RegexNode::Alternation(left, right) => Some((left, right)),
+                    _ => None,
+                });
+                for (index, operand) in operands.into_iter().enumerate() {
+                    if index > 0 {
+                        f.write_str("|")?;
+                    }
+                    operand.write_regex(f, 1)?;
+                }
+                Ok(())
+            }
+            RegexNode::Kleene(inner) => {
+                inner.write_regex(f, 0)?;
+                f.write_str("*")
+            }
+            RegexNode::Plus(inner) => {
+                inner.write_regex(f, 0)?;
+                f.write_str("+")
+            }
+            RegexNode::Optional(inner) => {
+                // A '?' straight after another quantifier would make it lazy instead
+                if matches!(**inner, RegexNode::Kleene(_) | RegexNode::Plus(_) | RegexNode::Optional(_) | RegexNode::Repeat { .. }) {
+                    f.write_str("(")?;
+                    inner.write_regex(f, 0)?;
+                    return f.write_str(")?");
+                }
+                inner.write_regex(f, 0)?;
+                f.write_str("?")
+            }
+            RegexNode::Lazy(inner) => {
+                inner.write_regex(f, 0)?;
+                f.write_str("?")
+            }
+            RegexNode::Repeat { node, min, max } => {
+                node.write_regex(f, 0)?;
+                match max {
+                    Some(max) if max == min => write!(f, "{{{}}}", min),
+                    Some(max) => write!(f, "{{{},{}}}", min, max),
+                    None => write!(f, "{{{},}}", min),
+                }
+            }
+            RegexNode::CharClass(chars) => {
+                f.write_str("[")?;
+                write_class_members(f, chars)?;
+                f.write_str("]")
+            }
+            RegexNode::NegatedCharClass(chars) => {
+                f.write_str("[^")?;
+                write_class_members(f, chars)?;
+                f.write_str("]")
+            }
+            RegexNode::Ranges(ranges) => write_ranges(f, ranges),
+            RegexNode::NegatedRanges(ranges) => {
+                // The class of the ranges themselves, negated
+                let class = RegexNode::Ranges(ranges.clone()).to_string();
+                match class.strip_prefix("\\p") {
+                    Some(property) => write!(f, "\\P{}", property),
+                    None => write!(f, "[^{}", &class[1..]),
+                }
+            }
+            RegexNode::LineStart => f.write_str("^"),
+            RegexNode::LineEnd => f.write_str("$"),
+            RegexNode::EndOfInput => f.write_str("\\z"),
+            RegexNode::TrailingContext(head, tail) => {
+                head.write_regex(f, 2)?;
+                f.write_str("/")?;
+                tail.write_regex(f, 2)
+            }
+            RegexNode::Capture(name, inner) => {
+                write!(f, "(?<{}>", name)?;
+                inner.write_regex(f, 2)?;
+                f.write_str(")")
+            }
+            RegexNode::FoldCase(class) => {
+                // The `\i` goes just inside the brackets, which a bare `\p{..}` needs added
+                let class = class.to_string();
+                if let Some(members) = class.strip_prefix("[^") {
+                    write!(f, "[^\\i{}", members)
+                } else if let Some(property) = class.strip_prefix("\\P") {
+                    write!(f, "[^\\i\\p{}]", property)
+                } else if let Some(members) = class.strip_prefix('[') {
+                    write!(f, "[\\i{}", members)
+                } else {
+                    write!(f, "[\\i{}]", class)
+                }
+            }
+        }
+    }
+}
+
+// Writes a character so it stands for itself, inside a class or out
+fn write_char(f: &mut fmt::Formatter, ch: char, in_class: bool) -> fmt::Result {
+    let special = if in_class { "\\]-^[" } else { "\\.[](){}|*+?^$/" };
+    match ch {
+        '\n' => f.write_str("\\n"),
+        '\t' => f.write_str("\\t"),
+        '\r' => f.write_str("\\r"),
+        '\u{c}' => f.write_str("\\f"),
+        '\u{b}' => f.write_str("\\v"),
+        '\0' => f.write_str("\\0"),
+        ' ' => f.write_str("\\_"),
+        _ if ch.is_control() => write!(f, "\\u{{{:x}}}", ch as u32),
+        _ if special.contains(ch) => write!(f, "\\{}", ch),
+        _ => write!(f, "{}", ch),
+    }
+}
+
+// Writes the members of a class in their order, with ascending runs of three or more as ranges
+fn write_class_members(f: &mut fmt::Formatter, chars: &[char]) -> fmt::Result {
+    let mut index = 0;
+    while index < chars.len() {
+        let mut end = index;
+        while chars.get(end + 1).is_some_and(|&next| next as u32 == chars[end] as u32 + 1) {
+            end += 1;
+        }
+        if end - index >= 2 {
+            write_range(f, chars[index], chars[end])?;
+        } else {
+            end = index;
+            write_char(f, chars[index], true)?;
+        }
+        index = end + 1;
+    }
+    Ok(())
+}
+
+fn write_range(f: &mut fmt::Formatter, low: char, high: char) -> fmt::Result {
+    write_char(f, low, true)?;
+    if high != low {
+        if high as u32 > low as u32 + 1 {
+            f.write_str("-")?;
+        }
+        write_char(f, high, true)?;
+    }
+    Ok(())
+}
+
+// The Unicode property covering the most of sorted, disjoint ranges while lying within them
+fn covering_property(ranges: &[(char, char)]) -> Option<(&'static str, &'static [(char, char)])> {
+    // Both are sorted, so one pass over `ranges` finds the run around each inner one
+    let within = |inner: &[(char, char)]| {
+        let mut outer = ranges.iter().peekable();
+        inner.iter().all(|&(low, high)| {
+            while outer.next_if(|&&(_, outer_high)| outer_high < low).is_some() {}
+            matches!(outer.peek(), Some(&&(outer_low, outer_high)) if outer_low <= low && high <= outer_high)
+        })
+    };
+    let coverage = |inner: &[(char, char)]| inner.iter().map(|&(low, high)| high as u32 - low as u32 + 1).sum::<u32>();
+    ["Alnum", "L", "N", "Lu", "Ll", "White_Space"]
+        .into_iter()
+        .filter_map(|name| Some((name, property_ranges(name)?)))
+        .filter(|(_, property)| within(property))
+        .max_by_key(|(_, property)| coverage(property))
+}
+
+// Writes sorted, disjoint ranges as the Unicode property covering most of them, plus
+// whatever that property leaves out
+fn write_ranges(f: &mut fmt::Formatter, ranges: &[(char, char)]) -> fmt::Result {
+    let Some((name, property)) = covering_property(ranges) else {
+        f.write_str("[")?;
+        for &(low, high) in ranges {
+            write_range(f, low, high)?;
+        }
+        return f.write_str("]");
+    };
+    if property == ranges {
+        return write!(f, "\\p{{{}}}", name);
+    }
+
+    write!(f, "[\\p{{{}}}", name)?;
+    for &(low, high) in ranges {
+        // The parts of this range between the property's ranges inside it
+        let mut next = low as u32;
+        for &(covered_low, covered_high) in property.iter().filter(|&&(covered_low, _)| low <= covered_low && covered_low <= high) {
+            if (covered_low as u32) > next {
+                write_range(f, char::from_u32(next).unwrap_or(low), char::from_u32(covered_low as u32 - 1).unwrap_or(low))?;
+            }
+            next = covered_high as u32 + 1;
+        }
+        if next <= high as u32 {
+            write_range(f, char::from_u32(next).unwrap_or(high), high)?;
+        }
+    }
+    f.write_str("]")
+}
+
+// The operands of a chain of one binary operator, in order. The parser nests such chains
+// to the left, so `split` is only followed down the left side.
+pub(crate) fn left_operands<'a>(
+    regex: &'a RegexNode,
+    split: impl Fn(&'a RegexNode) -> Option<(&'a RegexNode, &'a RegexNode)>,
+) -> Vec<&'a RegexNode> {
+    let mut operands = Vec::new();
+    let mut node = regex;
+    while let Some((left, right)) = split(node) {
+        operands.push(right);
+        node = left;
+    }
+    operands.push(node);
+    operands.reverse();
+    operands
+}
+
+/// A syntax error in a regex, with the zero-based character offset it refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl RegexError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        Self { message: message.into(), position }
+    }
+
+    /// The regex with a caret under the offending character
+    pub fn snippet(&self, regex: &str) -> String {
+        format!("{}\n{}^", regex, " ".repeat(self.position))
+    }
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+/// Parses the regex syntax used in spec rules
+pub fn parse_regex(regex: &str) -> Result<RegexNode, RegexError> {
+    parse_regex_with_warnings(regex).map(|(node, _)| node)
+}
+
+/// Like `parse_regex`, also returning warnings about parts that are probably mistakes,
+/// such as a character listed twice in one class
+pub fn parse_regex_with_warnings(regex: &str) -> Result<(RegexNode, Vec<String>), RegexError> {
     let mut parser = RegexParser::new(regex);
-    parser.parse_alternation()
+    let mut node = parser.parse_alternation()?;
+
+    // An unescaped '/' splits off the trailing context
+    if parser.current() == Some('/') {
+        let slash = parser.pos;
+        parser.advance();
+        let context = parser.parse_alternation()?;
+        if node == RegexNode::Empty || context == RegexNode::Empty {
+            return Err(RegexError::new("Trailing context needs a pattern on both sides of '/'", slash));
+        }
+        node = RegexNode::TrailingContext(Box::new(node), Box::new(context));
+        if node.trailing_split().is_none() {
+            return Err(RegexError::new("Trailing context needs one side of '/' to have a fixed length", slash));
+        }
+        if parser.current() == Some('/') {
+            return Err(RegexError::new("Only one '/' is allowed, escape the others as '\\/'", parser.pos));
+        }
+    }
+
+    if parser.current() == Some(')') {
+        return Err(RegexError::new("Unmatched closing parenthesis", parser.pos));
+    }
+    if let Some(capture) = parser.capture
+        && node.split_capture().is_none()
+    {
+        return Err(RegexError::new("A capture group can't be inside a quantifier, alternation or trailing context", capture));
+    }
+    Ok((node, parser.warnings))
 }
 
+// The largest count a repetition can give, since the NFA holds a copy of the repeated
+// part for each
+const MAX_REPEAT: usize = 1000;
+
+// Repetitions inside repetitions multiply, so the characters and classes the whole NFA
+// holds copies of are limited too, as `(a{1000}){1000}` would otherwise want a million
+const MAX_EXPANSION: usize = 10_000;
+
+// Groups nested deeper than this are an error, as each level takes several stack frames
+// to parse. Quantifiers are limited by how deep they leave the regex nested, since the
+// NFA is built by recursing into each of them.
+const MAX_GROUP_DEPTH: usize = 256;
+const MAX_NESTING: usize = 256;
+// Classes with more members than this are kept as ranges rather than listed
+const MAX_CLASS_CHARS: usize = 256;
+
 struct RegexParser {
     chars: Vec<char>,
     pos: usize,
+    depth: usize, // Groups open at the current position
+    capture: Option<usize>, // Where the capture group starts, once there is one
+    warnings: Vec<String>, // Likely mistakes that still parse, like `[a-ca-c]`
 }
 
 impl RegexParser {
@@ -26,6 +574,9 @@ impl RegexParser {
         Self {
             chars: regex.chars().collect(),
             pos: 0,
+            depth: 0,
+            capture: None,
+            warnings: Vec::new(),
         }
     }
 
@@ -43,7 +594,7 @@ impl RegexParser {
         self.chars.get(self.pos + 1).copied()
     }
 
-    fn parse_alternation(&mut self) -> Result<RegexNode, String> {
+    fn parse_alternation(&mut self) -> Result<RegexNode, RegexError> {
         let mut left = self.parse_concatenation()?;
 
         while self.current() == Some('|') {
@@ -55,22 +606,21 @@ impl RegexParser {
         Ok(left)
     }
 
-    fn parse_concatenation(&mut self) -> Result<RegexNode, String> {
+    fn parse_concatenation(&mut self) -> Result<RegexNode, RegexError> {
         let mut nodes = Vec::new();
 
         while let Some(ch) = self.current() {
-            if ch == '|' || ch == ')' {
+            if ch == '|' || ch == ')' || ch == '/' {
                 break;
             }
             nodes.push(self.parse_postfix()?);
         }
 
-        if nodes.is_empty() {
-            return Err("Empty concatenation".to_string());
-        }
-
+        // Nothing before a '|' or ')' is an empty branch
         let mut iter = nodes.into_iter();
-        let mut result = iter.next().unwrap();
+        let Some(mut result) = iter.next() else {
+            return Ok(RegexNode::Empty);
+        };
         for node in iter {
             result = RegexNode::Concatenation(Box::new(result), Box::new(node));
         }
@@ -78,10 +628,18 @@ impl RegexParser {
         Ok(result)
     }
 
-    fn parse_postfix(&mut self) -> Result<RegexNode, String> {
+    fn parse_postfix(&mut self) -> Result<RegexNode, RegexError> {
         let mut node = self.parse_primary()?;
+        let mut nesting = None; // Worked out at the first quantifier, which is usually the only one
 
         while let Some(ch) = self.current() {
+            if matches!(ch, '*' | '+' | '?') || (ch == '{' && self.is_repetition_start()) {
+                let depth = nesting.get_or_insert_with(|| node.nesting());
+                if *depth == MAX_NESTING {
+                    return Err(RegexError::new(format!("Quantifiers are nested more than {} deep", MAX_NESTING), self.pos));
+                }
+                *depth += 1;
+            }
             match ch {
                 '*' => {
                     self.advance();
@@ -95,22 +653,93 @@ impl RegexParser {
                     self.advance();
                     node = RegexNode::Optional(Box::new(node));
                 }
+                '{' if self.is_repetition_start() => {
+                    let start = self.pos;
+                    let (min, max) = self.parse_repetition()?;
+                    node = RegexNode::Repeat { node: Box::new(node), min, max };
+                    if node.expanded_size() > MAX_EXPANSION {
+                        let contents: String = self.chars[start + 1..self.pos - 1].iter().collect();
+                        return Err(RegexError::new(
+                            format!("Invalid repetition {{{}}}: the regex would expand to more than {} characters and classes", contents, MAX_EXPANSION),
+                            start,
+                        ));
+                    }
+                }
                 _ => break,
             }
+
+            // A '?' right after a quantifier makes it lazy rather than optional
+            if self.current() == Some('?') {
+                self.advance();
+                node = RegexNode::Lazy(Box::new(node));
+            }
         }
 
         Ok(node)
     }
 
-    fn parse_primary(&mut self) -> Result<RegexNode, String> {
+    fn is_repetition_start(&self) -> bool {
+        // A '{' only starts a repetition when followed by a count, otherwise it is a literal
+        matches!(self.peek(), Some(ch) if ch.is_ascii_digit() || ch == ',')
+    }
+
+    fn parse_repetition(&mut self) -> Result<(usize, Option<usize>), RegexError> {
+        let start = self.pos;
+        self.advance(); // consume '{'
+
+        let mut contents = String::new();
+        loop {
+            match self.advance() {
+                Some('}') => break,
+                Some(ch) => contents.push(ch),
+                None => return Err(RegexError::new(format!("Unterminated repetition {{{}", contents), start)),
+            }
+        }
+
+        let invalid = || RegexError::new(format!("Invalid repetition {{{}}}", contents), start);
+        let parse_count = |s: &str| s.parse::<usize>().map_err(|_| invalid());
+
+        let (min, max) = match contents.split_once(',') {
+            None => {
+                let count = parse_count(&contents)?;
+                (count, Some(count))
+            }
+            Some((min_str, "")) => (parse_count(min_str)?, None),
+            Some((min_str, max_str)) => (parse_count(min_str)?, Some(parse_count(max_str)?)),
+        };
+
+        if max.is_some_and(|max| min > max) {
+            return Err(RegexError::new(format!("Invalid repetition {{{}}}: minimum exceeds maximum", contents), start));
+        }
+        if min.max(max.unwrap_or(0)) > MAX_REPEAT {
+            return Err(RegexError::new(format!("Invalid repetition {{{}}}: counts are limited to {}", contents, MAX_REPEAT), start));
+        }
+
+        Ok((min, max))
+    }
+
+    fn parse_primary(&mut self) -> Result<RegexNode, RegexError> {
         match self.current() {
             Some('(') => {
+                let open = self.pos;
+                if self.depth == MAX_GROUP_DEPTH {
+                    return Err(RegexError::new(format!("Groups are nested more than {} deep", MAX_GROUP_DEPTH), open));
+                }
                 self.advance(); // consume '('
-                let node = self.parse_alternation()?;
+                let name = self.parse_capture_name(open)?;
+                self.depth += 1;
+                let mut node = self.parse_alternation()?;
+                self.depth -= 1;
+                if self.current() == Some('/') {
+                    return Err(RegexError::new("Trailing context '/' cannot be inside a group", self.pos));
+                }
                 if self.current() != Some(')') {
-                    return Err("Missing closing parenthesis".to_string());
+                    return Err(RegexError::new("Missing closing parenthesis", open));
                 }
                 self.advance(); // consume ')'
+                if let Some(name) = name {
+                    node = RegexNode::Capture(name, Box::new(node));
+                }
                 Ok(node)
             }
             Some('[') => self.parse_char_class(),
@@ -118,17 +747,56 @@ impl RegexParser {
                 self.advance();
                 Ok(RegexNode::Dot)
             }
+            Some('^') if self.pos == 0 => {
+                self.advance();
+                Ok(RegexNode::LineStart)
+            }
+            Some('$') if self.pos == self.chars.len() - 1 => {
+                self.advance();
+                Ok(RegexNode::LineEnd)
+            }
+            Some('\\') if self.peek() == Some('z') => {
+                if self.pos + 2 != self.chars.len() {
+                    return Err(RegexError::new("\\z only matches at the end of a regex", self.pos));
+                }
+                self.pos += 2;
+                Ok(RegexNode::EndOfInput)
+            }
             Some('\\') => self.parse_escape(),
             Some(ch) if ch != '|' && ch != ')' && ch != '*' && ch != '+' && ch != '?' => {
                 self.advance();
                 Ok(RegexNode::Char(ch))
             }
-            Some(ch) => Err(format!("Unexpected character: {}", ch)),
-            None => Err("Unexpected end of input".to_string()),
+            Some(ch) => Err(RegexError::new(format!("Unexpected character: {}", ch), self.pos)),
+            None => Err(RegexError::new("Unexpected end of input", self.pos)),
+        }
+    }
+
+    // The name of a `(?<name>` capture group whose '(' is at `open`, consuming it, or
+    // `None` for a plain group
+    fn parse_capture_name(&mut self, open: usize) -> Result<Option<String>, RegexError> {
+        if self.current() != Some('?') || self.peek() != Some('<') {
+            return Ok(None);
+        }
+        self.pos += 2;
+        let mut name = String::new();
+        while let Some(ch) = self.current().filter(|&ch| ch.is_ascii_alphanumeric() || ch == '_') {
+            name.push(ch);
+            self.advance();
+        }
+        if name.is_empty() || name.starts_with(|ch: char| ch.is_ascii_digit()) || self.current() != Some('>') {
+            return Err(RegexError::new("A capture group needs a name, as in (?<digits>[0-9]+)", open));
         }
+        self.advance(); // consume '>'
+        if self.capture.is_some() {
+            return Err(RegexError::new("Only one capture group is allowed per regex", open));
+        }
+        self.capture = Some(open);
+        Ok(Some(name))
     }
 
-    fn parse_escape(&mut self) -> Result<RegexNode, String> {
+    fn parse_escape(&mut self) -> Result<RegexNode, RegexError> {
+        let start = self.pos; // Errors point at the backslash
         self.advance(); // consume '\'
         match self.current() {
             Some('t') => {
@@ -139,6 +807,22 @@ impl RegexParser {
                 self.advance();
                 Ok(RegexNode::Char('\n'))
             }
+            Some('r') => {
+                self.advance();
+                Ok(RegexNode::Char('\r'))
+            }
+            Some('f') => {
+                self.advance();
+                Ok(RegexNode::Char('\u{c}'))
+            }
+            Some('v') => {
+                self.advance();
+                Ok(RegexNode::Char('\u{b}'))
+            }
+            Some('0') => {
+                self.advance();
+                Ok(RegexNode::Char('\0'))
+            }
             Some('_') => {
                 self.advance();
                 Ok(RegexNode::Char(' '))
@@ -155,15 +839,74 @@ impl RegexParser {
                 self.advance();
                 Ok(RegexNode::Char('\\'))
             }
+            Some('x') => {
+                self.advance();
+                let digits: String = (0..2).filter_map(|_| self.advance()).collect();
+                match hex_codepoint(&digits) {
+                    Some(ch) if digits.len() == 2 => Ok(RegexNode::Char(ch)),
+                    _ => Err(RegexError::new(format!("Invalid escape sequence \\x{}", digits), start)),
+                }
+            }
+            Some('u') => {
+                self.advance();
+                if self.current() != Some('{') {
+                    return Err(RegexError::new("Invalid escape sequence \\u (expected \\u{...})", start));
+                }
+                self.advance(); // consume '{'
+
+                let mut digits = String::new();
+                loop {
+                    match self.advance() {
+                        Some('}') => break,
+                        Some(ch) => digits.push(ch),
+                        None => return Err(RegexError::new(format!("Invalid escape sequence \\u{{{}", digits), start)),
+                    }
+                }
+
+                match hex_codepoint(&digits) {
+                    Some(ch) if digits.len() <= 6 => Ok(RegexNode::Char(ch)),
+                    _ => Err(RegexError::new(format!("Invalid escape sequence \\u{{{}}}", digits), start)),
+                }
+            }
+            Some(ch @ ('d' | 'w' | 's' | 'D' | 'W' | 'S')) => {
+                self.advance();
+                Ok(shorthand_class(ch))
+            }
+            Some(ch @ ('p' | 'P')) => {
+                self.advance();
+                if self.current() != Some('{') {
+                    return Err(RegexError::new(format!("Invalid escape sequence \\{} (expected \\{}{{...}})", ch, ch), start));
+                }
+                self.advance(); // consume '{'
+
+                let mut name = String::new();
+                loop {
+                    match self.advance() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(RegexError::new(format!("Invalid escape sequence \\{}{{{}", ch, name), start)),
+                    }
+                }
+
+                let Some(ranges) = property_ranges(&name) else {
+                    return Err(RegexError::new(format!("Unknown Unicode property '{}'", name), start));
+                };
+                if ch == 'P' {
+                    Ok(RegexNode::NegatedRanges(ranges.to_vec()))
+                } else {
+                    Ok(RegexNode::Ranges(ranges.to_vec()))
+                }
+            }
             Some(ch) => {
                 self.advance();
                 Ok(RegexNode::Char(ch))
             }
-            None => Err("Incomplete escape sequence".to_string()),
+            None => Err(RegexError::new("Incomplete escape sequence", start)),
         }
     }
 
-    fn parse_char_class(&mut self) -> Result<RegexNode, String> {
+    fn parse_char_class(&mut self) -> Result<RegexNode, RegexError> {
+        let open = self.pos;
         self.advance(); // consume '['
 
         let negated = if self.current() == Some('^') {
@@ -174,32 +917,628 @@ impl RegexParser {
         };
 
         let mut chars = Vec::new();
+        let mut ranges = Vec::new(); // From `\p{...}`, kept as ranges rather than listed
+        let mut listed_newline = false; // Whether '\n' is a member other than through a negation
+        let mut members = Vec::new(); // Ranges of what each item so far adds, to spot overlaps
+        let members_start = self.pos;
+        let mut operated = false; // Whether a set operation has combined the members so far
+        let mut fold = false; // Set by `\i`
 
-        while let Some(ch) = self.current() {
+        // A ']' right after '[' or '[^' is a member, not the end of an empty class
+        if self.current() == Some(']') {
+            chars.push(']');
+            members.push((']', ']'));
+            self.advance();
+        }
+
+        loop {
+            // Errors for a class the input ends inside point at its '['
+            let Some(ch) = self.current() else {
+                return Err(RegexError::new("Missing closing bracket", open));
+            };
             if ch == ']' {
                 self.advance();
                 break;
             }
 
-            if ch == '-' && !chars.is_empty() && self.peek().is_some() && self.peek() != Some(']') {
-                // Range
-                self.advance(); // consume '-'
-                let end_char = self.advance().unwrap();
-                let start_char = chars.pop().unwrap();
+            // `&&[...]` keeps what is also in the nested class and `--[...]` takes it away,
+            // applied left to right to everything before them
+            if let Some(operator) = self.set_operator() {
+                let operator_pos = self.pos;
+                if self.pos == members_start {
+                    return Err(RegexError::new(format!("Missing class before {}{}", operator, operator), operator_pos));
+                }
+                if self.depth == MAX_GROUP_DEPTH {
+                    return Err(RegexError::new(format!("Classes are nested more than {} deep", MAX_GROUP_DEPTH), operator_pos));
+                }
+                self.pos += 2;
+                self.depth += 1;
+                let nested_class = self.parse_char_class()?;
+                self.depth -= 1;
+
+                // Properties and negated classes span too much to list as characters
+                let nested = class_ranges(&nested_class);
+                if operator == '&' {
+                    listed_newline &= in_ranges(&nested, '\n') && !negated_class(&nested_class);
+                }
+                let current = merge_ranges(ranges.drain(..).chain(chars.drain(..).map(|c| (c, c))).collect());
+                ranges = match operator {
+                    '&' => intersect_ranges(&current, &nested),
+                    _ => subtract_ranges(&current, &nested),
+                };
+                operated = true;
+
+                if !matches!(self.current(), Some(']') | None) && self.set_operator().is_none() {
+                    return Err(RegexError::new("Only ']', '&&[' or '--[' can follow a nested class", self.pos));
+                }
+                continue;
+            }
+
+            // `\i` makes the letters of this class match either case, unlike `(CI)` on the rule
+            if ch == '\\' && self.peek() == Some('i') {
+                self.pos += 2;
+                fold = true;
+                continue;
+            }
+
+            // `[:alpha:]` and the other POSIX names add their ASCII characters. A '[' not
+            // followed by a name and ":]" is a member.
+            if let Some(name) = self.posix_class_name() {
+                let Some(members_of) = posix_class(&name) else {
+                    return Err(RegexError::new(format!("Unknown character class [:{}:]", name), self.pos));
+                };
+                self.check_overlap(&mut members, &merge_ranges(members_of.iter().map(|&c| (c, c)).collect()), || {
+                    format!("[:{}:] overlaps characters already in the class", name)
+                });
+                self.pos += name.chars().count() + 4;
+                listed_newline |= members_of.contains(&'\n');
+                chars.extend(members_of);
+                continue;
+            }
 
-                for c in (start_char as u8)..=(end_char as u8) {
-                    chars.push(c as char);
+            let range_start = self.pos;
+            let start_char = if ch == '\\' {
+                // Escapes inside brackets, splicing shorthand classes into the set
+                match &self.parse_escape()? {
+                    RegexNode::Char(escaped) => *escaped,
+                    class => {
+                        // Shorthand classes add characters, `\p{...}` and negated ones add ranges
+                        let (spliced_chars, spliced_ranges) = match class {
+                            RegexNode::CharClass(members) => (members.clone(), Vec::new()),
+                            RegexNode::NegatedCharClass(excluded) => (Vec::new(), complement_ranges(&char_ranges(excluded))),
+                            RegexNode::Ranges(property) => (Vec::new(), property.clone()),
+                            RegexNode::NegatedRanges(property) => (Vec::new(), complement_ranges(property)),
+                            _ => unreachable!("escapes only produce characters or classes"),
+                        };
+                        if !matches!(class, RegexNode::NegatedCharClass(_) | RegexNode::NegatedRanges(_)) {
+                            listed_newline |= spliced_chars.contains(&'\n') || in_ranges(&spliced_ranges, '\n');
+                        }
+                        let escape: String = self.chars[range_start..self.pos].iter().collect();
+                        let added: Vec<(char, char)> = spliced_chars.iter().map(|&c| (c, c)).chain(spliced_ranges.iter().copied()).collect();
+                        self.check_overlap(&mut members, &added, || format!("{} overlaps characters already in the class", escape));
+                        chars.extend(spliced_chars);
+                        ranges.extend(spliced_ranges);
+                        continue;
+                    }
                 }
             } else {
-                chars.push(ch);
                 self.advance();
+                ch
+            };
+
+            if self.current() == Some('-') && self.peek().is_none() {
+                return Err(RegexError::new(format!("Unterminated range {}-", start_char.escape_debug()), open));
+            } else if self.current() == Some('-') && self.peek().is_some_and(|next| next != ']') && self.set_operator().is_none() {
+                // Range, over codepoints
+                self.advance(); // consume '-'
+                let end_char = self.parse_range_end()?;
+                if start_char > end_char {
+                    let message = format!(
+                        "Invalid range {}-{}: start is after end",
+                        start_char.escape_debug(),
+                        end_char.escape_debug()
+                    );
+                    return Err(RegexError::new(message, range_start));
+                }
+                self.check_overlap(&mut members, &[(start_char, end_char)], || {
+                    format!("Range {}-{} overlaps characters already in the class", start_char.escape_debug(), end_char.escape_debug())
+                });
+                listed_newline |= (start_char..=end_char).contains(&'\n');
+                chars.extend(start_char..=end_char);
+            } else {
+                self.check_overlap(&mut members, &[(start_char, start_char)], || {
+                    format!("'{}' is already in the class", start_char.escape_debug())
+                });
+                listed_newline |= start_char == '\n';
+                chars.push(start_char);
             }
         }
 
-        if negated {
-            Ok(RegexNode::NegatedCharClass(chars))
+        // Overlapping items would otherwise give the NFA the same character several times
+        let mut seen = std::collections::HashSet::new();
+        chars.retain(|&c| seen.insert(c));
+        if operated && chars.is_empty() && ranges.is_empty() {
+            return Err(RegexError::new("Set operations leave the class empty", open));
+        } else if fold && chars.is_empty() && ranges.is_empty() {
+            return Err(RegexError::new("A class with only \\i has no members", open));
+        }
+
+        // Members listed one by one keep their order, unless there are too many to list
+        let class = if ranges.is_empty() && !operated && chars.len() <= MAX_CLASS_CHARS {
+            if negated { RegexNode::NegatedCharClass(chars) } else { RegexNode::CharClass(chars) }
         } else {
-            Ok(RegexNode::CharClass(chars))
+            ranges.extend(chars.iter().map(|&c| (c, c)));
+            let ranges = merge_ranges(ranges);
+            // A negation only brings in '\n' under `(DOTALL)`, which the NFA decides, so
+            // such a class is kept as the negation of what it leaves out
+            if in_ranges(&ranges, '\n') && !listed_newline {
+                class_node(complement_ranges(&ranges), !negated)
+            } else {
+                class_node(ranges, negated)
+            }
+        };
+        // The folding is left to the NFA, as for `(CI)`, so the class keeps the members written
+        Ok(if fold { RegexNode::FoldCase(Box::new(class)) } else { class })
+    }
+
+    // The name in a `[:name:]` at the current position, if there is one. Only letters
+    // followed by ":]" make a name, so `[:a]` is left as members.
+    fn posix_class_name(&self) -> Option<String> {
+        if self.current() != Some('[') || self.peek() != Some(':') {
+            return None;
+        }
+        let rest = &self.chars[self.pos + 2..];
+        let length = rest.iter().take_while(|ch| ch.is_ascii_alphabetic()).count();
+        (length > 0 && rest[length..].starts_with(&[':', ']'])).then(|| rest[..length].iter().collect())
+    }
+
+    // The operator character of a `&&[` or `--[` at the current position
+    fn set_operator(&self) -> Option<char> {
+        match self.chars.get(self.pos..self.pos + 3) {
+            Some(&[first, second, '[']) if first == second && matches!(first, '&' | '-') => Some(first),
+            _ => None,
         }
     }
+
+    // Warns when part of a class adds characters an earlier part already has, then
+    // records its ranges
+    fn check_overlap(&mut self, members: &mut Vec<(char, char)>, added: &[(char, char)], message: impl FnOnce() -> String) {
+        let overlaps = |&(low, high): &(char, char)| members.iter().any(|&(seen_low, seen_high)| low <= seen_high && seen_low <= high);
+        if added.iter().any(overlaps) {
+            self.warnings.push(message());
+        }
+        members.extend_from_slice(added);
+    }
+
+    fn parse_range_end(&mut self) -> Result<char, RegexError> {
+        match self.current() {
+            Some('\\') => {
+                let start = self.pos;
+                match self.parse_escape()? {
+                    RegexNode::Char(escaped) => Ok(escaped),
+                    _ => Err(RegexError::new("Invalid range: a class escape cannot end a range", start)),
+                }
+            }
+            Some(ch) => {
+                self.advance();
+                Ok(ch)
+            }
+            None => Err(RegexError::new("Unexpected end of input", self.pos)),
+        }
+    }
+}
+
+fn negated_class(class: &RegexNode) -> bool {
+    match class {
+        RegexNode::NegatedCharClass(_) | RegexNode::NegatedRanges(_) => true,
+        RegexNode::FoldCase(inner) => negated_class(inner),
+        _ => false,
+    }
+}
+
+// The characters as sorted runs
+fn char_ranges(chars: &[char]) -> Vec<(char, char)> {
+    merge_ranges(chars.iter().map(|&c| (c, c)).collect())
+}
+
+// The class of the characters in `ranges`, in the form its printed regex parses back to:
+// ranges when a Unicode property covers part of it or it is too big to list, and otherwise
+// its members in order
+fn class_node(ranges: Vec<(char, char)>, negated: bool) -> RegexNode {
+    let ranges = merge_ranges(ranges);
+    let size: usize = ranges.iter().map(|&(low, high)| (low..=high).size_hint().0).sum();
+    if size > MAX_CLASS_CHARS || covering_property(&ranges).is_some() {
+        return if negated { RegexNode::NegatedRanges(ranges) } else { RegexNode::Ranges(ranges) };
+    }
+    let chars = ranges.iter().flat_map(|&(low, high)| low..=high).collect();
+    if negated { RegexNode::NegatedCharClass(chars) } else { RegexNode::CharClass(chars) }
+}
+
+fn hex_codepoint(digits: &str) -> Option<char> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok().and_then(char::from_u32)
+}
+
+// The characters of a POSIX class like `[:alpha:]`, which only ever covers ASCII
+fn posix_class(name: &str) -> Option<Vec<char>> {
+    let test: fn(&char) -> bool = match name {
+        "alpha" => char::is_ascii_alphabetic,
+        "digit" => char::is_ascii_digit,
+        "alnum" => char::is_ascii_alphanumeric,
+        "upper" => char::is_ascii_uppercase,
+        "lower" => char::is_ascii_lowercase,
+        "punct" => char::is_ascii_punctuation,
+        "xdigit" => char::is_ascii_hexdigit,
+        "space" => |ch| matches!(ch, ' ' | '\t' | '\n' | '\r' | '\u{b}' | '\u{c}'),
+        "blank" => |ch| matches!(ch, ' ' | '\t'),
+        "cntrl" => char::is_ascii_control,
+        "graph" => char::is_ascii_graphic,
+        "print" => |ch| *ch == ' ' || ch.is_ascii_graphic(),
+        _ => return None,
+    };
+    Some((0..128u8).map(char::from).filter(test).collect())
+}
+
+// The characters a parsed class matches, as sorted runs, for set operations between
+// classes. A folded class takes in the other case of its ASCII letters, as it would match.
+fn class_ranges(class: &RegexNode) -> Vec<(char, char)> {
+    let other_case = |ch: char| if ch.is_ascii_uppercase() { ch.to_ascii_lowercase() } else { ch.to_ascii_uppercase() };
+    match class {
+        RegexNode::CharClass(members) => char_ranges(members),
+        RegexNode::NegatedCharClass(excluded) => complement_ranges(&char_ranges(excluded)),
+        RegexNode::Ranges(ranges) => ranges.clone(),
+        RegexNode::NegatedRanges(ranges) => complement_ranges(ranges),
+        RegexNode::FoldCase(inner) => match &**inner {
+            // Folding a negated class excludes both cases of what it lists
+            RegexNode::NegatedCharClass(excluded) => {
+                let excluded = excluded.iter().flat_map(|&c| [c, if c.is_ascii_alphabetic() { other_case(c) } else { c }]).collect();
+                class_ranges(&RegexNode::NegatedCharClass(excluded))
+            }
+            RegexNode::NegatedRanges(excluded) => {
+                let letters = ('A'..='Z').chain('a'..='z').filter(|&c| in_ranges(excluded, c)).map(|c| (other_case(c), other_case(c)));
+                complement_ranges(&merge_ranges(excluded.iter().copied().chain(letters).collect()))
+            }
+            inner => {
+                let mut ranges = class_ranges(inner);
+                let letters = ('A'..='Z').chain('a'..='z').filter(|&c| in_ranges(&ranges, c)).map(other_case).collect::<Vec<char>>();
+                ranges.extend(letters.into_iter().map(|c| (c, c)));
+                merge_ranges(ranges)
+            }
+        },
+        _ => unreachable!("classes only parse to classes"),
+    }
+}
+
+// The characters with a Unicode property, as runs, worked out from the standard library's
+// tables. Letters are the Alphabetic property, which also takes in some marks. Each
+// table takes a pass over every codepoint, so it is only worked out once.
+fn property_ranges(name: &str) -> Option<&'static [(char, char)]> {
+    static TABLES: [OnceLock<Vec<(char, char)>>; 6] = [const { OnceLock::new() }; 6];
+    let (table, test): (usize, fn(char) -> bool) = match name {
+        "L" | "Letter" | "Alpha" | "Alphabetic" => (0, char::is_alphabetic),
+        "Lu" | "Upper" | "Uppercase" => (1, char::is_uppercase),
+        "Ll" | "Lower" | "Lowercase" => (2, char::is_lowercase),
+        "N" | "Number" | "Numeric" => (3, char::is_numeric),
+        "Alnum" => (4, char::is_alphanumeric),
+        "White_Space" | "Whitespace" | "Space" => (5, char::is_whitespace),
+        _ => return None,
+    };
+
+    let ranges = TABLES[table].get_or_init(|| {
+        let mut ranges: Vec<(char, char)> = Vec::new();
+        for ch in (0..=char::MAX as u32).filter_map(char::from_u32).filter(|&ch| test(ch)) {
+            match ranges.last_mut() {
+                Some((_, high)) if *high as u32 + 1 == ch as u32 => *high = ch,
+                _ => ranges.push((ch, ch)),
+            }
+        }
+        ranges
+    });
+    Some(ranges)
+}
+
+pub(crate) fn in_ranges(ranges: &[(char, char)], ch: char) -> bool {
+    ranges.iter().any(|&(low, high)| (low..=high).contains(&ch))
+}
+
+// Sorts ranges and joins the ones that overlap or touch
+pub(crate) fn merge_ranges(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(char, char)> = Vec::new();
+    for (low, high) in ranges {
+        match merged.last_mut() {
+            // Runs either side of the surrogates touch, as no char lies between them
+            Some((_, last_high)) if char_after(*last_high).is_none_or(|next| low <= next) => *last_high = (*last_high).max(high),
+            _ => merged.push((low, high)),
+        }
+    }
+    merged
+}
+
+// Every character outside sorted, disjoint `ranges`
+pub(crate) fn complement_ranges(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    subtract_ranges(&[('\0', char::MAX)], ranges)
+}
+
+// The characters in both of two sorted, disjoint lists of ranges
+pub(crate) fn intersect_ranges(ranges: &[(char, char)], other: &[(char, char)]) -> Vec<(char, char)> {
+    let mut common = Vec::new();
+    for &(low, high) in ranges {
+        for &(other_low, other_high) in other {
+            let (low, high) = (low.max(other_low), high.min(other_high));
+            if low <= high {
+                common.push((low, high));
+            }
+        }
+    }
+    common
+}
+
+// The characters of sorted, disjoint `ranges` that are not in `removed`
+pub(crate) fn subtract_ranges(ranges: &[(char, char)], removed: &[(char, char)]) -> Vec<(char, char)> {
+    let mut left = Vec::new();
+    for &(low, high) in ranges {
+        let mut low = Some(low);
+        for &(removed_low, removed_high) in removed {
+            let Some(start) = low.filter(|&start| start <= high) else {
+                break;
+            };
+            if removed_high < start || removed_low > high {
+                continue;
+            }
+            if removed_low > start {
+                left.push((start, char_before(removed_low)));
+            }
+            low = char_after(removed_high);
+        }
+        if let Some(start) = low.filter(|&start| start <= high) {
+            left.push((start, high));
+        }
+    }
+    left
+}
+
+// Neighbouring characters, stepping over the surrogates that are not chars
+pub(crate) fn char_before(ch: char) -> char {
+    match ch {
+        '\u{E000}' => '\u{D7FF}',
+        _ => char::from_u32(ch as u32 - 1).unwrap_or(ch),
+    }
+}
+
+pub(crate) fn char_after(ch: char) -> Option<char> {
+    match ch {
+        '\u{D7FF}' => Some('\u{E000}'),
+        _ => char::from_u32(ch as u32 + 1),
+    }
+}
+
+// Expands \d, \w, \s and their negated uppercase forms
+fn shorthand_class(ch: char) -> RegexNode {
+    let chars: Vec<char> = match ch.to_ascii_lowercase() {
+        'd' => ('0'..='9').collect(),
+        'w' => ('a'..='z').chain('A'..='Z').chain('0'..='9').chain(['_']).collect(),
+        's' => vec![' ', '\t', '\n', '\r'],
+        _ => unreachable!("not a shorthand class: {}", ch),
+    };
+
+    if ch.is_ascii_uppercase() {
+        RegexNode::NegatedCharClass(chars)
+    } else {
+        RegexNode::CharClass(chars)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> RegexNode {
+        let mut chars = text.chars().map(RegexNode::Char);
+        let first = chars.next().unwrap();
+        chars.fold(first, |left, right| RegexNode::Concatenation(Box::new(left), Box::new(right)))
+    }
+
+    #[test]
+    fn repetition_counts() {
+        assert_eq!(parse_regex("a{2}"), Ok(RegexNode::Repeat { node: Box::new(RegexNode::Char('a')), min: 2, max: Some(2) }));
+        assert_eq!(parse_regex("a{2,}"), Ok(RegexNode::Repeat { node: Box::new(RegexNode::Char('a')), min: 2, max: None }));
+        assert!(parse_regex("a{1000}").is_ok());
+    }
+
+    #[test]
+    fn invalid_repetitions() {
+        let message = |regex: &str| parse_regex(regex).unwrap_err().message;
+        assert_eq!(message("a{2,1}"), "Invalid repetition {2,1}: minimum exceeds maximum");
+        assert_eq!(message("a{,}"), "Invalid repetition {,}");
+        assert_eq!(message("a{1001}"), "Invalid repetition {1001}: counts are limited to 1000");
+        assert_eq!(message("a{2,1000000000}"), "Invalid repetition {2,1000000000}: counts are limited to 1000");
+        // Nested counts multiply, and are limited as a whole
+        assert!(parse_regex("(a{100}){100}").is_ok());
+        assert_eq!(message("(a{100}){101}"), "Invalid repetition {101}: the regex would expand to more than 10000 characters and classes");
+        assert_eq!(message("((ab){1000}){1000}c"), "Invalid repetition {1000}: the regex would expand to more than 10000 characters and classes");
+        assert_eq!(parse_regex("((ab){1000}){1000}").unwrap_err().position, 12);
+    }
+
+    #[test]
+    fn shorthand_classes() {
+        let digits: Vec<char> = ('0'..='9').collect();
+        assert_eq!(parse_regex("\\d"), Ok(RegexNode::CharClass(digits.clone())));
+        assert_eq!(parse_regex("\\D"), Ok(RegexNode::NegatedCharClass(digits)));
+        assert_eq!(parse_regex("\\s"), Ok(RegexNode::CharClass(vec![' ', '\t', '\n', '\r'])));
+        // Inside brackets the members are spliced into the set
+        let Ok(RegexNode::CharClass(ref members)) = parse_regex("[\\d_]") else { panic!("expected a class") };
+        assert_eq!(members.len(), 11);
+        // A negated one splices in everything else, so the class is kept as what it leaves out
+        let Ok(RegexNode::NegatedCharClass(ref excluded)) = parse_regex("[\\W]") else { panic!("expected a negated class") };
+        assert!(!excluded.contains(&'-') && excluded.contains(&'a') && excluded.contains(&'_'));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for regex in ["ab|c", "(a|b)*c", "a+?", "[a-z0-9_]+", "[^\\n\"]", "x{2,3}", "^a$", "a/b", "\\p{L}+", "[\\p{Lu}_]", "(?<n>[0-9]+)x", "\\P{L}", "[^\\p{N}_]", "[w\\W]", "[^一-龥]", "[\\ia-c]x", "[^\\ia]", "[\\i\\p{Lu}]", "[^\\i\\p{Lu}]"] {
+            let node = parse_regex(regex).unwrap();
+            assert_eq!(parse_regex(&node.to_string()), Ok(node), "{}", regex);
+        }
+    }
+
+    #[test]
+    fn classes_take_one_form() {
+        // The same characters give the same node however they were written
+        assert_eq!(parse_regex("[一-龥\\p{L}&]"), parse_regex("[\\p{L}&]"));
+        assert_eq!(parse_regex("[\\p{L}&&[a-c]]"), parse_regex("[a-c]"));
+        assert_eq!(parse_regex("[a-c&&[^b]]"), Ok(RegexNode::CharClass(vec!['a', 'c'])));
+        assert_eq!(parse_regex("[^\\p{Lu}&&[A-C]]"), Ok(RegexNode::NegatedCharClass(vec!['A', 'B', 'C'])));
+        // Too many members to list, so kept as a range
+        assert_eq!(parse_regex("[一-龥]"), Ok(RegexNode::Ranges(vec![('一', '龥')])));
+        // A negated escape keeps the class negated, so the NFA decides about '\n'
+        let digits: Vec<char> = ('0'..='9').collect();
+        assert_eq!(parse_regex("[\\D]"), Ok(RegexNode::NegatedCharClass(digits.clone())));
+        assert_eq!(parse_regex("[^\\D]"), Ok(RegexNode::CharClass(digits)));
+        // Unless '\n' is listed too
+        assert!(matches!(parse_regex("[\\D\\n]"), Ok(RegexNode::Ranges(ref ranges)) if in_ranges(ranges, '\n')));
+    }
+
+    #[test]
+    fn brace_without_count_is_literal() {
+        assert_eq!(parse_regex("a{b}"), Ok(chars("a{b}")));
+        assert_eq!(parse_regex("a{"), Ok(chars("a{")));
+    }
+
+    #[test]
+    fn codepoint_escapes() {
+        assert_eq!(parse_regex("\\x41"), Ok(RegexNode::Char('A')));
+        assert_eq!(parse_regex("\\u{1F600}"), Ok(RegexNode::Char('\u{1F600}')));
+        let message = |regex: &str| parse_regex(regex).unwrap_err().message;
+        assert_eq!(message("\\x4"), "Invalid escape sequence \\x4");
+        assert_eq!(message("\\u{zzz}"), "Invalid escape sequence \\u{zzz}");
+    }
+
+    #[test]
+    fn anchors_only_at_the_ends() {
+        let line_start = RegexNode::Concatenation(Box::new(RegexNode::LineStart), Box::new(RegexNode::Char('#')));
+        assert_eq!(parse_regex("^#"), Ok(line_start));
+        let line_end = RegexNode::Concatenation(Box::new(RegexNode::Char('a')), Box::new(RegexNode::LineEnd));
+        assert_eq!(parse_regex("a$"), Ok(line_end));
+        // Anywhere else they are ordinary characters
+        assert_eq!(parse_regex("a^$b"), Ok(chars("a^$b")));
+    }
+
+    #[test]
+    fn leading_bracket_is_a_member() {
+        assert_eq!(parse_regex("[]]"), Ok(RegexNode::CharClass(vec![']'])));
+        assert_eq!(parse_regex("[]a]"), Ok(RegexNode::CharClass(vec![']', 'a'])));
+        assert_eq!(parse_regex("[^]]"), Ok(RegexNode::NegatedCharClass(vec![']'])));
+        // After the first member a `]` still closes the class
+        let class = RegexNode::CharClass(vec!['a']);
+        assert_eq!(parse_regex("[a]]"), Ok(RegexNode::Concatenation(Box::new(class), Box::new(RegexNode::Char(']')))));
+    }
+
+    #[test]
+    fn class_ranges() {
+        let error = |regex: &str| parse_regex(regex).map_err(|error| (error.message, error.position));
+        assert_eq!(error("x[z-a]"), Err(("Invalid range z-a: start is after end".to_string(), 2)));
+        // Escapes can end a range at either side
+        assert_eq!(parse_regex("[\\t-\\r]"), Ok(RegexNode::CharClass(vec!['\t', '\n', '\u{b}', '\u{c}', '\r'])));
+        assert_eq!(error("[\\r-\\t]"), Err(("Invalid range \\r-\\t: start is after end".to_string(), 1)));
+    }
+
+    #[test]
+    fn unbalanced_parentheses() {
+        // Reported at the '(' left open
+        let error = parse_regex("ab(c|d").unwrap_err();
+        assert_eq!((error.message.as_str(), error.position), ("Missing closing parenthesis", 2));
+        assert_eq!(error.snippet("ab(c|d"), "ab(c|d\n  ^");
+        assert_eq!(parse_regex("(a(b)").unwrap_err().position, 0);
+        assert_eq!(parse_regex("(a)(b").unwrap_err().position, 3);
+    }
+
+    #[test]
+    fn empty_branch() {
+        assert_eq!(parse_regex("a|"), Ok(RegexNode::Alternation(Box::new(RegexNode::Char('a')), Box::new(RegexNode::Empty))));
+    }
+
+    #[test]
+    fn unterminated_classes() {
+        // Reported at the '[' the class opened with
+        let error = |regex: &str| parse_regex(regex).map_err(|error| (error.message, error.position));
+        assert_eq!(error("[abc"), Err(("Missing closing bracket".to_string(), 0)));
+        assert_eq!(error("x[a-"), Err(("Unterminated range a-".to_string(), 1)));
+        assert_eq!(error("[a-]"), Ok(RegexNode::CharClass(vec!['a', '-'])));
+    }
+
+    #[test]
+    fn malformed_regexes_are_errors() {
+        // Each of these once panicked instead, by ending partway through something or by
+        // nesting deep enough to overflow the stack
+        let mut regexes: Vec<String> = ["\\", "[a-", "[a-\\", "[-", "[]", "[^]", "\\p{", "\\u{110000}", "[[:", "(?<"]
+            .iter()
+            .map(|regex| regex.to_string())
+            .collect();
+        regexes.push("(".repeat(300) + &")".repeat(300));
+        regexes.push("[a&&".repeat(300));
+        for regex in regexes {
+            assert!(parse_regex(&regex).is_err(), "{}", regex);
+        }
+        assert_eq!(parse_regex("a{"), Ok(chars("a{")));
+    }
+
+    #[test]
+    fn repetition_start() {
+        // Whether the '{' at `pos` of `regex` starts a count
+        let starts = |regex: &str, pos: usize| {
+            let mut parser = RegexParser::new(regex);
+            parser.pos = pos;
+            parser.is_repetition_start()
+        };
+        assert!(starts("a{2}", 1));
+        assert!(starts("a{,3}", 1));
+        assert!(!starts("a{b}", 1));
+        assert!(!starts("a{", 1));
+        assert!(!starts("{", 0));
+    }
+
+    #[test]
+    fn class_set_operations() {
+        assert_eq!(parse_regex("[a-f&&[d-z]]"), Ok(RegexNode::CharClass(vec!['d', 'e', 'f'])));
+        assert_eq!(parse_regex("[a-f&&[d-z]]"), parse_regex("[d-f]"));
+        let consonants = parse_regex("[b-df-hj-np-tv-z]");
+        assert_eq!(parse_regex("[a-z--[aeiou]]"), consonants);
+        assert_eq!(parse_regex("[a-z&&[^aeiou]]"), consonants);
+        // Applied left to right
+        assert_eq!(parse_regex("[a-z--[a-c]&&[a-e]]"), parse_regex("[de]"));
+        // Without a '[' after them, `&&` and `--` are members
+        assert_eq!(parse_regex("[a&&b]"), Ok(RegexNode::CharClass(vec!['a', '&', 'b'])));
+    }
+
+    #[test]
+    fn malformed_set_operations() {
+        let error = |regex: &str| parse_regex(regex).map(|_| ()).map_err(|err| (err.message, err.position));
+        assert_eq!(error("[&&[a]]"), Err(("Missing class before &&".to_string(), 1)));
+        assert_eq!(error("[a-c&&[b]x]"), Err(("Only ']', '&&[' or '--[' can follow a nested class".to_string(), 9)));
+        assert_eq!(error("[a-c&&[b"), Err(("Missing closing bracket".to_string(), 6)));
+        // `[]]` is a class of one `]`, so the outer class is never closed
+        assert_eq!(error("[a-c--[]]"), Err(("Missing closing bracket".to_string(), 0)));
+    }
+
+    #[test]
+    fn fold_keeps_class_members() {
+        let class = RegexNode::CharClass(vec!['a', 'b', 'c']);
+        assert_eq!(parse_regex("[\\ia-c]"), Ok(RegexNode::FoldCase(Box::new(class))));
+        assert_eq!(parse_regex("[\\ia-c&&[b-z]]"), Ok(RegexNode::FoldCase(Box::new(RegexNode::CharClass(vec!['b', 'c'])))));
+        // Set operations take the folded members of a nested class
+        assert_eq!(parse_regex("[A-C--[\\ia]]"), Ok(RegexNode::CharClass(vec!['B', 'C'])));
+    }
+    #[test]
+    fn posix_classes() {
+        let digits: Vec<char> = ('0'..='9').collect();
+        assert_eq!(parse_regex("[[:digit:]]"), Ok(RegexNode::CharClass(digits.clone())));
+        assert_eq!(parse_regex("[[:digit:]_]"), Ok(RegexNode::CharClass(digits.into_iter().chain(['_']).collect())));
+        // Without a name and ":]" the '[' is a member
+        assert_eq!(parse_regex("[[:a]"), Ok(RegexNode::CharClass(vec!['[', ':', 'a'])));
+        assert_eq!(parse_regex("[[:alfa:]]").unwrap_err().message, "Unknown character class [:alfa:]");
+    }
+
 }
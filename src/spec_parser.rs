@@ -1,57 +1,788 @@
-#[derive(Debug, Clone)]
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::regex_parser::{merge_ranges, parse_regex, RegexNode};
+
+/// What happens when a rule wins a match
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Action {
-    Skip,
+    Skip(Option<String>), // From `(SKIP keep)`, the name the skipped text still comes out under as a token
     Error(String),
-    Token { name: String, keep_lexeme: bool },
+    Token {
+        name: String,
+        keep_lexeme: bool,
+        value: Option<String>, // Replaces the lexeme when set
+        channel: Option<String>, // Set by `(CHANNEL name)`; `None` is the default channel
+    },
+    /// Several tokens from one match, as in `NUMBER true, DICE false, NUMBER true`.
+    /// Each spans the whole match and keeps the lexeme or not on its own.
+    Tokens {
+        tokens: Vec<(String, bool)>, // Token names in emission order, with their keep flags
+        channel: Option<String>,
+    },
+    Begin(usize), // Switch to a start condition, by index into `Spec::conditions`
+    Push(usize), // Switch to a start condition, remembering the current one for `(POP)`
+    Pop, // Go back to the condition before the latest `(PUSH ...)`
 }
 
+/// One line of the spec: a regex and its action
 #[derive(Debug, Clone)]
 pub struct Rule {
     pub regex: String,
     pub action: Action,
+    pub conditions: Vec<usize>, // Start conditions the rule applies in
+    pub priority: i32, // Higher priorities win ties on match length before line order does
+    pub case_insensitive: bool, // ASCII letters match either case
+    pub dotall: bool, // '.' and negated classes also match newline
+    pub shortest: bool, // Takes the shortest match instead of the longest, as for `(SHORTEST)`
+    pub extended: bool, // Written with whitespace and `#` comments, which `regex` has had removed
+    pub line: usize, // Line of the spec the rule was defined on
+    pub eof: bool, // An `<<EOF>>` rule, whose action runs at end of input; `regex` is unused
+}
+
+/// What the generated lexer does with a character no rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unmatched {
+    #[default]
+    Skip,
+    Report, // Print a diagnostic to stderr
+    Error,  // Emit an ERROR token for the character
+    Recover, // Skip to the next character that can start a token, with one ERROR token for the run
 }
 
+/// Written in place of a regex for a rule that applies at end of input, as in flex
+pub const EOF_RULE: &str = "<<EOF>>";
+
+/// Name of the channel tokens are on without a `(CHANNEL name)` modifier
+pub const DEFAULT_CHANNEL: &str = "default";
+
+/// Name of the start condition the lexer begins in, which unprefixed rules belong to
+pub const INITIAL: &str = "INITIAL";
+
+/// A parsed spec; rules are listed in precedence order
+///
+/// Start conditions are declared with `%state NAME` and a rule is limited to some of
+/// them by a prefix such as `<STRING>` or `<STRING,COMMENT>`. Rules without a prefix
+/// only apply in `INITIAL`, and the `(BEGIN NAME)` action switches condition.
 #[derive(Debug)]
 pub struct Spec {
-    pub rules: Vec<Rule>,
+    pub rules: Vec<Rule>, // Regexes have their macro references already expanded
+    pub unmatched: Unmatched,
+    pub conditions: Vec<String>, // Start condition names; `INITIAL` is always first
+    pub eof_token: bool, // Whether tokens end with EOF; `%no-eof` turns it off
+    pub alphabet: Option<Vec<(char, char)>>, // From `%alphabet`: sorted, disjoint ranges of the only characters rules match
+}
+
+/// Why a spec failed to parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecError {
+    /// A line that could not be parsed, with the part of it at fault: a name, an action
+    /// or the whole rule, depending on `kind`
+    Line { kind: SpecErrorKind, line: usize, text: String },
+    /// A spec file that could not be read, and the line of the `%include` naming it
+    Read { path: PathBuf, line: Option<usize>, message: String },
+    /// An error in a file pulled in with `%include`
+    Included { path: PathBuf, error: Box<SpecError> },
+}
+
+/// What is wrong with a line of a spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecErrorKind {
+    DirectiveArgument, // A directive that takes no argument was given one
+    MacroAfterRules,
+    CyclicMacro,
+    MissingIncludePath,
+    IncludeCycle,
+    InvalidUnmatched,
+    MissingStateName,
+    InvalidAlphabet,
+    InvalidStateName,
+    DuplicateState,
+    EofBegin, // An `<<EOF>>` rule with a `(BEGIN ...)`, `(PUSH ...)` or `(POP)` action
+    InvalidFormat,
+    UnterminatedQuote,
+    EmptyLiteral,
+    InvalidPriority,
+    UndeclaredState,
+    MissingErrorMessage,
+    InvalidAction,
+    InvalidKeep,
+    InvalidChannel,
+    ChannelWithoutToken, // `(CHANNEL name)` on a rule that makes no token
+}
+
+impl SpecError {
+    fn at(line: usize, kind: SpecErrorKind, text: impl Into<String>) -> Self {
+        SpecError::Line { kind, line, text: text.into() }
+    }
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SpecErrorKind::*;
+        match self {
+            SpecError::Line { kind, line, text } => {
+                write!(f, "Line {}: ", line)?;
+                match kind {
+                    DirectiveArgument => write!(f, "%{} takes no argument", text),
+                    MacroAfterRules => write!(f, "Macro '{}' must be defined before the rules", text),
+                    CyclicMacro => write!(f, "Cyclic macro definition '{}'", text),
+                    MissingIncludePath => write!(f, "%include needs a path"),
+                    IncludeCycle => write!(f, "Include cycle, '{}' is already being read", text),
+                    InvalidUnmatched => write!(f, "%unmatched must be 'skip', 'report', 'error' or 'recover'"),
+                    MissingStateName => write!(f, "%state needs at least one name"),
+                    InvalidAlphabet => write!(f, "Invalid alphabet '{}', list the characters as inside [...], as in %alphabet ACGTN\\n", text),
+                    InvalidStateName => write!(f, "Invalid state name '{}'", text),
+                    DuplicateState => write!(f, "State '{}' is already declared", text),
+                    EofBegin => write!(f, "{} rules cannot switch state", EOF_RULE),
+                    InvalidFormat => write!(f, "Invalid rule format"),
+                    UnterminatedQuote => write!(f, "Unterminated quoted regex"),
+                    EmptyLiteral => write!(f, "Empty literal"),
+                    InvalidPriority => write!(f, "Priority must be an integer, as in (PRIO 10)"),
+                    UndeclaredState => write!(f, "Undeclared state '{}'", text),
+                    MissingErrorMessage => write!(f, "Error action must have quoted message"),
+                    InvalidAction => write!(f, "Invalid action format"),
+                    InvalidKeep => write!(f, "Keep value must be 'true' or 'false'"),
+                    InvalidChannel => write!(f, "Invalid channel name '{}', as in (CHANNEL hidden)", text),
+                    ChannelWithoutToken => write!(f, "(CHANNEL {}) only applies to rules that make a token", text),
+                }
+            }
+            SpecError::Read { path, line: Some(line), message } => {
+                write!(f, "Line {}: Cannot read '{}': {}", line, path.display(), message)
+            }
+            SpecError::Read { path, line: None, message } => write!(f, "Cannot read '{}': {}", path.display(), message),
+            SpecError::Included { path, error } => write!(f, "In '{}': {}", path.display(), error),
+        }
+    }
+}
+
+/// Parses spec file contents, reporting the first error with its line number.
+/// `%include` paths are resolved against the current directory.
+pub fn parse_spec(content: &str) -> Result<Spec, SpecError> {
+    let mut builder = SpecBuilder::new();
+    builder.parse(content, Path::new("."))?;
+    Ok(builder.finish())
+}
+
+/// Reads and parses the spec file at `path`, resolving `%include` paths against the
+/// directory of the file that includes them
+pub fn parse_spec_from_path(path: impl AsRef<Path>) -> Result<Spec, SpecError> {
+    let mut builder = SpecBuilder::new();
+    builder.parse_file(path.as_ref())?;
+    Ok(builder.finish())
 }
 
-pub fn parse_spec(content: &str) -> Result<Spec, String> {
-    let mut rules = Vec::new();
+// What has been read so far of a spec and the specs it includes, which share their
+// macros, start conditions and settings
+struct SpecBuilder {
+    rules: Vec<Rule>,
+    macros: HashMap<String, String>,
+    unmatched: Unmatched,
+    conditions: Vec<String>,
+    case_insensitive: bool,
+    dotall: bool,
+    extended: bool,
+    eof_token: bool,
+    alphabet: Option<Vec<(char, char)>>,
+    including: Vec<PathBuf>, // Files being read, outermost first, to catch include cycles
+}
+
+impl SpecBuilder {
+    fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            macros: HashMap::new(),
+            unmatched: Unmatched::default(),
+            conditions: vec![INITIAL.to_string()],
+            case_insensitive: false,
+            dotall: false,
+            extended: false,
+            eof_token: true,
+            alphabet: None,
+            including: Vec::new(),
+        }
+    }
+
+    fn parse(&mut self, content: &str, dir: &Path) -> Result<(), SpecError> {
+        for (line_num, line) in content.lines().enumerate() {
+            let line = line.trim();
+            // A regex that starts with a `#` escapes it as `\#`
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((directive, argument)) = split_directive(line) {
+                match directive {
+                    "unmatched" => self.unmatched = parse_unmatched(argument, line_num + 1)?,
+                    "state" => declare_conditions(argument, &mut self.conditions, line_num + 1)?,
+                    "include" => self.include(argument, dir, line_num + 1)?,
+                    "alphabet" => {
+                        let mut ranges = parse_alphabet(argument, line_num + 1)?;
+                        ranges.extend(self.alphabet.take().unwrap_or_default());
+                        self.alphabet = Some(merge_ranges(ranges));
+                    }
+                    "case-insensitive" if argument.is_empty() => self.case_insensitive = true,
+                    "dotall" if argument.is_empty() => self.dotall = true,
+                    "extended" if argument.is_empty() => self.extended = true,
+                    "no-eof" if argument.is_empty() => self.eof_token = false,
+                    "case-insensitive" | "dotall" | "extended" | "no-eof" => {
+                        return Err(SpecError::at(line_num + 1, SpecErrorKind::DirectiveArgument, directive));
+                    }
+                    _ => unreachable!("unknown directive: {}", directive),
+                }
+                continue;
+            }
+
+            if let Some((name, regex)) = split_macro_definition(line) {
+                if !self.rules.is_empty() {
+                    return Err(SpecError::at(line_num + 1, SpecErrorKind::MacroAfterRules, name));
+                }
+                self.macros.insert(name.to_string(), regex.to_string());
+                continue;
+            }
+
+            let (rule_conditions, line) = split_conditions(line, &self.conditions).unwrap_or((vec![0], line));
+            let mut rule = parse_rule(line, line_num + 1, &self.conditions)?;
+            rule.conditions = rule_conditions;
+            rule.line = line_num + 1;
+            rule.regex = expand_macros(&rule.regex, &self.macros, &mut Vec::new())
+                .map_err(|(kind, name)| SpecError::at(line_num + 1, kind, name))?;
+            self.rules.push(rule);
+        }
+        Ok(())
+    }
+
+    fn parse_file(&mut self, path: &Path) -> Result<(), SpecError> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| SpecError::Read { path: path.to_path_buf(), line: None, message: err.to_string() })?;
+        self.parse_included(&content, path)
+    }
 
-    for (line_num, line) in content.lines().enumerate() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
+    // Reads the rules of `%include "path"` in place; errors inside name the file
+    fn include(&mut self, argument: &str, dir: &Path, line_num: usize) -> Result<(), SpecError> {
+        let name = argument.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')).unwrap_or(argument);
+        if name.is_empty() {
+            return Err(SpecError::at(line_num, SpecErrorKind::MissingIncludePath, argument));
         }
 
-        let rule = parse_rule(line, line_num + 1)?;
-        rules.push(rule);
+        let path = dir.join(name);
+        let content = fs::read_to_string(&path)
+            .map_err(|err| SpecError::Read { path: path.clone(), line: Some(line_num), message: err.to_string() })?;
+        if self.including.contains(&canonical(&path)) {
+            return Err(SpecError::at(line_num, SpecErrorKind::IncludeCycle, path.display().to_string()));
+        }
+        self.parse_included(&content, &path)
+            .map_err(|error| SpecError::Included { path, error: Box::new(error) })
+    }
+
+    // Parses the contents of the file at `path`, which its own includes are relative to
+    fn parse_included(&mut self, content: &str, path: &Path) -> Result<(), SpecError> {
+        self.including.push(canonical(path));
+        self.parse(content, path.parent().unwrap_or(Path::new(".")))?;
+        self.including.pop();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Spec {
+        // %case-insensitive, %dotall and %extended apply to every rule, wherever they appear
+        for rule in &mut self.rules {
+            rule.case_insensitive |= self.case_insensitive;
+            rule.dotall |= self.dotall;
+            rule.extended |= self.extended && !rule.eof;
+            if rule.extended {
+                rule.regex = strip_layout(&rule.regex);
+            }
+        }
+
+        Spec {
+            rules: self.rules,
+            unmatched: self.unmatched,
+            conditions: self.conditions,
+            eof_token: self.eof_token,
+            alphabet: self.alphabet,
+        }
     }
+}
 
-    Ok(Spec { rules })
+// Files are compared by canonical path, however the include spelled them
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
 }
 
-fn parse_rule(line: &str, line_num: usize) -> Result<Rule, String> {
-    // Find the last space to split regex from action
-    let parts: Vec<&str> = line.splitn(2, ' ').collect();
-    if parts.len() < 2 {
-        return Err(format!("Line {}: Invalid rule format", line_num));
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(ch) if ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+// Definitions look like `NAME = regex`
+fn split_macro_definition(line: &str) -> Option<(&str, &str)> {
+    let (name, rest) = line.split_once(char::is_whitespace)?;
+    let regex = rest.trim_start().strip_prefix('=')?;
+    let regex = regex.trim();
+    (is_identifier(name) && !regex.is_empty()).then_some((name, regex))
+}
+
+// Textually replaces each {NAME} with its parenthesized definition. Escapes and
+// bracketed classes are copied untouched, as are repetition counts like {2,3} and
+// braces around a name that isn't a defined macro, so `a{b}` stays literal. Errors
+// give the name of the macro at fault.
+fn expand_macros(
+    regex: &str,
+    macros: &HashMap<String, String>,
+    expanding: &mut Vec<String>,
+) -> Result<String, (SpecErrorKind, String)> {
+    let chars: Vec<char> = regex.chars().collect();
+    let mut expanded = String::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        match chars[pos] {
+            '\\' => {
+                let mut end = (pos + 2).min(chars.len());
+                // The braces of `\p{L}` name a Unicode property and those of `\u{E9}` a
+                // codepoint, not a macro
+                if matches!(chars.get(pos + 1), Some('p' | 'P' | 'u')) && chars.get(end) == Some(&'{') {
+                    end = chars[end..].iter().position(|&c| c == '}').map_or(chars.len(), |offset| end + offset + 1);
+                }
+                expanded.extend(&chars[pos..end]);
+                pos = end;
+            }
+            '[' => {
+                // Copy the class through its closing bracket
+                let start = pos;
+                pos += 1;
+                if chars.get(pos) == Some(&'^') {
+                    pos += 1;
+                }
+                if chars.get(pos) == Some(&']') {
+                    pos += 1;
+                }
+                while pos < chars.len() && chars[pos] != ']' {
+                    pos += if chars[pos] == '\\' { 2 } else { 1 };
+                }
+                pos = (pos + 1).min(chars.len());
+                expanded.extend(&chars[start..pos]);
+            }
+            '{' => {
+                let name: String = chars[pos + 1..].iter().take_while(|&&c| c != '}').collect();
+                let closed = chars.get(pos + 1 + name.chars().count()) == Some(&'}');
+                let definition = macros.get(&name).filter(|_| closed);
+                let Some(definition) = definition else {
+                    expanded.push('{');
+                    pos += 1;
+                    continue;
+                };
+                pos += name.chars().count() + 2;
+
+                if expanding.contains(&name) {
+                    return Err((SpecErrorKind::CyclicMacro, name));
+                }
+
+                expanding.push(name);
+                let inner = expand_macros(definition, macros, expanding)?;
+                expanding.pop();
+
+                expanded.push('(');
+                expanded.push_str(&inner);
+                expanded.push(')');
+            }
+            ch => {
+                expanded.push(ch);
+                pos += 1;
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+const DIRECTIVES: &[&str] = &["unmatched", "state", "include", "alphabet", "case-insensitive", "dotall", "extended", "no-eof"];
+
+// A directive is a '%' followed by a known name; anything else is an ordinary rule
+fn split_directive(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('%')?;
+    let (name, argument) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    DIRECTIVES.contains(&name).then(|| (name, argument.trim()))
+}
+
+fn parse_unmatched(argument: &str, line_num: usize) -> Result<Unmatched, SpecError> {
+    match argument {
+        "skip" => Ok(Unmatched::Skip),
+        "report" => Ok(Unmatched::Report),
+        "error" => Ok(Unmatched::Error),
+        "recover" => Ok(Unmatched::Recover),
+        _ => Err(SpecError::at(line_num, SpecErrorKind::InvalidUnmatched, argument)),
     }
+}
 
-    let regex = parts[0].to_string();
-    let action_str = parts[1];
+// The characters of `%alphabet`, written like the inside of a bracket class so ranges and
+// escapes such as `a-z` and `\n` work. Several `%alphabet` lines add up.
+fn parse_alphabet(argument: &str, line_num: usize) -> Result<Vec<(char, char)>, SpecError> {
+    match &parse_regex(&format!("[{}]", argument)) {
+        Ok(RegexNode::Char(ch)) => Ok(vec![(*ch, *ch)]),
+        Ok(RegexNode::CharClass(chars)) => Ok(chars.iter().map(|&ch| (ch, ch)).collect()),
+        Ok(RegexNode::Ranges(ranges)) => Ok(ranges.clone()),
+        _ => Err(SpecError::at(line_num, SpecErrorKind::InvalidAlphabet, argument)),
+    }
+}
 
-    let action = parse_action(action_str, line_num)?;
+fn declare_conditions(argument: &str, conditions: &mut Vec<String>, line_num: usize) -> Result<(), SpecError> {
+    if argument.is_empty() {
+        return Err(SpecError::at(line_num, SpecErrorKind::MissingStateName, argument));
+    }
+    for name in argument.split_whitespace() {
+        if !is_identifier(name) {
+            return Err(SpecError::at(line_num, SpecErrorKind::InvalidStateName, name));
+        }
+        if conditions.iter().any(|condition| condition == name) {
+            return Err(SpecError::at(line_num, SpecErrorKind::DuplicateState, name));
+        }
+        conditions.push(name.to_string());
+    }
+    Ok(())
+}
 
-    Ok(Rule { regex, action })
+// A `<A,B>` prefix limits a rule to those start conditions. Like directives, it is only
+// recognized when every name is a declared condition, so regexes such as `<=` or `<a>`
+// keep their meaning.
+fn split_conditions<'a>(line: &'a str, conditions: &[String]) -> Option<(Vec<usize>, &'a str)> {
+    let (names, rest) = line.strip_prefix('<')?.split_once('>')?;
+    let indices = names
+        .split(',')
+        .map(|name| conditions.iter().position(|condition| condition == name.trim()))
+        .collect::<Option<Vec<usize>>>()?;
+    let rest = rest.trim_start();
+    (!rest.is_empty()).then_some((indices, rest))
 }
 
-fn parse_action(action_str: &str, line_num: usize) -> Result<Action, String> {
+fn parse_rule(line: &str, line_num: usize, conditions: &[String]) -> Result<Rule, SpecError> {
+    // `<<EOF>> action` replaces the EOF token at the end of input
+    if let Some(action_str) = line.strip_prefix(EOF_RULE).filter(|rest| rest.starts_with(char::is_whitespace)) {
+        let (action_str, mut modifiers) = split_modifiers(strip_comment(action_str), line_num)?;
+        let action = parse_action(action_str, modifiers.channel.take(), line_num, conditions)?;
+        if let Action::Begin(_) | Action::Push(_) | Action::Pop = action {
+            return Err(SpecError::at(line_num, SpecErrorKind::EofBegin, action_str.trim()));
+        }
+        let mut rule = modifiers.rule(EOF_RULE.to_string(), action);
+        rule.eof = true;
+        return Ok(rule);
+    }
+
+    let quoted = match split_literal(line, line_num)? {
+        Some(literal) => Some(literal),
+        None => split_quoted_regex(line, line_num)?,
+    };
+    if let Some((regex, action_str)) = quoted {
+        let (action_str, mut modifiers) = split_modifiers(strip_comment(action_str), line_num)?;
+        let action = parse_action(action_str, modifiers.channel.take(), line_num, conditions)?;
+        return Ok(modifiers.rule(regex, action));
+    }
+
+    // The first unescaped space or tab splits regex from action, so `\ ` stays in the regex
+    let Some(split) = separator_index(line) else {
+        return Err(SpecError::at(line_num, SpecErrorKind::InvalidFormat, line));
+    };
+
+    let regex = line[..split].to_string();
+    let action_str = strip_comment(&line[split + 1..]);
+    let (action_str, mut modifiers) = split_modifiers(action_str, line_num)?;
+
+    let action = parse_action(action_str, modifiers.channel.take(), line_num, conditions)?;
+
+    Ok(modifiers.rule(regex, action))
+}
+
+// The regex of an extended rule without its layout: unescaped whitespace is dropped and
+// `#` starts a comment running to the end, except inside brackets, where both are literal
+fn strip_layout(regex: &str) -> String {
+    let mut stripped = String::new();
+    let mut in_class = false;
+    let mut chars = regex.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                stripped.push(ch);
+                stripped.extend(chars.next());
+            }
+            '[' if !in_class => {
+                in_class = true;
+                stripped.push(ch);
+                // A ']' right after '[' or '[^' is a member, as in the regex parser
+                if chars.peek() == Some(&'^') {
+                    stripped.extend(chars.next());
+                }
+                if chars.peek() == Some(&']') {
+                    stripped.extend(chars.next());
+                }
+            }
+            ']' if in_class => {
+                in_class = false;
+                stripped.push(ch);
+            }
+            '#' if !in_class => break,
+            ch if ch.is_whitespace() && !in_class => {}
+            _ => stripped.push(ch),
+        }
+    }
+    stripped
+}
+
+fn separator_index(line: &str) -> Option<usize> {
+    let mut chars = line.char_indices();
+    while let Some((index, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            ' ' | '\t' => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+// A regex wrapped in quotes may contain spaces, e.g. "[ \t]+" (SKIP). The quoted form
+// only applies when the closing quote ends the first token, so unquoted regexes that
+// merely start with a quote (like "[^"]*") keep their old meaning.
+fn split_quoted_regex(line: &str, line_num: usize) -> Result<Option<(String, &str)>, SpecError> {
+    let Some(body) = line.strip_prefix('"') else {
+        return Ok(None);
+    };
+    if body.starts_with(char::is_whitespace) {
+        return Ok(None); // A lone '"' is still a literal quote
+    }
+
+    let mut regex = String::new();
+    let mut chars = body.char_indices();
+    while let Some((index, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                // Keep escapes intact for the regex parser, including \"
+                regex.push(ch);
+                if let Some((_, escaped)) = chars.next() {
+                    regex.push(escaped);
+                }
+            }
+            '"' => {
+                let rest = &body[index + 1..];
+                if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+                    return Ok(None);
+                }
+                if rest.trim().is_empty() {
+                    return Err(SpecError::at(line_num, SpecErrorKind::InvalidFormat, line));
+                }
+                return Ok(Some((regex, rest)));
+            }
+            _ => regex.push(ch),
+        }
+    }
+
+    Err(SpecError::at(line_num, SpecErrorKind::UnterminatedQuote, line))
+}
+
+// A string in backticks is matched verbatim, e.g. `++` INCR false, and becomes a regex
+// with its metacharacters escaped. Like the quoted form it must be the whole first
+// token; a backtick with no such closing one is an ordinary regex character.
+fn split_literal(line: &str, line_num: usize) -> Result<Option<(String, &str)>, SpecError> {
+    let Some(body) = line.strip_prefix('`') else {
+        return Ok(None);
+    };
+
+    let closing = body.match_indices('`').find(|(index, _)| {
+        let rest = &body[index + 1..];
+        rest.is_empty() || rest.starts_with(char::is_whitespace)
+    });
+    let Some((index, _)) = closing else {
+        return Ok(None);
+    };
+
+    let (literal, rest) = (&body[..index], &body[index + 1..]);
+    if literal.is_empty() {
+        return Err(SpecError::at(line_num, SpecErrorKind::EmptyLiteral, line));
+    }
+    if rest.trim().is_empty() {
+        return Err(SpecError::at(line_num, SpecErrorKind::InvalidFormat, line));
+    }
+
+    // Letters, digits and '_' are left alone since escaping them means \t, \d, \_ and so on
+    let mut regex = String::new();
+    for ch in literal.chars() {
+        if !ch.is_alphanumeric() && ch != '_' {
+            regex.push('\\');
+        }
+        regex.push(ch);
+    }
+    Ok(Some((regex, rest)))
+}
+
+// Drops a trailing '#' comment from the action part of a rule. Only a '#' preceded by
+// whitespace and outside a quoted error message starts a comment.
+fn strip_comment(action_str: &str) -> &str {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut prev_whitespace = true; // The action follows the separating space
+
+    for (index, ch) in action_str.char_indices() {
+        match ch {
+            '\\' if in_quotes && !escaped => {
+                escaped = true;
+                prev_whitespace = false;
+                continue;
+            }
+            '"' if !escaped => in_quotes = !in_quotes,
+            '#' if !in_quotes && prev_whitespace => return &action_str[..index],
+            _ => {}
+        }
+        escaped = false;
+        prev_whitespace = ch.is_whitespace();
+    }
+
+    action_str
+}
+
+#[derive(Default)]
+struct Modifiers {
+    priority: i32,
+    case_insensitive: bool,
+    dotall: bool,
+    shortest: bool,
+    extended: bool,
+    channel: Option<String>,
+}
+
+impl Modifiers {
+    fn rule(self, regex: String, action: Action) -> Rule {
+        Rule {
+            regex,
+            action,
+            conditions: vec![0],
+            priority: self.priority,
+            case_insensitive: self.case_insensitive,
+            dotall: self.dotall,
+            shortest: self.shortest,
+            extended: self.extended,
+            line: 0,
+            eof: false,
+        }
+    }
+}
+
+// An action may end in modifiers, in any order: `(PRIO n)` sets the priority, which is
+// 0 otherwise, `(CI)` makes the rule case-insensitive, `(DOTALL)` lets '.' match newline
+// `(X)` ignores layout in the regex, `(SHORTEST)` stops at the first position the rule
+// matches and `(CHANNEL name)` puts the token on a channel
+fn split_modifiers(action_str: &str, line_num: usize) -> Result<(&str, Modifiers), SpecError> {
+    let mut action_str = action_str;
+    let mut modifiers = Modifiers::default();
+
+    loop {
+        action_str = action_str.trim_end();
+        if let Some(rest) = action_str.strip_suffix("(CI)") {
+            modifiers.case_insensitive = true;
+            action_str = rest;
+        } else if let Some(rest) = action_str.strip_suffix("(DOTALL)") {
+            modifiers.dotall = true;
+            action_str = rest;
+        } else if let Some(rest) = action_str.strip_suffix("(SHORTEST)") {
+            modifiers.shortest = true;
+            action_str = rest;
+        } else if let Some(rest) = action_str.strip_suffix("(X)") {
+            modifiers.extended = true;
+            action_str = rest;
+        } else if let Some((rest, value)) = split_priority(action_str, line_num)? {
+            modifiers.priority = value;
+            action_str = rest;
+        } else if let Some((rest, channel)) = split_channel(action_str, line_num)? {
+            modifiers.channel = Some(channel.to_string());
+            action_str = rest;
+        } else {
+            return Ok((action_str, modifiers));
+        }
+    }
+}
+
+fn split_priority(action_str: &str, line_num: usize) -> Result<Option<(&str, i32)>, SpecError> {
+    let Some(start) = action_str.rfind("(PRIO") else {
+        return Ok(None);
+    };
+    let Some(value) = action_str[start + "(PRIO".len()..].strip_suffix(')') else {
+        return Ok(None);
+    };
+
+    match value.trim().parse() {
+        Ok(priority) => Ok(Some((&action_str[..start], priority))),
+        Err(_) => Err(SpecError::at(line_num, SpecErrorKind::InvalidPriority, value.trim())),
+    }
+}
+
+fn split_channel(action_str: &str, line_num: usize) -> Result<Option<(&str, &str)>, SpecError> {
+    let Some(start) = action_str.rfind("(CHANNEL") else {
+        return Ok(None);
+    };
+    let Some(name) = action_str[start + "(CHANNEL".len()..].strip_suffix(')') else {
+        return Ok(None);
+    };
+
+    let name = name.trim();
+    if is_identifier(name) {
+        Ok(Some((&action_str[..start], name)))
+    } else {
+        Err(SpecError::at(line_num, SpecErrorKind::InvalidChannel, name))
+    }
+}
+
+// The action of a rule, with the channel from its modifiers for a token action
+fn parse_action(
+    action_str: &str,
+    channel: Option<String>,
+    line_num: usize,
+    conditions: &[String],
+) -> Result<Action, SpecError> {
+    let action = parse_action_kind(action_str, line_num, conditions)?;
+    match (action, channel) {
+        (Action::Token { name, keep_lexeme, value, .. }, channel) => {
+            let channel = channel.filter(|channel| channel != DEFAULT_CHANNEL);
+            Ok(Action::Token { name, keep_lexeme, value, channel })
+        }
+        (Action::Tokens { tokens, .. }, channel) => {
+            let channel = channel.filter(|channel| channel != DEFAULT_CHANNEL);
+            Ok(Action::Tokens { tokens, channel })
+        }
+        (_, Some(channel)) => Err(SpecError::at(line_num, SpecErrorKind::ChannelWithoutToken, channel)),
+        (action, None) => Ok(action),
+    }
+}
+
+fn parse_action_kind(action_str: &str, line_num: usize, conditions: &[String]) -> Result<Action, SpecError> {
     let action_str = action_str.trim();
 
     if action_str == "(SKIP)" {
-        return Ok(Action::Skip);
+        return Ok(Action::Skip(None));
+    }
+
+    // `(SKIP keep)` keeps the text as a WHITESPACE token, or one named after `keep`
+    if let Some(rest) = action_str.strip_prefix("(SKIP").and_then(|rest| rest.strip_suffix(')')) {
+        return match rest.split_whitespace().collect::<Vec<_>>()[..] {
+            ["keep"] => Ok(Action::Skip(Some("WHITESPACE".to_string()))),
+            ["keep", name] => Ok(Action::Skip(Some(name.to_string()))),
+            _ => Err(SpecError::at(line_num, SpecErrorKind::InvalidAction, action_str)),
+        };
+    }
+
+    if action_str == "(POP)" {
+        return Ok(Action::Pop);
+    }
+
+    for (keyword, switch) in [("(BEGIN", Action::Begin as fn(usize) -> Action), ("(PUSH", Action::Push)] {
+        if let Some(name) = action_str.strip_prefix(keyword).and_then(|rest| rest.strip_suffix(')')) {
+            let name = name.trim();
+            return match conditions.iter().position(|condition| condition == name) {
+                Some(index) => Ok(switch(index)),
+                None => Err(SpecError::at(line_num, SpecErrorKind::UndeclaredState, name)),
+            };
+        }
     }
 
     if action_str.starts_with("(ERR)") {
@@ -60,14 +791,35 @@ fn parse_action(action_str: &str, line_num: usize) -> Result<Action, String> {
             let message = err_part[1..err_part.len() - 1].to_string();
             Ok(Action::Error(message))
         } else {
-            Err(format!("Line {}: Error action must have quoted message", line_num))
+            Err(SpecError::at(line_num, SpecErrorKind::MissingErrorMessage, action_str))
+        }
+    }
+
+    // A quoted string after the name is a fixed value for the lexeme, as in BOOL "1"
+    if let Some((name, value)) = action_str.split_once(char::is_whitespace) {
+        let value = value.trim();
+        if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            let value = Some(value[1..value.len() - 1].to_string());
+            return Ok(Action::Token { name: name.to_string(), keep_lexeme: true, value, channel: None });
         }
     }
 
+    // A comma separated list makes one token per entry, as in NUMBER true, DICE false
+    if action_str.contains(',') && !action_str.contains('"') {
+        let tokens = action_str
+            .split(',')
+            .map(|entry| match parse_action_kind(entry, line_num, conditions)? {
+                Action::Token { name, keep_lexeme, value: None, .. } => Ok((name, keep_lexeme)),
+                _ => Err(SpecError::at(line_num, SpecErrorKind::InvalidAction, entry.trim())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Action::Tokens { tokens, channel: None });
+    }
+
     // Parse token action: <token> <keep>
     let parts: Vec<&str> = action_str.split_whitespace().collect();
     if parts.len() != 2 {
-        return Err(format!("Line {}: Invalid action format", line_num));
+        return Err(SpecError::at(line_num, SpecErrorKind::InvalidAction, action_str));
     }
 
     let token_name = parts[0].to_string();
@@ -76,11 +828,169 @@ fn parse_action(action_str: &str, line_num: usize) -> Result<Action, String> {
     let keep_lexeme = match keep_str {
         "true" => true,
         "false" => false,
-        _ => return Err(format!("Line {}: Keep value must be 'true' or 'false'", line_num)),
+        _ => return Err(SpecError::at(line_num, SpecErrorKind::InvalidKeep, keep_str)),
     };
 
     Ok(Action::Token {
         name: token_name,
         keep_lexeme,
+        value: None,
+        channel: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each rule's regex and action
+    fn rules(spec: &str) -> Vec<(String, Action)> {
+        parse_spec(spec).unwrap().rules.into_iter().map(|rule| (rule.regex, rule.action)).collect()
+    }
+
+    fn token(name: &str, keep_lexeme: bool) -> Action {
+        Action::Token { name: name.to_string(), keep_lexeme, value: None, channel: None }
+    }
+
+    #[test]
+    fn unmatched_directive() {
+        assert_eq!(parse_spec("[a-z]+ WORD true\n").unwrap().unmatched, Unmatched::Skip);
+        assert_eq!(parse_spec("%unmatched report\n[a-z]+ WORD true\n").unwrap().unmatched, Unmatched::Report);
+        let err = parse_spec("%unmatched maybe\n").unwrap_err();
+        assert_eq!(err, SpecError::at(1, SpecErrorKind::InvalidUnmatched, "maybe"));
+        assert_eq!(err.to_string().lines().next(), Some("Line 1: %unmatched must be 'skip', 'report', 'error' or 'recover'"));
+    }
+
+    #[test]
+    fn comments() {
+        let spec = "# numbers\n  #\n[0-9]+ NUM true # trailing\n#\tsee above\n#comment\n";
+        assert_eq!(rules(spec), [("[0-9]+".to_string(), token("NUM", true))]);
+        // An escaped `#` starts a regex, and one after the start without whitespace before
+        // it is part of the regex
+        assert_eq!(rules("\\#[0-9]+ REF true\na#b X false #c\n"), [
+            ("\\#[0-9]+".to_string(), token("REF", true)),
+            ("a#b".to_string(), token("X", false)),
+        ]);
+    }
+
+    #[test]
+    fn quoted_regexes() {
+        assert_eq!(rules("\"[ \\t]+\" (SKIP)\n"), [("[ \\t]+".to_string(), Action::Skip(None))]);
+        assert_eq!(rules("\"a\\\" b\" X true\n"), [("a\\\" b".to_string(), token("X", true))]);
+        // A quote that doesn't close the first token leaves the regex unquoted
+        assert_eq!(rules("\"[^\"]*\" STR true\n\" Q true\n"), [
+            ("\"[^\"]*\"".to_string(), token("STR", true)),
+            ("\"".to_string(), token("Q", true)),
+        ]);
+    }
+
+    #[test]
+    fn unterminated_quote_is_an_error() {
+        let err = parse_spec("a A true\n\"abc X true\n").unwrap_err();
+        assert_eq!(err, SpecError::at(2, SpecErrorKind::UnterminatedQuote, "\"abc X true"));
+        assert_eq!(err.to_string().lines().next(), Some("Line 2: Unterminated quoted regex"));
+        // Escaped, the quote is just the first character of the regex
+        assert_eq!(rules("\\\"abc X true\n"), [("\\\"abc".to_string(), token("X", true))]);
+    }
+
+    #[test]
+    fn macros_expand_in_later_rules() {
+        let spec = parse_spec("LETTER = [a-zA-Z]\nDIGIT = [0-9]\n{LETTER}({LETTER}|{DIGIT})* IDENT true\n\\_+ (SKIP)\n").unwrap();
+        assert_eq!(spec.rules[0].regex, "([a-zA-Z])(([a-zA-Z])|([0-9]))*");
+
+        // The braces of escapes aren't macro references
+        assert_eq!(rules("\\u{E9} E true\n")[0].0, "\\u{E9}");
+        assert_eq!(rules("\\u{E9}\\u{FF} E true\n")[0].0, "\\u{E9}\\u{FF}");
+    }
+
+    #[test]
+    fn cyclic_macros_are_errors() {
+        let err = parse_spec("A = a{B}\nB = b|{A}\n{A} X true\n").unwrap_err();
+        assert_eq!(err, SpecError::at(3, SpecErrorKind::CyclicMacro, "A"));
+        assert_eq!(err.to_string().lines().next(), Some("Line 3: Cyclic macro definition 'A'"));
+    }
+
+    #[test]
+    fn escaped_space_stays_in_the_regex() {
+        assert_eq!(rules("a\\ b TOKEN true\n"), [("a\\ b".to_string(), token("TOKEN", true))]);
+        let spec = parse_spec("a\\ b TOKEN true\n[ab] ONE true\n").unwrap();
+        let tokens = crate::build_dfa(&spec).unwrap().tokenize(&spec, "a ba");
+        let names: Vec<(&str, Option<&str>)> = tokens.iter().map(|token| (token.name.as_str(), token.lexeme.as_deref())).collect();
+        assert_eq!(names, [("TOKEN", Some("a b")), ("ONE", Some("a")), ("EOF", None)]);
+    }
+
+    #[test]
+    fn backtick_literals_match_verbatim() {
+        assert_eq!(rules("`a+b` PLUS true\n"), [("a\\+b".to_string(), token("PLUS", true))]);
+        let spec = parse_spec("`a+b` PLUS true\n[a-z]+ ID true\n").unwrap();
+        let tokens = crate::build_dfa(&spec).unwrap().tokenize(&spec, "a+baab");
+        let names: Vec<(&str, Option<&str>)> = tokens.iter().map(|token| (token.name.as_str(), token.lexeme.as_deref())).collect();
+        assert_eq!(names, [("PLUS", Some("a+b")), ("ID", Some("aab")), ("EOF", None)]);
+    }
+
+    #[test]
+    fn included_rules_come_in_place() {
+        let dir = std::env::temp_dir().join(format!("dragonlex-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(dir.join("shared/keywords.spec"), "if IF false\nwhile WHILE false\n").unwrap();
+        fs::write(dir.join("lang.spec"), "%include \"shared/keywords.spec\"\n[a-z]+ ID true\n").unwrap();
+        let spec = parse_spec_from_path(dir.join("lang.spec")).unwrap();
+        let regexes: Vec<&str> = spec.rules.iter().map(|rule| rule.regex.as_str()).collect();
+        assert_eq!(regexes, ["if", "while", "[a-z]+"]);
+        let names: Vec<String> = crate::build_dfa(&spec).unwrap().tokenize(&spec, "while").into_iter().map(|token| token.name).collect();
+        assert_eq!(names, ["WHILE", "EOF"]);
+
+        // Each file including the other is an error naming the file read a second time
+        fs::write(dir.join("a.spec"), "%include \"b.spec\"\n").unwrap();
+        fs::write(dir.join("b.spec"), "a A true\n%include \"a.spec\"\n").unwrap();
+        let cycle = SpecError::at(2, SpecErrorKind::IncludeCycle, dir.join("a.spec").display().to_string());
+        let err = parse_spec_from_path(dir.join("a.spec")).unwrap_err();
+        assert_eq!(err, SpecError::Included { path: dir.join("b.spec"), error: Box::new(cycle) });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tab_separates_regex_from_action() {
+        assert_eq!(rules("[0-9]+\tNUM true\n"), [("[0-9]+".to_string(), token("NUM", true))]);
+        assert_eq!(rules("[a-z]+ \t  ID\tfalse\n"), [("[a-z]+".to_string(), token("ID", false))]);
+        // A `\t` escape stays in the regex
+        assert_eq!(rules("\\t+\tTAB false\n"), [("\\t+".to_string(), token("TAB", false))]);
+    }
+
+    #[test]
+    fn extended_rules_match_like_their_compact_form() {
+        let extended = parse_spec("\"[a-z]+ [0-9]*  ( - [0-9]+ )?  # name, digits, then a range\" ID true (X)\n\"[ #]+\" (SKIP)\n").unwrap();
+        let compact = parse_spec("[a-z]+[0-9]*(-[0-9]+)? ID true\n\"[ #]+\" (SKIP)\n").unwrap();
+        assert_eq!(extended.rules[0].regex, compact.rules[0].regex);
+        let input = "ab12-34 # c d5";
+        let lexemes = |spec: &Spec| -> Vec<Option<String>> {
+            crate::build_dfa(spec).unwrap().tokenize(spec, input).into_iter().map(|token| token.lexeme).collect()
+        };
+        assert_eq!(lexemes(&extended), lexemes(&compact));
+        assert_eq!(lexemes(&compact), [Some("ab12-34".to_string()), Some("c".to_string()), Some("d5".to_string()), None]);
+    }
+
+    #[test]
+    fn fixed_values_replace_the_lexeme() {
+        let value = |value: &str| Action::Token { name: "BOOL".to_string(), keep_lexeme: true, value: Some(value.to_string()), channel: None };
+        assert_eq!(rules("true BOOL \"1\"\nyes BOOL \"1\"\nfalse BOOL \"0\"\n"), [
+            ("true".to_string(), value("1")),
+            ("yes".to_string(), value("1")),
+            ("false".to_string(), value("0")),
+        ]);
+        let spec = parse_spec("true BOOL \"1\"\nyes BOOL \"1\"\nfalse BOOL \"0\"\n\\_+ (SKIP)\n").unwrap();
+        let tokens = crate::build_dfa(&spec).unwrap().tokenize(&spec, "yes false true");
+        let lexemes: Vec<Option<&str>> = tokens.iter().map(|token| token.lexeme.as_deref()).collect();
+        assert_eq!(lexemes, [Some("1"), Some("0"), Some("1"), None]);
+    }
+
+    #[test]
+    fn braces_in_spec_regexes() {
+        // Braces around a name are only a macro reference when the macro is defined
+        assert_eq!(rules("DIGIT = [0-9]\na{b}{DIGIT} E true\n")[0].0, "a{b}([0-9])");
+        let spec = parse_spec("a{b} E true\na{2} AA true\n{ LBRACE false\n").unwrap();
+        let tokens = crate::build_dfa(&spec).unwrap().tokenize(&spec, "a{b}aa{");
+        let names: Vec<(&str, Option<&str>)> = tokens.iter().map(|token| (token.name.as_str(), token.lexeme.as_deref())).collect();
+        assert_eq!(names, [("E", Some("a{b}")), ("AA", Some("aa")), ("LBRACE", None), ("EOF", None)]);
+    }
+}
@@ -0,0 +1,400 @@
+use std::cmp::Reverse;
+use crate::nfa::{BuildOptions, NFA};
+use crate::regex_parser::{parse_regex, TrailingSplit};
+use crate::spec_parser::{Action, Rule, Spec, Unmatched};
+
+/// A token produced by `DFA::tokenize`, `DFA::token_iter` or `NFA::tokenize`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub name: String,
+    pub lexeme: Option<String>, // Only kept when the rule asks for it; the message for ERROR tokens, the value for fixed-value rules
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize, // Line and column of the last character; the start for EOF
+    pub end_column: usize,
+    pub start: usize, // Byte offsets of the matched text in the input, `end` exclusive
+    pub end: usize,
+    pub channel: Option<String>, // From the rule's `(CHANNEL name)`; `None` is the default channel
+    pub capture: Option<(usize, usize)>, // Byte offsets of the rule's `(?<name>...)` group in the input, when it has one
+}
+
+/// A raw match: the lexeme, its line and column, its byte offset in the input, and the
+/// index of the winning rule
+pub type Match = (String, usize, usize, usize, Option<usize>);
+
+// Longest-match scan shared by the DFA and NFA simulators, run lazily one entry per
+// call to `next`. `longest_match` gets the current start condition, the remaining input
+// and whether it starts at column 1, and returns the match length in bytes. `tables`
+// give the condition each rule switches to and the `<<EOF>>` rule of each condition.
+// Characters no rule matches appear with no rule index, and the scan ends with an empty
+// EOF marker, carrying the `<<EOF>>` rule of the final condition if it has one. With `recover`,
+// which tells whether a character can start a token in a condition, each unmatched run
+// up to such a character is one entry. A tab moves the column to the next multiple of
+// `tab_width`, plus one.
+pub(crate) fn scan<'a, F>(
+    input: &'a str,
+    tab_width: usize,
+    tables: RuleTables,
+    recover: Option<Box<dyn Fn(usize, char) -> bool + 'a>>,
+    longest_match: F,
+) -> Scanner<'a, F>
+where
+    F: Fn(usize, &str, bool) -> (usize, Option<usize>),
+{
+    Scanner { input, tab_width, pos: 0, line: 1, column: 1, condition: 0, stack: Vec::new(), tables, recover, longest_match, done: false }
+}
+
+pub(crate) struct Scanner<'a, F> {
+    input: &'a str,
+    tab_width: usize,
+    pos: usize, // Byte offset of the next character
+    line: usize,
+    column: usize,
+    condition: usize,
+    stack: Vec<usize>, // Conditions to go back to, from `(PUSH ...)`
+    tables: RuleTables,
+    recover: Option<Box<dyn Fn(usize, char) -> bool + 'a>>,
+    longest_match: F,
+    done: bool,
+}
+
+impl<F> Scanner<'_, F> {
+    // Starts the scan at byte offset `pos` of the input instead of its beginning, at the
+    // given line and column. Matching starts over in the first start condition.
+    pub(crate) fn starting_at(mut self, pos: usize, line: usize, column: usize) -> Self {
+        assert!(self.input.is_char_boundary(pos), "offset {} is not at a character boundary", pos);
+        self.pos = pos;
+        self.line = line;
+        self.column = column;
+        self
+    }
+}
+
+impl<F> Iterator for Scanner<'_, F>
+where
+    F: Fn(usize, &str, bool) -> (usize, Option<usize>),
+{
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        let rest = &self.input[self.pos..];
+        let start_line = self.line;
+        let start_column = self.column;
+
+        let Some(first) = rest.chars().next() else {
+            if self.done {
+                return None;
+            }
+            self.done = true;
+            let eof_rule = self.tables.eofs.get(self.condition).copied().flatten();
+            return Some(("".to_string(), start_line, start_column, self.pos, eof_rule)); // EOF marker
+        };
+
+        let (token_length, rule_index) = (self.longest_match)(self.condition, rest, start_column == 1);
+        let (length, rule_index) = if token_length > 0 {
+            match rule_index.and_then(|rule_index| self.tables.switches.get(rule_index).copied().flatten()) {
+                Some(Switch::Begin(next)) => self.condition = next,
+                Some(Switch::Push(next)) => {
+                    self.stack.push(self.condition);
+                    self.condition = next;
+                }
+                // A `(POP)` with nothing pushed stays put; `apply_actions` reports it
+                Some(Switch::Pop) => self.condition = self.stack.pop().unwrap_or(self.condition),
+                None => {}
+            }
+            (token_length, rule_index)
+        } else {
+            // No match found, skip the character and, when recovering, everything up to
+            // the next one that can start a token
+            let mut end = first.len_utf8();
+            if let Some(can_start) = &self.recover {
+                end = rest
+                    .char_indices()
+                    .skip(1)
+                    .find(|&(_, ch)| can_start(self.condition, ch))
+                    .map_or(rest.len(), |(index, _)| index);
+            }
+            (end, None)
+        };
+
+        // Advance the cursor past the lexeme; entries report where they started
+        let lexeme = &rest[..length];
+        let start = self.pos;
+        advance(lexeme, self.tab_width, &mut self.line, &mut self.column);
+        self.pos += length;
+        Some((lexeme.to_string(), start_line, start_column, start, rule_index))
+    }
+}
+
+// Moves `line` and `column` past `consumed`, returning the position of its last character,
+// or the starting one if it is empty
+fn advance(consumed: &str, tab_width: usize, line: &mut usize, column: &mut usize) -> (usize, usize) {
+    let mut last = (*line, *column);
+    for ch in consumed.chars() {
+        last = (*line, *column);
+        if ch == '\n' {
+            *line += 1;
+            *column = 1;
+        } else if ch == '\t' {
+            *column += tab_width - (*column - 1) % tab_width;
+        } else {
+            *column += 1;
+        }
+    }
+    last
+}
+
+// The line and column of the last character of a lexeme found at `line` and `column`
+fn end_position(lexeme: &str, tab_width: usize, mut line: usize, mut column: usize) -> (usize, usize) {
+    advance(lexeme, tab_width, &mut line, &mut column)
+}
+
+// What `simulate` returns: the scan without the unmatched characters
+pub(crate) fn drop_unmatched(matches: impl Iterator<Item = Match>) -> impl Iterator<Item = Match> {
+    matches.filter(|(lexeme, _, _, _, rule_index)| rule_index.is_some() || lexeme.is_empty())
+}
+
+// Picks the winner among rules accepting at the same position: the highest priority,
+// then the earliest rule. Rules missing from `priorities` have priority 0.
+pub(crate) fn best_rule(rules: impl IntoIterator<Item = usize>, priorities: &[i32]) -> Option<usize> {
+    rules
+        .into_iter()
+        .min_by_key(|&rule| (Reverse(priorities.get(rule).copied().unwrap_or(0)), rule))
+}
+
+// The length of the token in a `length` byte match of `input` by `rule`, which leaves out
+// the rule's trailing context if it has one
+pub(crate) fn without_context(input: &str, length: usize, rule: Option<usize>, trailing: &[Option<TrailingSplit>]) -> usize {
+    match rule.and_then(|rule| trailing.get(rule).copied().flatten()) {
+        Some(split) => split.token_length(&input[..length]),
+        None => length,
+    }
+}
+
+// What the scan needs to know about the rules of a spec; empty when simulating without one
+#[derive(Default)]
+pub(crate) struct RuleTables {
+    switches: Vec<Option<Switch>>, // How each rule changes the start condition, if it does
+    eofs: Vec<Option<usize>>, // The `<<EOF>>` rule of each start condition, if any
+}
+
+#[derive(Clone, Copy)]
+enum Switch {
+    Begin(usize),
+    Push(usize),
+    Pop,
+}
+
+impl RuleTables {
+    pub(crate) fn new(spec: &Spec) -> Self {
+        let switches = spec
+            .rules
+            .iter()
+            .map(|rule| match rule.action {
+                Action::Begin(condition) => Some(Switch::Begin(condition)),
+                Action::Push(condition) => Some(Switch::Push(condition)),
+                Action::Pop => Some(Switch::Pop),
+                _ => None,
+            })
+            .collect();
+        Self { switches, eofs: eof_rules(spec) }
+    }
+}
+
+// The first `<<EOF>>` rule active in each start condition
+pub(crate) fn eof_rules(spec: &Spec) -> Vec<Option<usize>> {
+    (0..spec.conditions.len())
+        .map(|condition| {
+            spec.rules
+                .iter()
+                .position(|rule| rule.eof && rule.conditions.contains(&condition))
+        })
+        .collect()
+}
+
+// The tokens for `matches` under the actions of `spec`, as they are consumed. `tab_width`
+// must be the one the matches were scanned with, from the start of the scan so pushes
+// and pops pair up the same way. `bytes` is whether the rules were built over bytes.
+pub(crate) fn apply_actions<'a>(
+    spec: &'a Spec,
+    tab_width: usize,
+    bytes: bool,
+    matches: impl Iterator<Item = Match> + 'a,
+) -> impl Iterator<Item = Token> + 'a {
+    let mut depth = 0; // Conditions pushed and not yet popped
+    let captures: Vec<Option<CaptureMatcher>> = spec.rules.iter().map(|rule| CaptureMatcher::new(rule, bytes)).collect();
+    matches.flat_map(move |(lexeme, line, column, start, rule_index)| {
+        let end = start + lexeme.len();
+        let (end_line, end_column) = end_position(&lexeme, tab_width, line, column);
+        let Some(rule_index) = rule_index else {
+            if lexeme.is_empty() {
+                if !spec.eof_token {
+                    return Vec::new();
+                }
+                return vec![Token { name: "EOF".to_string(), lexeme: None, line, column, end_line, end_column, start, end, channel: None, capture: None }];
+            } else if spec.unmatched == Unmatched::Recover {
+                let message = format!("Unexpected input '{}'", lexeme.escape_debug());
+                return vec![Token { name: "ERROR".to_string(), lexeme: Some(message), line, column, end_line, end_column, start, end, channel: None, capture: None }];
+            } else if spec.unmatched != Unmatched::Skip {
+                // Without a stderr to report to, both modes give an ERROR token
+                let message = format!("Unexpected character '{}'", lexeme.escape_debug());
+                return vec![Token { name: "ERROR".to_string(), lexeme: Some(message), line, column, end_line, end_column, start, end, channel: None, capture: None }];
+            }
+            return Vec::new();
+        };
+
+        let capture = captures[rule_index]
+            .as_ref()
+            .and_then(|matcher| matcher.find(&lexeme))
+            .map(|(group_start, group_end)| (start + group_start, start + group_end));
+        match &spec.rules[rule_index].action {
+            Action::Skip(None) | Action::Begin(_) => Vec::new(),
+            Action::Push(_) => {
+                depth += 1;
+                Vec::new()
+            }
+            Action::Pop if depth > 0 => {
+                depth -= 1;
+                Vec::new()
+            }
+            Action::Pop => {
+                let message = "(POP) with no state to return to".to_string();
+                vec![Token { name: "ERROR".to_string(), lexeme: Some(message), line, column, end_line, end_column, start, end, channel: None, capture }]
+            }
+            Action::Skip(Some(name)) => {
+                vec![Token { name: name.clone(), lexeme: Some(lexeme), line, column, end_line, end_column, start, end, channel: None, capture }]
+            }
+            Action::Error(message) => vec![Token { name: "ERROR".to_string(), lexeme: Some(message.clone()), line, column, end_line, end_column, start, end, channel: None, capture }],
+            Action::Token { name, keep_lexeme, value, channel } => {
+                let lexeme = value.clone().or(keep_lexeme.then_some(lexeme));
+                let channel = channel.clone();
+                vec![Token { name: name.clone(), lexeme, line, column, end_line, end_column, start, end, channel, capture }]
+            }
+            // Every token of the list spans the whole match
+            Action::Tokens { tokens, channel } => tokens
+                .iter()
+                .map(|(name, keep_lexeme)| {
+                    let lexeme = keep_lexeme.then(|| lexeme.clone());
+                    Token { name: name.clone(), lexeme, line, column, end_line, end_column, start, end, channel: channel.clone(), capture }
+                })
+                .collect(),
+        }
+    })
+}
+
+// Finds where a rule's capture group matched within its lexeme. The DFA only knows where
+// the whole match ends, so the lexeme is matched again against the parts of the regex
+// before the group, the group, and the part after it.
+struct CaptureMatcher {
+    before: NFA,
+    group: NFA,
+    after: NFA,
+}
+
+impl CaptureMatcher {
+    fn new(rule: &Rule, bytes: bool) -> Option<Self> {
+        // Most rules have no group, and are not worth parsing again to find out
+        if rule.eof || !rule.regex.contains("(?<") {
+            return None;
+        }
+        let regex = parse_regex(&rule.regex).ok()?;
+        let (before, group, after) = regex.split_capture()?;
+        let options = BuildOptions { case_insensitive: rule.case_insensitive, dotall: rule.dotall, shortest: false, bytes };
+        Some(Self {
+            before: NFA::from_regex_with(&before, options),
+            group: NFA::from_regex_with(group, options),
+            after: NFA::from_regex_with(&after, options),
+        })
+    }
+
+    // The byte offsets of the group in `lexeme`, preferring the longest part before it and
+    // then the longest group, as greedy quantifiers would
+    fn find(&self, lexeme: &str) -> Option<(usize, usize)> {
+        for &start in self.before.match_ends(lexeme).iter().rev() {
+            for &length in self.group.match_ends(&lexeme[start..]).iter().rev() {
+                let end = start + length;
+                if self.after.match_ends(&lexeme[end..]).last() == Some(&(lexeme.len() - end)) {
+                    return Some((start, end));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{build_dfa, parse_spec};
+
+    // Each token's name, lexeme and position
+    fn tokens(spec: &str, input: &str) -> Vec<(String, Option<String>, usize, usize)> {
+        let spec = parse_spec(spec).unwrap();
+        build_dfa(&spec)
+            .unwrap()
+            .tokenize(&spec, input)
+            .into_iter()
+            .map(|token| (token.name, token.lexeme, token.line, token.column))
+            .collect()
+    }
+
+    fn token(name: &str, lexeme: Option<&str>, line: usize, column: usize) -> (String, Option<String>, usize, usize) {
+        (name.to_string(), lexeme.map(str::to_string), line, column)
+    }
+
+    #[test]
+    fn unmatched_character_is_an_error_token() {
+        let spec = "%unmatched error\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+        assert_eq!(tokens(spec, "ab@cd"), [
+            token("ID", Some("ab"), 1, 1),
+            token("ERROR", Some("Unexpected character '@'"), 1, 3),
+            token("ID", Some("cd"), 1, 4),
+            token("EOF", None, 1, 6),
+        ]);
+    }
+
+    #[test]
+    fn recovery_skips_a_run_of_bad_characters_at_once() {
+        let spec = "%unmatched recover\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+        // The space after the run can start a token, so it ends the run
+        assert_eq!(tokens(spec, "ab @#$% cd"), [
+            token("ID", Some("ab"), 1, 1),
+            token("ERROR", Some("Unexpected input '@#$%'"), 1, 4),
+            token("ID", Some("cd"), 1, 9),
+            token("EOF", None, 1, 11),
+        ]);
+    }
+
+    #[test]
+    fn eof_rule_names_the_last_token() {
+        let spec = "[a-z]+ ID true\n\\_+ (SKIP)\n<<EOF>> ENDMARK true\n";
+        assert_eq!(tokens(spec, "ab c"), [
+            token("ID", Some("ab"), 1, 1),
+            token("ID", Some("c"), 1, 4),
+            token("ENDMARK", Some(""), 1, 5),
+        ]);
+    }
+
+    #[test]
+    fn one_rule_emits_a_list_of_tokens() {
+        let spec = "[0-9]+d[0-9]+ NUMBER true, DICE false, NUMBER true\n\\_+ (SKIP)\n";
+        // All three span the whole match
+        assert_eq!(tokens(spec, "  3d6"), [
+            token("NUMBER", Some("3d6"), 1, 3),
+            token("DICE", None, 1, 3),
+            token("NUMBER", Some("3d6"), 1, 3),
+            token("EOF", None, 1, 6),
+        ]);
+    }
+
+    #[test]
+    fn kept_skips_come_out_as_tokens() {
+        let spec = "[a-z]+ ID true\n\\_+ (SKIP keep)\n\\n (SKIP keep NEWLINE)\n";
+        assert_eq!(tokens(spec, "a  b\n"), [
+            token("ID", Some("a"), 1, 1),
+            token("WHITESPACE", Some("  "), 1, 2),
+            token("ID", Some("b"), 1, 4),
+            token("NEWLINE", Some("\n"), 1, 5),
+            token("EOF", None, 2, 1),
+        ]);
+    }
+}
@@ -0,0 +1,609 @@
+// Generates lexers for a small spec, builds them with rustc and checks what they do
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use dragonlex::{build_dfa, generate_into, generate_lexer, generate_source, normalize_input, parse_spec, record_kind_names, run_generated_lexer, Backend, GeneratedLexer, GeneratorOptions, OutputKind, Spec, TokenFormat};
+
+const SPEC: &str = "dog NOUN true\nbites VERB true\n[a-z]+ WORD true\n[\\_\\n]+ (SKIP)\n";
+
+fn spec() -> Spec {
+    parse_spec(SPEC).unwrap()
+}
+
+// An empty directory of its own for each test, since they run in parallel
+fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dragonlex-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Runs rustc with `args` in `dir`, which is also its `OUT_DIR`, failing the test with its errors
+fn rustc(args: &[&str], dir: &Path) {
+    let output = Command::new("rustc").args(args).current_dir(dir).env("OUT_DIR", dir).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// Generates and compiles a binary lexer for `spec` and runs it on `input`, giving its stdout and stderr
+fn run_lexer(spec: &str, test: &str, input: &str) -> (String, String) {
+    run_lexer_with(spec, test, input, GeneratorOptions::default())
+}
+
+// Like `run_lexer`, generating with `options` but for the source path
+fn run_lexer_with(spec: &str, test: &str, input: &str, options: GeneratorOptions) -> (String, String) {
+    let dir = temp_dir(test);
+    let options = GeneratorOptions { source_path: dir.join("lexer.rs"), ..options };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&parse_spec(spec).unwrap(), &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+    fs::write(dir.join("input.txt"), input).unwrap();
+    let output = Command::new(output).arg(dir.join("input.txt")).output().unwrap();
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+// Runs the dragonlex CLI with `args` in a fresh directory holding `spec` as words.spec,
+// giving its stdout and stderr and the directory
+fn run_cli(spec: &str, test: &str, args: &[&str]) -> (String, String, PathBuf) {
+    let dir = temp_dir(test);
+    fs::write(dir.join("words.spec"), spec).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_dragonlex")).args(args).arg("words.spec").current_dir(&dir).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap(), dir)
+}
+
+#[test]
+fn no_compile_only_writes_the_source() {
+    // A PATH without rustc on it would fail any attempt to compile
+    let dir = temp_dir("no-compile");
+    fs::write(dir.join("words.spec"), SPEC).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_dragonlex"))
+        .args(["--no-compile", "-o", "out/words.rs", "words.spec"])
+        .current_dir(&dir)
+        .env("PATH", "")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Lexer source written to out/words.rs (compilation skipped)\n");
+    assert!(dir.join("out/words.rs").exists());
+    assert!(!dir.join("out/words").exists());
+
+    let options = GeneratorOptions { source_path: dir.join("lib.rs"), compile: false, ..GeneratorOptions::default() };
+    assert_eq!(generate_lexer(&spec(), &options).unwrap(), GeneratedLexer::SourceOnly(dir.join("lib.rs")));
+    assert!(dir.join("lib.rs").exists());
+}
+
+#[test]
+fn skipped_newlines_are_not_reported() {
+    // Any character no rule matched would be reported on stderr
+    let spec = "%unmatched report\n[a-z]+ WORD true\n\\n (SKIP)\n\\t (SKIP)\n\\_ (SKIP)\n";
+    let (stdout, stderr) = run_lexer(spec, "newlines", "ab\ncd\n\tef\n\n");
+    assert_eq!(stderr, "");
+    assert_eq!(stdout, "WORD:ab [1,1-1,2] @0-2\nWORD:cd [2,1-2,2] @3-5\nWORD:ef [3,2-3,3] @7-9\nEOF [5,1-5,1] @11-11\n");
+}
+
+#[test]
+fn generated_lexer_reports_stray_characters() {
+    let spec = "%unmatched report\n[a-z]+ WORD true\n\\_ (SKIP)\n";
+    let input = "ab \u{1} c\u{fc}d";
+    let (stdout, stderr) = run_lexer(spec, "stray", input);
+    // Each is reported with its position, escaped if it can't be printed, and lexing goes on
+    assert_eq!(stderr, "Unexpected character '\\u{1}' [1,4]\nUnexpected character '\u{fc}' [1,7]\n");
+    assert_eq!(stdout, "WORD:ab [1,1-1,2] @0-2\nWORD:c [1,6-1,6] @5-6\nWORD:d [1,8-1,8] @8-9\nEOF [1,9-1,9] @9-9\n");
+    // Skipped by default
+    let (_, stderr) = run_lexer("[a-z]+ WORD true\n\\_ (SKIP)\n", "stray-skip", input);
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn in_process_tokens_match_the_generated_lexer() {
+    let input = "dog bites\nthe cat";
+    let (stdout, _) = run_lexer(SPEC, "in-process", input);
+    let spec = spec();
+    // The text format, written out from the tokens of the DFA
+    let printed: String = build_dfa(&spec)
+        .unwrap()
+        .tokenize(&spec, input)
+        .into_iter()
+        .map(|token| match token.lexeme {
+            Some(lexeme) => format!("{}:{} [{},{}-{},{}] @{}-{}\n", token.name, lexeme, token.line, token.column, token.end_line, token.end_column, token.start, token.end),
+            None => format!("{} [{},{}-{},{}] @{}-{}\n", token.name, token.line, token.column, token.end_line, token.end_column, token.start, token.end),
+        })
+        .collect();
+    assert_eq!(printed, stdout);
+}
+
+#[test]
+fn json_lines_escape_lexemes() {
+    let spec = "\\\"[^\\\"\\n]*\\\" STR true\n; SEMI false\n[\\_\\n]+ (SKIP)\n";
+    // Quotes and a backslash need escaping
+    let input = "\"a\\b\";\n";
+    let options = GeneratorOptions { format: TokenFormat::Json, ..GeneratorOptions::default() };
+    let (stdout, _) = run_lexer_with(spec, "json", input, options);
+    assert_eq!(
+        stdout,
+        concat!(
+            r#"{"kind":"STR","lexeme":"\"a\\b\"","line":1,"column":1,"end_line":1,"end_column":5,"start":0,"end":5}"#,
+            "\n",
+            r#"{"kind":"SEMI","lexeme":null,"line":1,"column":6,"end_line":1,"end_column":6,"start":5,"end":6}"#,
+            "\n",
+            r#"{"kind":"EOF","lexeme":null,"line":2,"column":1,"end_line":2,"end_column":1,"start":7,"end":7}"#,
+            "\n",
+        )
+    );
+}
+
+#[test]
+fn higher_priority_keyword_beats_an_earlier_rule() {
+    let spec = "[a-z]+ ID true\nif IF false (PRIO 1)\n\\_+ (SKIP)\n";
+    let (stdout, _) = run_lexer(spec, "priority", "if iff");
+    assert_eq!(stdout, "IF [1,1-1,2] @0-2\nID:iff [1,4-1,6] @3-6\nEOF [1,7-1,7] @6-6\n");
+}
+
+#[test]
+fn token_kinds_are_an_enum() {
+    let dir = temp_dir("kinds");
+    let spec = parse_spec("dog NOUN true\n[a-z]+-[a-z]+ foo-bar true\n[a-z]+ WORD true\n\\_+ (SKIP)\n").unwrap();
+    let options = GeneratorOptions { output: OutputKind::Library, source_path: dir.join("lexer.rs"), compile: false, ..GeneratorOptions::default() };
+    generate_lexer(&spec, &options).unwrap();
+    let main = r#"mod lexer { include!(concat!(env!("OUT_DIR"), "/lexer.rs")); }
+
+use lexer::TokenKind;
+
+fn main() {
+    for token in lexer::tokenize("dog ab-cd cat @") {
+        let role = match token.kind {
+            TokenKind::NOUN => "noun",
+            TokenKind::foo_bar => "pair",
+            TokenKind::WORD => "word",
+            TokenKind::Error => "error",
+            TokenKind::Eof => "end",
+        };
+        println!("{} {}", token.kind, role);
+    }
+}
+"#;
+    fs::write(dir.join("main.rs"), main).unwrap();
+    rustc(&["--edition", "2024", "main.rs", "-o", "main"], &dir);
+    let output = Command::new(dir.join("main")).output().unwrap();
+    // Display gives the names as written in the spec
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "NOUN noun\nfoo-bar pair\nWORD word\nEOF end\n");
+}
+
+#[test]
+fn binary_keeps_token_names_with_backslashes() {
+    let spec = "a A\\B true\nb A\\B \"x\"\nc+ C\\D false, A\\B true\n\\_+ (SKIP)\n";
+    let (stdout, _) = run_lexer(spec, "backslash_names", "a b cc");
+    assert_eq!(stdout, "A\\B:a [1,1-1,1] @0-1\nA\\B:x [1,3-1,3] @2-3\nC\\D [1,5-1,6] @4-6\nA\\B:cc [1,5-1,6] @4-6\nEOF [1,7-1,7] @6-6\n");
+}
+
+#[test]
+fn generated_lexer_reads_stdin() {
+    let dir = temp_dir("stdin");
+    let options = GeneratorOptions { source_path: dir.join("lexer.rs"), ..GeneratorOptions::default() };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&spec(), &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+    let input = "dog bites\ncat\n";
+    fs::write(dir.join("input.txt"), input).unwrap();
+    let from_file = Command::new(&output).arg(dir.join("input.txt")).output().unwrap();
+    let from_file = String::from_utf8_lossy(&from_file.stdout);
+
+    // With no argument and with `-`
+    for args in [&[][..], &["-"][..]] {
+        let mut child = Command::new(&output).args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let result = child.wait_with_output().unwrap();
+        assert!(result.status.success());
+        assert_eq!(String::from_utf8_lossy(&result.stdout), from_file);
+    }
+
+    let dfa = build_dfa(&spec()).unwrap();
+    assert_eq!(dfa.simulate_reader(input.as_bytes()).unwrap(), dfa.simulate(input));
+}
+
+#[test]
+fn generated_lexer_recovers_past_a_run_of_bad_characters() {
+    let (stdout, _) = run_lexer("%unmatched recover\n[a-z]+ ID true\n\\_+ (SKIP)\n", "recover", "ab @#$% cd");
+    assert_eq!(stdout, "ID:ab [1,1-1,2] @0-2\nERROR:@#$% [1,4-1,7] @3-7\nID:cd [1,9-1,10] @8-10\nEOF [1,11-1,11] @10-10\n");
+}
+
+#[test]
+fn token_iterators_stop_after_eof() {
+    let spec = spec();
+    let input = "dog bites\ncat";
+    let dfa = build_dfa(&spec).unwrap();
+    let mut tokens = dfa.token_iter(&spec, input);
+    let iterated: Vec<_> = tokens.by_ref().collect();
+    assert_eq!(tokens.next(), None);
+    assert_eq!(iterated, dfa.tokenize(&spec, input));
+    // One token for each match but the skipped ones, at the same offsets
+    let starts: Vec<usize> = dfa.simulate(input).into_iter().filter(|&(_, _, _, _, rule)| rule != Some(3)).map(|(_, _, _, start, _)| start).collect();
+    assert_eq!(iterated.iter().map(|token| token.start).collect::<Vec<_>>(), starts);
+
+    let dir = temp_dir("iter");
+    let options = GeneratorOptions { output: OutputKind::Library, source_path: dir.join("lexer.rs"), compile: false, ..GeneratorOptions::default() };
+    generate_lexer(&spec, &options).unwrap();
+    let main = r#"mod lexer { include!(concat!(env!("OUT_DIR"), "/lexer.rs")); }
+
+fn main() {
+    let mut tokens = lexer::tokens("dog bites\ncat");
+    let iterated: Vec<lexer::Token> = tokens.by_ref().collect();
+    assert!(tokens.next().is_none());
+    assert_eq!(iterated, lexer::tokenize("dog bites\ncat"));
+    println!("{}", iterated.len());
+}
+"#;
+    fs::write(dir.join("main.rs"), main).unwrap();
+    rustc(&["--edition", "2024", "main.rs", "-o", "main"], &dir);
+    let output = Command::new(dir.join("main")).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "4\n");
+}
+
+#[test]
+fn generated_lexer_moves_tabs_to_the_next_stop() {
+    let spec = "[a-z]+ ID true\n[\\t\\_]+ (SKIP)\n";
+    let input = "\tab c\td";
+    let options = GeneratorOptions { tab_width: 4, ..GeneratorOptions::default() };
+    let (stdout, _) = run_lexer_with(spec, "tabs", input, options);
+    assert_eq!(stdout, "ID:ab [1,5-1,6] @1-3\nID:c [1,8-1,8] @4-5\nID:d [1,13-1,13] @6-7\nEOF [1,14-1,14] @7-7\n");
+
+    // The same columns as lexing in-process
+    let spec = parse_spec(spec).unwrap();
+    let columns: Vec<usize> = build_dfa(&spec).unwrap().tokenize_with(&spec, input, 4).into_iter().map(|token| token.column).collect();
+    assert_eq!(columns, [5, 8, 13, 14]);
+}
+
+#[test]
+fn generated_lexer_skips_empty_matches() {
+    // Ties go to the rule listed first, as in `DFA::tokenize`
+    let (stdout, _) = run_lexer("a* A true\nab X true\n[a-z]+ Y true\n", "empty", "ab aa-");
+    assert_eq!(stdout, "X:ab [1,1-1,2] @0-2\nA:aa [1,4-1,5] @3-5\nEOF [1,7-1,7] @6-6\n");
+}
+
+#[test]
+fn match_backend_lexes_like_the_table() {
+    let spec = "if IF false\n[a-z\u{e0}-\u{ff}][a-z\u{e0}-\u{ff}0-9]* ID true\n[0-9]+ NUM true\n^\\#.* COMMENT true\n[\\_\\n]+ (SKIP)\n";
+    let input = "if café 42 x9\n# note\nabcd ifs @ ü\n";
+    let (table, _) = run_lexer(spec, "table-backend", input);
+    let dir = temp_dir("match-source");
+    let options = GeneratorOptions { backend: Backend::Match, source_path: dir.join("lexer.rs"), compile: false, ..GeneratorOptions::default() };
+    generate_lexer(&parse_spec(spec).unwrap(), &options).unwrap();
+    assert!(fs::read_to_string(dir.join("lexer.rs")).unwrap().contains("fn next_state("));
+    let options = GeneratorOptions { backend: Backend::Match, ..GeneratorOptions::default() };
+    let (matched, _) = run_lexer_with(spec, "match-backend", input, options);
+    assert_eq!(matched, table);
+    assert!(table.starts_with("IF [1,1-1,2] @0-2\nID:café [1,4-1,7] @3-8\n"), "{}", table);
+}
+
+#[test]
+fn stats_count_tokens_by_name() {
+    let dir = temp_dir("stats");
+    fs::write(dir.join("words.spec"), "%unmatched error\n".to_string() + SPEC).unwrap();
+    fs::write(dir.join("input.txt"), "dog bites cat\ndog 1 man dog\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_dragonlex"))
+        .args(["--stats", "input.txt", "words.spec"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // Sorted by name, with the error in the total and EOF left out
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "NOUN 3\nVERB 1\nWORD 2\ntotal 7\nerrors 1\n");
+    assert!(!dir.join("lexer.rs").exists());
+}
+
+#[test]
+fn no_eof_leaves_out_the_eof_line() {
+    let spec = "%no-eof\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+    let (stdout, _) = run_lexer(spec, "no-eof", "ab c");
+    assert_eq!(stdout, "ID:ab [1,1-1,2] @0-2\nID:c [1,4-1,4] @3-4\n");
+
+    let spec = parse_spec(spec).unwrap();
+    let names: Vec<String> = build_dfa(&spec).unwrap().tokenize(&spec, "ab c").into_iter().map(|token| token.name).collect();
+    assert_eq!(names, ["ID", "ID"]);
+}
+
+#[test]
+fn generated_into_chosen_path() {
+    let dir = temp_dir("path");
+    let source = dir.join("nested/dir/words.rs");
+    let options = GeneratorOptions { source_path: source.clone(), ..GeneratorOptions::default() };
+    let generated = generate_lexer(&spec(), &options).unwrap();
+    assert_eq!(generated, GeneratedLexer::Compiled { source: source.clone(), output: dir.join("nested/dir/words") });
+    assert!(source.exists());
+    assert!(dir.join("nested/dir/words").exists());
+
+    // The CLI takes the path with `-o`
+    fs::write(dir.join("words.spec"), SPEC).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_dragonlex"))
+        .args(["-o", "out/cli.rs", "words.spec"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(dir.join("out/cli.rs").exists());
+    assert!(dir.join("out/cli").exists());
+    assert!(!dir.join("lexer.rs").exists());
+}
+
+#[test]
+fn crlf_input_lexes_like_lf() {
+    let lf = "dog bites\ncat\n\ndog";
+    let crlf = "\u{feff}dog bites\r\ncat\r\n\rdog";
+    assert_eq!(normalize_input(crlf), lf);
+
+    let dfa = build_dfa(&spec()).unwrap();
+    let positions = |input: &str| {
+        let tokens = dfa.tokenize(&spec(), &normalize_input(input));
+        tokens.into_iter().map(|token| (token.name, token.line, token.column, token.end_line, token.end_column)).collect::<Vec<_>>()
+    };
+    assert_eq!(positions(crlf), positions(lf));
+
+    // The generated lexer reads its input the same way
+    let dir = temp_dir("crlf");
+    let options = GeneratorOptions { source_path: dir.join("lexer.rs"), ..GeneratorOptions::default() };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&spec(), &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+    fs::write(dir.join("lf.txt"), lf).unwrap();
+    fs::write(dir.join("crlf.txt"), crlf).unwrap();
+    let (from_lf, _) = run_generated_lexer(&output, dir.join("lf.txt")).unwrap();
+    let (from_crlf, _) = run_generated_lexer(&output, dir.join("crlf.txt")).unwrap();
+    assert_eq!(from_crlf, from_lf);
+    assert!(from_crlf.contains("WORD:cat [2,1-2,3]") && from_crlf.contains("NOUN:dog [4,1-4,3]"), "{}", from_crlf);
+}
+
+#[test]
+fn generated_lexer_spans_lines() {
+    let (stdout, _) = run_lexer("\\/\\*([^*]|\\n)*\\*\\/ COMMENT true\n[a-z]+ WORD true\n[\\_\\n]+ (SKIP)\n", "spans", "ab /* c\nd */\n");
+    // The comment ends on the line after it starts
+    assert_eq!(stdout, "WORD:ab [1,1-1,2] @0-2\nCOMMENT:/* c\nd */ [1,4-2,4] @3-12\nEOF [3,1-3,1] @13-13\n");
+}
+
+#[test]
+fn hidden_channel_tokens_stand_apart() {
+    let spec = "\\/\\/.* COMMENT true (CHANNEL hidden)\n[a-z]+ ID true\n[\\_\\n]+ (SKIP)\n";
+    let input = "a // b\nc";
+    let (stdout, _) = run_lexer(spec, "channels", input);
+    assert_eq!(stdout, "ID:a [1,1-1,1] @0-1\nCOMMENT:// b [1,3-1,6] @2-6 (hidden)\nID:c [2,1-2,1] @7-8\nEOF [2,2-2,2] @8-8\n");
+
+    let spec = parse_spec(spec).unwrap();
+    let channels: Vec<Option<String>> = build_dfa(&spec).unwrap().tokenize(&spec, input).into_iter().map(|token| token.channel).collect();
+    assert_eq!(channels, [None, Some("hidden".to_string()), None, None]);
+}
+
+#[test]
+fn generated_lexer_starts_at_a_given_position() {
+    let dir = temp_dir("start");
+    let options = GeneratorOptions { source_path: dir.join("lexer.rs"), ..GeneratorOptions::default() };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&spec(), &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+    fs::write(dir.join("input.txt"), "dog\ncat").unwrap();
+    let result = Command::new(&output).args(["--start-line", "10", "--start-column", "5", "input.txt"]).current_dir(&dir).output().unwrap();
+    // Only the first line is offset by the starting column
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "NOUN:dog [10,5-10,7] @0-3\nWORD:cat [11,1-11,3] @4-7\nEOF [11,4-11,4] @7-7\n");
+}
+
+// An unsigned LEB128 varint at `pos` of `bytes`, moving `pos` past it
+fn read_varint(bytes: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= usize::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[test]
+fn records_decode_to_the_tokens() {
+    let spec = parse_spec("dog NOUN false\nbites VERB true\n[a-z]+ WORD true (CHANNEL hidden)\n[\\_\\n]+ (SKIP)\n").unwrap();
+    let dir = temp_dir("records");
+    let options = GeneratorOptions { format: TokenFormat::Records, source_path: dir.join("lexer.rs"), ..GeneratorOptions::default() };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&spec, &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+    // Long enough for offsets that take two bytes
+    let input = "dog bites cat\n".repeat(10);
+    fs::write(dir.join("input.txt"), &input).unwrap();
+    let bytes = Command::new(&output).arg(dir.join("input.txt")).output().unwrap().stdout;
+
+    let kinds = record_kind_names(&spec);
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let length = read_varint(&bytes, &mut pos);
+        let end = pos + length;
+        let kind = kinds[read_varint(&bytes, &mut pos)].clone();
+        let span: Vec<usize> = (0..6).map(|_| read_varint(&bytes, &mut pos)).collect();
+        let mut text = || match read_varint(&bytes, &mut pos) {
+            0 => None,
+            length => {
+                pos += length - 1;
+                Some(String::from_utf8(bytes[pos - (length - 1)..pos].to_vec()).unwrap())
+            }
+        };
+        let (lexeme, channel) = (text(), text());
+        assert_eq!(pos, end);
+        decoded.push((kind, lexeme, span, channel));
+    }
+
+    let expected: Vec<_> = build_dfa(&spec)
+        .unwrap()
+        .tokenize(&spec, &input)
+        .into_iter()
+        .map(|token| (token.name, token.lexeme, vec![token.line, token.column, token.end_line, token.end_column, token.start, token.end], token.channel))
+        .collect();
+    assert_eq!(decoded, expected);
+    assert_eq!(decoded[1], ("VERB".to_string(), Some("bites".to_string()), vec![1, 5, 1, 9, 4, 9], None));
+    // The last `cat` starts at a byte offset that needs a two-byte varint
+    assert_eq!(decoded[29].2[4], 136);
+}
+
+#[test]
+fn generated_lexer_takes_shortest_match() {
+    let input = "\"a\" x \"b\"";
+    let (longest, _) = run_lexer("\\\".*\\\" STR true\n[a-z] CHAR true\n\\_ (SKIP)\n", "longest", input);
+    assert_eq!(longest, "STR:\"a\" x \"b\" [1,1-1,9] @0-9\nEOF [1,10-1,10] @9-9\n");
+    let (shortest, _) = run_lexer("\\\".*\\\" STR true (SHORTEST)\n[a-z] CHAR true\n\\_ (SKIP)\n", "shortest", input);
+    // Each string stops at its own closing quote
+    assert_eq!(shortest, "STR:\"a\" [1,1-1,3] @0-3\nCHAR:x [1,5-1,5] @4-5\nSTR:\"b\" [1,7-1,9] @6-9\nEOF [1,10-1,10] @9-9\n");
+}
+
+#[test]
+fn timing_prints_every_phase() {
+    let (stdout, stderr, _) = run_cli(SPEC, "timing", &["--timing"]);
+    assert_eq!(stdout, "Lexer generated successfully\n");
+    let phases: Vec<&str> = stderr.lines().map(|line| line.split("  ").next().unwrap().trim_end()).collect();
+    assert_eq!(phases, [
+        "parse spec",
+        "check rules",
+        "parse regexes",
+        "build NFAs",
+        "subset construction",
+        "minimize",
+        "generate code",
+        "write source",
+        "rustc",
+        "total",
+    ]);
+    assert!(stderr.lines().all(|line| line.ends_with(" ms")), "{}", stderr);
+}
+#[test]
+fn generated_source_compiles() {
+    let options = GeneratorOptions { output: OutputKind::Library, ..GeneratorOptions::default() };
+    let source = generate_source(&spec(), &options).unwrap();
+    assert!(source.contains("pub fn tokenize(input: &str) -> Vec<Token>"));
+
+    let dir = temp_dir("source");
+    fs::write(dir.join("lexer.rs"), &source).unwrap();
+    rustc(&["--crate-type", "lib", "lexer.rs"], &dir);
+    assert!(dir.join("liblexer.rlib").exists());
+}
+
+#[test]
+fn generated_lexer_runs() {
+    let dir = temp_dir("run");
+    let options = GeneratorOptions { source_path: dir.join("lexer.rs"), ..GeneratorOptions::default() };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&spec(), &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+    fs::write(dir.join("input.txt"), "dog bites cat\n").unwrap();
+
+    let (stdout, stderr) = run_generated_lexer(&output, dir.join("input.txt")).unwrap();
+    let expected = "NOUN:dog [1,1-1,3] @0-3\nVERB:bites [1,5-1,9] @4-9\nWORD:cat [1,11-1,13] @10-13\nEOF [2,1-2,1] @14-14\n";
+    assert_eq!(stdout, expected);
+    assert_eq!(stderr, "");
+
+    assert!(run_generated_lexer(dir.join("missing"), dir.join("input.txt")).is_err());
+}
+
+#[test]
+fn generated_lexer_reports_invalid_utf8() {
+    let dir = temp_dir("utf8");
+    let options = GeneratorOptions { source_path: dir.join("lexer.rs"), ..GeneratorOptions::default() };
+    let GeneratedLexer::Compiled { output, .. } = generate_lexer(&spec(), &options).unwrap() else {
+        panic!("the lexer was not compiled");
+    };
+
+    // Bytes that aren't UTF-8 on stdin are an error reading the input, not a panic
+    let mut child = Command::new(&output).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"dog \xff\xfe\n").unwrap();
+    let result = child.wait_with_output().unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.starts_with("Error reading input: "), "{}", stderr);
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn generated_into_out_dir() {
+    let out_dir = temp_dir("out-dir");
+    generate_into(&spec(), &out_dir.join("lexer.rs")).unwrap();
+
+    // What a crate whose build script called `generate_into` would have
+    let main = r#"mod lexer { include!(concat!(env!("OUT_DIR"), "/lexer.rs")); }
+
+fn main() {
+    for token in lexer::tokenize("dog cat") {
+        println!("{:?} {:?}", token.kind, token.lexeme);
+    }
+}
+"#;
+    fs::write(out_dir.join("main.rs"), main).unwrap();
+    rustc(&["--edition", "2024", "main.rs", "-o", "main"], &out_dir);
+
+    let output = Command::new(out_dir.join("main")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "NOUN Some(\"dog\")\nWORD Some(\"cat\")\nEof None\n");
+}
+
+#[test]
+fn no_std_library_compiles() {
+    // The second spec needs `format!`, the state stack and the queue of pending tokens
+    let busy = "%unmatched error\n%state C\n\\/\\* (PUSH C)\n<C>\\*\\/ (POP)\n<C>[^*]+|\\* (SKIP)\n[0-9]+d[0-9]+ NUMBER true, DICE false, NUMBER true\n[a-z]+ WORD true (CHANNEL hidden)\n<<EOF>> END false\n";
+    for (test, spec) in [("no-std", SPEC), ("no-std-busy", busy)] {
+        let options = GeneratorOptions { output: OutputKind::Library, no_std: true, ..GeneratorOptions::default() };
+        let source = generate_source(&parse_spec(spec).unwrap(), &options).unwrap();
+        assert!(source.starts_with("#![no_std]") && !source.contains("std::"), "{}", source);
+
+        let dir = temp_dir(test);
+        fs::write(dir.join("lexer.rs"), &source).unwrap();
+        rustc(&["--edition", "2024", "--crate-type", "lib", "lexer.rs"], &dir);
+        assert!(dir.join("liblexer.rlib").exists());
+    }
+}
+
+#[test]
+fn generated_lexer_nests_comments() {
+    let spec = "%state COMMENT\n\\/\\* (PUSH COMMENT)\n<COMMENT>\\/\\* (PUSH COMMENT)\n<COMMENT>\\*\\/ (POP)\n<COMMENT>[^*\\/]+|[*\\/] (SKIP)\n[a-z]+ ID true\n\\_+ (SKIP)\n";
+    let (stdout, stderr) = run_lexer(spec, "nested", "/* /* */ */ x");
+    assert_eq!(stdout, "ID:x [1,13-1,13] @12-13\nEOF [1,14-1,14] @13-13\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn rules_are_static() {
+    let source = generate_source(&spec(), &GeneratorOptions::default()).unwrap();
+    assert!(source.contains("static RULES: &[RuleAction] = &[\n    RuleAction::Token { name: \"NOUN\", keep_lexeme: true },"));
+    assert!(source.contains("Token { name: &'static str, keep_lexeme: bool },"));
+    assert!(!source.contains("vec!["));
+
+    // The tables are shared between calls, so a second call gives the same tokens
+    let dir = temp_dir("static");
+    let options = GeneratorOptions { output: OutputKind::Library, ..GeneratorOptions::default() };
+    fs::write(dir.join("lexer.rs"), generate_source(&spec(), &options).unwrap()).unwrap();
+    let main = r#"mod lexer { include!(concat!(env!("OUT_DIR"), "/lexer.rs")); }
+
+fn main() {
+    let first = lexer::tokenize("dog bites cat");
+    assert_eq!(first, lexer::tokenize("dog bites cat"));
+    println!("{}", first.len());
+}
+"#;
+    fs::write(dir.join("main.rs"), main).unwrap();
+    rustc(&["--edition", "2024", "main.rs", "-o", "main"], &dir);
+
+    let output = Command::new(dir.join("main")).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "4\n");
+}
+
+#[test]
+fn list_rules_prints_a_table() {
+    let spec = "%state STR\nDIGIT = [0-9]\n{DIGIT}+ NUM true (PRIO 2)\n<STR>\\\" (POP)\nif IF false (CI)\n\\/\\/.* COMMENT true (CHANNEL hidden)\n";
+    let (stdout, _, dir) = run_cli(spec, "list-rules", &["--list-rules"]);
+    assert_eq!(stdout, "line  states   regex     action\n\
+        3     INITIAL  ([0-9])+  NUM true (PRIO 2)\n\
+        4     STR      \\\"        (POP)\n\
+        5     INITIAL  if        IF false (CI)\n\
+        6     INITIAL  \\/\\/.*    COMMENT true (CHANNEL hidden)\n\
+        4 rules\n");
+    // Nothing is generated
+    assert!(!dir.join("lexer.rs").exists());
+}
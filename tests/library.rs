@@ -0,0 +1,60 @@
+// Builds lexers in-process through the public API, with no codegen or rustc involved
+use dragonlex::{build_dfa, build_nfa, compile_spec, parse_regex, parse_spec, SpecError, SpecErrorKind, NFA};
+
+const SPEC: &str = "if IF false\n[a-z]+ ID true\n[0-9]+ NUM true\n\\_+ (SKIP)\n";
+
+#[test]
+fn compiled_spec_simulates_input() {
+    let dfa = compile_spec(SPEC).unwrap();
+    let matches = dfa.simulate("if x1");
+    // Each match is the lexeme, line, column, byte offset and winning rule
+    assert_eq!(
+        matches,
+        [
+            ("if".to_string(), 1, 1, 0, Some(0)),
+            (" ".to_string(), 1, 3, 2, Some(3)),
+            ("x".to_string(), 1, 4, 3, Some(1)),
+            ("1".to_string(), 1, 5, 4, Some(2)),
+            (String::new(), 1, 6, 5, None),
+        ]
+    );
+    assert!(compile_spec("a{2,1} BAD true\n").is_err());
+}
+
+#[test]
+fn parsed_spec_tokenizes_with_either_automaton() {
+    // With `.`, which is what makes subset construction expensive
+    let spec = parse_spec(&format!("{}\\/\\/.* COMMENT true\n. OTHER true\n", SPEC)).unwrap();
+    let input = "if abc 42 + // note\nx";
+    let tokens = build_dfa(&spec).unwrap().tokenize(&spec, input);
+    let names: Vec<(&str, Option<&str>)> = tokens.iter().map(|token| (token.name.as_str(), token.lexeme.as_deref())).collect();
+    assert_eq!(
+        names,
+        [("IF", None), ("ID", Some("abc")), ("NUM", Some("42")), ("OTHER", Some("+")), ("COMMENT", Some("// note")), ("ID", Some("x")), ("EOF", None)]
+    );
+    assert_eq!((tokens[2].line, tokens[2].column, tokens[2].start, tokens[2].end), (1, 8, 7, 9));
+
+    // The NFA gives the same tokens without subset construction
+    assert_eq!(build_nfa(&spec).unwrap().tokenize(&spec, input), tokens);
+}
+
+#[test]
+fn single_regex_matches_whole_strings() {
+    let nfa = NFA::from_regex(&parse_regex("[a-z]+[0-9]?").unwrap());
+    assert!(nfa.accepts("abc7"));
+    assert!(!nfa.accepts("abc77"));
+    assert_eq!(parse_regex("a{2,1}").unwrap_err().position, 1);
+}
+
+#[test]
+fn spec_errors_say_what_went_wrong() {
+    // Callers can match on the kind instead of the message
+    let kind = |spec: &str| match parse_spec(spec) {
+        Err(SpecError::Line { kind, line, .. }) => Some((kind, line)),
+        _ => None,
+    };
+    assert_eq!(kind("%state S\na (BEGIN T)\n"), Some((SpecErrorKind::UndeclaredState, 2)));
+    assert_eq!(kind("a A maybe\n"), Some((SpecErrorKind::InvalidKeep, 1)));
+    assert_eq!(kind("a (SKIP) (CHANNEL hidden)\n"), Some((SpecErrorKind::ChannelWithoutToken, 1)));
+    assert_eq!(kind("a A true\n"), None);
+}